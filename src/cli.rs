@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use poise::serenity_prelude::{GuildId, Http};
+use sea_orm::prelude::DateTimeUtc;
+
+use crate::config::PingchuConfig;
+use crate::export::{Export, ExportFilter, ExportFormat};
+use crate::logging::LoggingArgs;
+use crate::names::Names;
+use crate::ping::PingKind;
+use crate::register::Scope;
+use crate::{commands, config, data, export, interactions, lifecycle, ping, register, site, utils};
+
+/// 🐁 Ping'chu! A Discord bot to track pings.
+#[derive(Debug, Parser)]
+#[clap(version, about)]
+pub struct Cli {
+    /// Directory to read `config.json` from.
+    #[clap(long, global = true, default_value = config::DEFAULT_CONFIG_DIR)]
+    pub config_dir: PathBuf,
+    /// Directory to store the database in.
+    #[clap(long, global = true, default_value = data::DEFAULT_DATA_DIR)]
+    pub data_dir: PathBuf,
+    #[clap(flatten)]
+    pub logging: LoggingArgs,
+    #[clap(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CliCommand {
+    /// Run the bot. This is the default if no subcommand is given.
+    Run,
+    /// Serve slash commands from an HTTP interactions endpoint instead of the gateway.
+    /// Pings are still tracked by `run`.
+    ServeInteractions,
+    /// Create any missing database tables, then exit.
+    Migrate,
+    /// Check that the config file parses, without writing it back.
+    CheckConfig,
+    /// Register slash commands without starting the bot. By default, this follows
+    /// the `commands` section of the config.
+    RegisterCommands {
+        /// Register commands on this guild only.
+        #[clap(long)]
+        guild: Option<u64>,
+        /// Register commands globally, even in dev mode.
+        #[clap(long, conflicts_with = "guild")]
+        global: bool,
+        /// Remove all commands from the guild or globally instead of registering them.
+        #[clap(long)]
+        clear: bool,
+    },
+    /// Dump the database as a JSON backup, or export ping stats as CSV or JSON for analysis.
+    Export {
+        /// File to write a backup to instead of stdout. With `--format`, the directory to write files to.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        /// Export ping stats and @everyone/@here history in this format instead of making a backup.
+        #[clap(long, value_enum)]
+        format: Option<ExportFormat>,
+        /// Only export this guild.
+        #[clap(long, requires = "format")]
+        guild: Option<u64>,
+        /// Only export pings on or after this date, like `2022-06-01`.
+        #[clap(long, requires = "format", parse(try_from_str = export::parse_date))]
+        since: Option<DateTimeUtc>,
+        /// Only export pings before this date.
+        #[clap(long, requires = "format", parse(try_from_str = export::parse_date))]
+        until: Option<DateTimeUtc>,
+        /// Only export these kinds of pings.
+        #[clap(long, requires = "format", value_delimiter = ',')]
+        kind: Vec<PingKind>,
+        /// Look up member names on Discord instead of showing ids. Needs the bot token.
+        #[clap(long, requires = "format")]
+        resolve_names: bool,
+    },
+    /// Load a JSON dump created by `export` into the database.
+    Import {
+        /// File to read from.
+        input: PathBuf,
+        /// Delete all existing data before importing.
+        #[clap(long)]
+        replace: bool,
+    },
+    /// Print a guild's ping leaderboard from the database.
+    Stats {
+        /// Guild to print stats for.
+        guild: u64,
+        /// Number of members to show.
+        #[clap(short, long, default_value_t = 10)]
+        limit: u64,
+    },
+    /// Render a static HTML stats site for every allowed server.
+    Site {
+        /// Directory to write the site to.
+        #[clap(short, long, default_value = "site")]
+        output: PathBuf,
+        /// Number of members to show on each leaderboard.
+        #[clap(short, long, default_value_t = 25)]
+        limit: usize,
+        /// Look up server and member names on Discord instead of showing ids. Needs the bot token.
+        #[clap(long)]
+        resolve_names: bool,
+    },
+}
+
+impl Cli {
+    pub async fn run(self) -> Result<()> {
+        match self.command.unwrap_or(CliCommand::Run) {
+            CliCommand::Run => crate::run(&self.config_dir, &self.data_dir).await,
+            CliCommand::ServeInteractions => interactions::serve(&self.config_dir, &self.data_dir).await,
+            CliCommand::Migrate => {
+                // loading the database creates any missing tables
+                data::load_database(&self.data_dir).await?;
+                println!("Database is up to date.");
+                Ok(())
+            }
+            CliCommand::CheckConfig => {
+                let config = config::read_config(&self.config_dir)?;
+                println!("Config OK, {} allowed server(s).", config.allowed_servers.len());
+                Ok(())
+            }
+            CliCommand::RegisterCommands { guild, global, clear } => {
+                let config = config::load_config(&self.config_dir)?;
+                let scope = match guild {
+                    Some(guild) => Some(Scope::Guild(GuildId(guild))),
+                    None if global || clear => Some(Scope::Global),
+                    None => None,
+                };
+                register_commands(&config, scope, clear).await
+            }
+            CliCommand::Export {
+                output,
+                format: Some(format),
+                guild,
+                since,
+                until,
+                kind,
+                resolve_names,
+            } => {
+                let database = data::load_database(&self.data_dir).await?;
+                let filter = ExportFilter {
+                    since,
+                    until,
+                    kinds: kind,
+                };
+                let mut export = Export::load(&database, guild.map(GuildId), &filter).await?;
+                if resolve_names {
+                    let http = Http::new(&crate::read_token()?);
+                    let mut names = HashMap::new();
+                    for (guild, users) in export.members() {
+                        let mut guild_names = Names::default();
+                        guild_names.fetch_members(&http, guild, users).await;
+                        names.insert(guild, guild_names);
+                    }
+                    export.resolve_names(&names);
+                }
+
+                let output = output.unwrap_or_else(|| PathBuf::from("export"));
+                fs::create_dir_all(&output).with_context(|| format!("Couldn't create {}", output.display()))?;
+                for (filename, data) in export.files(format)? {
+                    let path = output.join(filename);
+                    fs::write(&path, data).with_context(|| format!("Couldn't write {}", path.display()))?;
+                    println!("Wrote {}", path.display());
+                }
+                Ok(())
+            }
+            CliCommand::Export { output, .. } => {
+                let database = data::load_database(&self.data_dir).await?;
+                let backup = data::export_backup(&database).await?;
+                match output {
+                    Some(path) => serde_json::to_writer_pretty(File::create(path)?, &backup)?,
+                    None => serde_json::to_writer_pretty(io::stdout(), &backup)?,
+                }
+                Ok(())
+            }
+            CliCommand::Import { input, replace } => {
+                let database = data::load_database(&self.data_dir).await?;
+                let backup: data::Backup = serde_json::from_reader(File::open(&input)?)
+                    .with_context(|| format!("Couldn't parse {}", input.display()))?;
+                let rows = backup.guild_pings.len();
+                data::import_backup(&database, backup, replace).await?;
+                println!("Imported {} row(s).", rows);
+                Ok(())
+            }
+            CliCommand::Stats { guild, limit } => {
+                let database = data::load_database(&self.data_dir).await?;
+                let leaderboard = ping::guild_leaderboard(&database, GuildId(guild), limit).await?;
+                let departed = lifecycle::departed_users(&database, GuildId(guild)).await?;
+                if leaderboard.is_empty() {
+                    println!("Nobody has pinged anyone in {} yet.", guild);
+                }
+                for (rank, row) in leaderboard.iter().enumerate() {
+                    let left = if departed.contains(&row.user_id) { " (left)" } else { "" };
+                    println!("{:>3}. {:<20} {} ping(s){}", rank + 1, row.user_id, row.pings, left);
+                }
+                Ok(())
+            }
+            CliCommand::Site {
+                output,
+                limit,
+                resolve_names,
+            } => site::generate(&self.config_dir, &self.data_dir, &output, limit, resolve_names).await,
+        }
+    }
+}
+
+async fn register_commands(config: &PingchuConfig, scope: Option<Scope>, clear: bool) -> Result<()> {
+    let http = crate::application_http(&crate::read_token()?).await?;
+    let commands = commands::all(utils::check_uwu_support());
+    match scope {
+        Some(scope) if clear => register::clear(&http, scope).await,
+        Some(scope) => register::register(&http, scope, &commands).await.map(|_| ()),
+        None => register::register_from_config(&http, config, &commands).await,
+    }
+}
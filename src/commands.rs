@@ -1,10 +1,28 @@
-use anyhow::{Context as AnyhowContext, Result};
+pub mod export;
+pub mod privacy;
+pub mod raid;
+pub mod servers;
+
+use anyhow::{Context as AnyhowContext, Error, Result};
 use poise::serenity_prelude::{Colour, CreateEmbed, Timestamp, User};
+use poise::Command;
 
-use crate::{ping, utils, PingchuContext};
+use self::privacy::privacy;
+use self::raid::raid;
+use crate::data::guild_ping;
+use crate::{ping, utils, Pingchu, PingchuContext};
 
 pub const EMBED_COLOR: Colour = Colour(0xEF5858);
 
+/// Every command Pingchu can register, depending on what this machine supports.
+pub fn all(uwu_supported: bool) -> Vec<Command<Pingchu, Error>> {
+    let mut commands = vec![pinginfo(), pingchu(), export::export(), servers::servers()];
+    if uwu_supported {
+        commands.push(uwuify());
+    }
+    commands
+}
+
 /// Applies a standard "UI" theme to embeds sent by Pingchu.
 pub fn apply_ui(embed: &mut CreateEmbed, in_reply_to: Option<&User>, timestamp: Timestamp) {
     embed.color(EMBED_COLOR);
@@ -18,16 +36,14 @@ pub fn apply_ui(embed: &mut CreateEmbed, in_reply_to: Option<&User>, timestamp:
     embed.timestamp(timestamp);
 }
 
-#[poise::command(slash_command)]
-/// Get ping stats for yourself or a target user.
-pub async fn pinginfo(ctx: PingchuContext<'_>, #[description = "Target user."] user: Option<User>) -> Result<()> {
-    let user = user.as_ref().unwrap_or_else(|| ctx.author());
-    // SAFETY: the pre-command hook filters out commands not sent in guilds
-    let guild = ctx.guild_id().unwrap();
-    let member = guild.member(&ctx.discord().http, user.id).await?;
-    let timestamp = ctx.created_at();
-
-    let info = ping::member_ping_info(ctx.data(), ctx.guild_id().unwrap(), user.id).await?;
+/// Fills in the `/pinginfo` embed for `user`, who goes by `display_name` on the server.
+pub fn ping_stats_embed<'a>(
+    embed: &'a mut CreateEmbed,
+    user: &User,
+    display_name: &str,
+    info: Option<guild_ping::Model>,
+    timestamp: Timestamp,
+) -> &'a mut CreateEmbed {
     let (last_everyone_ping, last_here_ping, last_role_ping, last_user_ping, pings) = match info {
         Some(m) => (
             m.last_everyone_ping,
@@ -39,45 +55,76 @@ pub async fn pinginfo(ctx: PingchuContext<'_>, #[description = "Target user."] u
         None => (None, None, None, None, 0),
     };
 
-    ctx.send(|msg| {
-        msg.embed(|embed| {
-            apply_ui(embed, Some(user), timestamp);
-            embed
-                .title(format!("{}'s Ping Stats", member.display_name()))
-                .field("Total Pings", pings, false);
-            if let Some(time) = last_everyone_ping {
-                embed.field(
-                    "Time since last @everyone",
-                    utils::format_time_v1_duration(*timestamp - time),
-                    true,
-                );
-            }
-            if let Some(time) = last_here_ping {
-                embed.field(
-                    "Time since last @here",
-                    utils::format_time_v1_duration(*timestamp - time),
-                    true,
-                );
-            }
-            if let Some(time) = last_role_ping {
-                embed.field(
-                    "Time since last @role",
-                    utils::format_time_v1_duration(*timestamp - time),
-                    true,
-                );
-            }
-            if let Some(time) = last_user_ping {
-                embed.field(
-                    "Time since last @User",
-                    utils::format_time_v1_duration(*timestamp - time),
-                    true,
-                );
-            }
-            embed
+    apply_ui(embed, Some(user), timestamp);
+    embed
+        .title(format!("{}'s Ping Stats", display_name))
+        .field("Total Pings", pings, false);
+    if let Some(time) = last_everyone_ping {
+        embed.field(
+            "Time since last @everyone",
+            utils::format_time_v1_duration(*timestamp - time),
+            true,
+        );
+    }
+    if let Some(time) = last_here_ping {
+        embed.field(
+            "Time since last @here",
+            utils::format_time_v1_duration(*timestamp - time),
+            true,
+        );
+    }
+    if let Some(time) = last_role_ping {
+        embed.field(
+            "Time since last @role",
+            utils::format_time_v1_duration(*timestamp - time),
+            true,
+        );
+    }
+    if let Some(time) = last_user_ping {
+        embed.field(
+            "Time since last @User",
+            utils::format_time_v1_duration(*timestamp - time),
+            true,
+        );
+    }
+    embed
+}
+
+#[poise::command(slash_command, subcommands("privacy", "raid"))]
+/// Ping'chu settings.
+pub async fn pingchu(_ctx: PingchuContext<'_>) -> Result<()> {
+    Ok(())
+}
+
+#[poise::command(slash_command)]
+/// Get ping stats for yourself or a target user.
+pub async fn pinginfo(ctx: PingchuContext<'_>, #[description = "Target user."] user: Option<User>) -> Result<()> {
+    let user = user.as_ref().unwrap_or_else(|| ctx.author());
+    // SAFETY: the pre-command hook filters out commands not sent in guilds
+    let guild = ctx.guild_id().unwrap();
+    let member = guild
+        .member(&ctx.discord().http, user.id)
+        .await
+        .context("Couldn't find that member, maybe they left?")?;
+    let timestamp = ctx.created_at();
+
+    if user.id != ctx.author().id
+        && crate::privacy::settings(&ctx.data().database, guild, user.id)
+            .await?
+            .hidden
+    {
+        ctx.send(|msg| {
+            msg.ephemeral(true)
+                .content(format!("{} has hidden their ping stats.", member.display_name()))
         })
-    })
-    .await
-    .context("Failed to reply to /pinginfo")?;
+        .await
+        .context("Failed to reply to /pinginfo")?;
+        return Ok(());
+    }
+    let info = ping::member_ping_info(&ctx.data().database, guild, user.id).await?;
+    ctx.send(|msg| msg.embed(|embed| ping_stats_embed(embed, user, &member.display_name(), info, timestamp)))
+        .await
+        .context("Failed to reply to /pinginfo")?;
     Ok(())
 }
 
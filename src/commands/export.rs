@@ -0,0 +1,86 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use anyhow::{Context as AnyhowContext, Result};
+use poise::serenity_prelude::AttachmentType;
+
+use crate::export::{self, Export, ExportFilter, ExportFormat};
+use crate::names::Names;
+use crate::ping::PingKind;
+use crate::PingchuContext;
+
+/// Discord's limit on attachment sizes for servers without boosts.
+const MAX_ATTACHMENT_SIZE: usize = 8 * 1024 * 1024;
+
+/// What `/export` says when the export can't be attached.
+pub const TOO_BIG: &str = "That export is too big to upload, try a shorter date range or use the `export` CLI.";
+
+#[poise::command(slash_command, required_permissions = "MANAGE_GUILD")]
+/// Export this server's ping stats as CSV or JSON.
+pub async fn export(
+    ctx: PingchuContext<'_>,
+    #[description = "csv or json, defaults to csv."] format: Option<String>,
+    #[description = "Only pings on or after this date, like 2022-06-01."] since: Option<String>,
+    #[description = "Only pings before this date, like 2022-07-01."] until: Option<String>,
+    #[description = "Only this kind of ping: everyone, here, role or user."] kind: Option<String>,
+) -> Result<()> {
+    // SAFETY: the pre-command hook filters out commands not sent in guilds
+    let guild = ctx.guild_id().unwrap();
+    let (format, filter) = parse_options(format.as_deref(), since.as_deref(), until.as_deref(), kind.as_deref())?;
+    ctx.defer_ephemeral().await.context("Failed to defer /export")?;
+
+    let mut export = Export::load(&ctx.data().database, Some(guild), &filter).await?;
+    let names = HashMap::from([(guild, Names::from_cache(&ctx.discord().cache, guild))]);
+    export.resolve_names(&names);
+    let files = export.files(format)?;
+    if too_big(&files) {
+        ctx.send(|msg| msg.ephemeral(true).content(TOO_BIG))
+            .await
+            .context("Failed to reply to /export")?;
+        return Ok(());
+    }
+
+    ctx.send(|msg| {
+        msg.ephemeral(true).content(summary(&export));
+        for (filename, data) in files {
+            msg.attachment(AttachmentType::Bytes {
+                data: Cow::Owned(data),
+                filename,
+            });
+        }
+        msg
+    })
+    .await
+    .context("Failed to reply to /export")?;
+    Ok(())
+}
+
+/// Parses `/export`'s options into a format and filter.
+pub fn parse_options(
+    format: Option<&str>,
+    since: Option<&str>,
+    until: Option<&str>,
+    kind: Option<&str>,
+) -> Result<(ExportFormat, ExportFilter)> {
+    let format = format.map(ExportFormat::parse).transpose()?;
+    let filter = ExportFilter {
+        since: since.map(export::parse_date).transpose()?,
+        until: until.map(export::parse_date).transpose()?,
+        kinds: kind.map(str::parse::<PingKind>).transpose()?.into_iter().collect(),
+    };
+    Ok((format.unwrap_or(ExportFormat::Csv), filter))
+}
+
+/// Whether `files` are too big to attach to a message.
+pub fn too_big(files: &[(String, Vec<u8>)]) -> bool {
+    files.iter().map(|(_, data)| data.len()).sum::<usize>() > MAX_ATTACHMENT_SIZE
+}
+
+/// What `/export` says about an export it attached.
+pub fn summary(export: &Export) -> String {
+    format!(
+        "Exported {} member(s) and {} @everyone/@here ping(s).",
+        export.pings.len(),
+        export.incidents.len()
+    )
+}
@@ -0,0 +1,184 @@
+use anyhow::{Context as AnyhowContext, Result};
+use poise::serenity_prelude::{GuildId, Mentionable, UserId};
+use sea_orm::DatabaseConnection;
+
+use crate::commands::apply_ui;
+use crate::{privacy, PingchuContext};
+
+#[poise::command(slash_command, subcommands("optout", "optin", "hide", "show", "delete", "list"))]
+/// Control what Ping'chu tracks about you.
+pub async fn privacy(_ctx: PingchuContext<'_>) -> Result<()> {
+    Ok(())
+}
+
+/// A `/pingchu privacy` subcommand that changes or deletes a member's data, shared with the interactions server.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PrivacyChange {
+    OptOut,
+    OptIn,
+    Hide,
+    Show,
+    Delete { confirm: bool },
+}
+
+impl PrivacyChange {
+    /// Applies the change to `user`'s data on `scope`, returning what to tell them.
+    pub async fn apply(self, database: &DatabaseConnection, scope: Option<GuildId>, user: UserId) -> Result<String> {
+        let content = match self {
+            Self::OptOut => {
+                privacy::update(database, scope, user, |x| x.opted_out = true).await?;
+                format!(
+                    "Ping'chu won't track your pings {} anymore. Stats you already have are kept, use `/pingchu privacy delete` to remove them.",
+                    where_(scope)
+                )
+            }
+            Self::OptIn => {
+                privacy::update(database, scope, user, |x| x.opted_out = false).await?;
+                format!("Ping'chu will track your pings {} again.", where_(scope))
+            }
+            Self::Hide => {
+                privacy::update(database, scope, user, |x| x.hidden = true).await?;
+                format!("Your ping stats are now hidden {}.", where_(scope))
+            }
+            Self::Show => {
+                privacy::update(database, scope, user, |x| x.hidden = false).await?;
+                format!("Your ping stats are visible {} again.", where_(scope))
+            }
+            Self::Delete { confirm: false } => {
+                "Nothing was deleted. Run this again with `confirm: True` if you're sure.".to_string()
+            }
+            Self::Delete { confirm: true } => {
+                let rows = privacy::delete_user_data(database, scope, user).await?;
+                format!(
+                    "Deleted {} record(s) {}. Use `/pingchu privacy optout` to stop new pings from being tracked.",
+                    rows,
+                    where_(scope)
+                )
+            }
+        };
+        Ok(content)
+    }
+}
+
+#[poise::command(slash_command)]
+/// Stop Ping'chu from tracking your pings.
+pub async fn optout(
+    ctx: PingchuContext<'_>,
+    #[description = "Opt out on every server instead of just this one."] everywhere: Option<bool>,
+) -> Result<()> {
+    change(ctx, PrivacyChange::OptOut, everywhere).await
+}
+
+#[poise::command(slash_command)]
+/// Let Ping'chu track your pings again.
+pub async fn optin(
+    ctx: PingchuContext<'_>,
+    #[description = "Opt back in on every server instead of just this one."] everywhere: Option<bool>,
+) -> Result<()> {
+    change(ctx, PrivacyChange::OptIn, everywhere).await
+}
+
+#[poise::command(slash_command)]
+/// Hide your ping stats from other members and leaderboards.
+pub async fn hide(
+    ctx: PingchuContext<'_>,
+    #[description = "Hide your stats on every server instead of just this one."] everywhere: Option<bool>,
+) -> Result<()> {
+    change(ctx, PrivacyChange::Hide, everywhere).await
+}
+
+#[poise::command(slash_command)]
+/// Show your ping stats to other members and on leaderboards again.
+pub async fn show(
+    ctx: PingchuContext<'_>,
+    #[description = "Show your stats on every server instead of just this one."] everywhere: Option<bool>,
+) -> Result<()> {
+    change(ctx, PrivacyChange::Show, everywhere).await
+}
+
+#[poise::command(slash_command)]
+/// Delete everything Ping'chu has tracked about you.
+pub async fn delete(
+    ctx: PingchuContext<'_>,
+    #[description = "Set to true to confirm. This can't be undone!"] confirm: bool,
+    #[description = "Delete your data from every server instead of just this one."] everywhere: Option<bool>,
+) -> Result<()> {
+    change(ctx, PrivacyChange::Delete { confirm }, everywhere).await
+}
+
+#[poise::command(slash_command, required_permissions = "MANAGE_GUILD")]
+/// List members who opted out or hid their stats on this server.
+pub async fn list(ctx: PingchuContext<'_>) -> Result<()> {
+    // SAFETY: the pre-command hook filters out commands not sent in guilds
+    let guild = ctx.guild_id().unwrap();
+    let description = list_description(&ctx.data().database, guild).await?;
+    let timestamp = ctx.created_at();
+
+    ctx.send(|msg| {
+        msg.ephemeral(true).embed(|embed| {
+            apply_ui(embed, Some(ctx.author()), timestamp);
+            embed.title("Privacy Settings").description(description)
+        })
+    })
+    .await
+    .context("Failed to reply to /pingchu privacy list")?;
+    Ok(())
+}
+
+/// Lists who opted out or hid their stats on `guild`, for `/pingchu privacy list`.
+pub async fn list_description(database: &DatabaseConnection, guild: GuildId) -> Result<String> {
+    let settings = privacy::guild_settings(database, guild).await?;
+    if settings.is_empty() {
+        return Ok("Nobody has changed their privacy settings.".to_string());
+    }
+    Ok(settings
+        .iter()
+        .map(|x| {
+            let mut flags = vec![];
+            if x.opted_out {
+                flags.push("opted out");
+            }
+            if x.hidden {
+                flags.push("hidden");
+            }
+            let scope = if x.guild_id == privacy::GLOBAL {
+                " (everywhere)"
+            } else {
+                ""
+            };
+            format!(
+                "{}: **{}**{}",
+                UserId(x.user_id as u64).mention(),
+                flags.join(", "),
+                scope
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// The guild a setting applies to, or `None` for every guild.
+pub fn scope(guild: GuildId, everywhere: Option<bool>) -> Option<GuildId> {
+    if everywhere.unwrap_or(false) {
+        None
+    } else {
+        Some(guild)
+    }
+}
+
+fn where_(scope: Option<GuildId>) -> &'static str {
+    match scope {
+        Some(_) => "on this server",
+        None => "on any server",
+    }
+}
+
+async fn change(ctx: PingchuContext<'_>, change: PrivacyChange, everywhere: Option<bool>) -> Result<()> {
+    // SAFETY: the pre-command hook filters out commands not sent in guilds
+    let scope = scope(ctx.guild_id().unwrap(), everywhere);
+    let content = change.apply(&ctx.data().database, scope, ctx.author().id).await?;
+    ctx.send(|msg| msg.ephemeral(true).content(content))
+        .await
+        .context("Failed to reply to /pingchu privacy")?;
+    Ok(())
+}
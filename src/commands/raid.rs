@@ -0,0 +1,64 @@
+use anyhow::{Context as AnyhowContext, Result};
+use poise::serenity_prelude::GuildId;
+use sea_orm::DatabaseConnection;
+
+use crate::{raids, PingchuContext};
+
+pub const ENABLED: &str =
+    "Raid mode is now on. Messages with mass mentions will be deleted until someone runs `/pingchu raid clear`.";
+pub const CLEARED: &str = "Raid mode is now off.";
+
+#[poise::command(
+    slash_command,
+    required_permissions = "MANAGE_GUILD",
+    subcommands("status", "enable", "clear")
+)]
+/// Manage raid mode, which deletes messages with mass mentions.
+pub async fn raid(_ctx: PingchuContext<'_>) -> Result<()> {
+    Ok(())
+}
+
+#[poise::command(slash_command, required_permissions = "MANAGE_GUILD")]
+/// Check whether this server is in raid mode.
+pub async fn status(ctx: PingchuContext<'_>) -> Result<()> {
+    // SAFETY: the pre-command hook filters out commands not sent in guilds
+    let guild = ctx.guild_id().unwrap();
+    let content = status_message(&ctx.data().database, guild).await?;
+    ctx.send(|msg| msg.ephemeral(true).content(content))
+        .await
+        .context("Failed to reply to /pingchu raid status")?;
+    Ok(())
+}
+
+#[poise::command(slash_command, required_permissions = "MANAGE_GUILD")]
+/// Turn on raid mode, deleting messages with mass mentions until it's cleared.
+pub async fn enable(ctx: PingchuContext<'_>) -> Result<()> {
+    // SAFETY: the pre-command hook filters out commands not sent in guilds
+    let guild = ctx.guild_id().unwrap();
+    raids::set_raid_mode(&ctx.data().database, guild, true).await?;
+    ctx.say(ENABLED)
+        .await
+        .context("Failed to reply to /pingchu raid enable")?;
+    Ok(())
+}
+
+#[poise::command(slash_command, required_permissions = "MANAGE_GUILD")]
+/// Turn off raid mode.
+pub async fn clear(ctx: PingchuContext<'_>) -> Result<()> {
+    // SAFETY: the pre-command hook filters out commands not sent in guilds
+    let guild = ctx.guild_id().unwrap();
+    raids::set_raid_mode(&ctx.data().database, guild, false).await?;
+    ctx.say(CLEARED)
+        .await
+        .context("Failed to reply to /pingchu raid clear")?;
+    Ok(())
+}
+
+/// What `/pingchu raid status` says about `guild`.
+pub async fn status_message(database: &DatabaseConnection, guild: GuildId) -> Result<&'static str> {
+    Ok(if raids::raid_mode(database, guild).await? {
+        "Raid mode is **on**. Run `/pingchu raid clear` once things have calmed down."
+    } else {
+        "Raid mode is **off**."
+    })
+}
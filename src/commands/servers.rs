@@ -0,0 +1,125 @@
+use anyhow::{anyhow, Context as AnyhowContext, Result};
+use poise::serenity_prelude::{Channel, GuildId};
+
+use crate::commands::apply_ui;
+use crate::servers::ServerStatus;
+use crate::{servers, PingchuContext};
+
+#[poise::command(slash_command, owners_only, subcommands("allow", "deny", "logchannel", "list"))]
+/// Manage which servers Ping'chu runs on.
+pub async fn servers(_ctx: PingchuContext<'_>) -> Result<()> {
+    Ok(())
+}
+
+#[poise::command(slash_command, owners_only)]
+/// Allow Ping'chu to run on a server.
+pub async fn allow(
+    ctx: PingchuContext<'_>,
+    #[description = "Server ID, defaults to this server."] server: Option<String>,
+    #[description = "Channel to log @everyone pings to."] log_channel: Option<Channel>,
+) -> Result<()> {
+    let guild = target_guild(ctx, server)?;
+    let pingchu = ctx.data();
+    pingchu
+        .servers
+        .allow(&pingchu.database, guild, log_channel.map(|x| x.id()))
+        .await?;
+    ctx.say(format!("Ping'chu is now allowed on {}!", guild_name(ctx, guild)))
+        .await
+        .context("Failed to reply to /servers allow")?;
+    Ok(())
+}
+
+#[poise::command(slash_command, owners_only)]
+/// Stop Ping'chu from running on a server.
+pub async fn deny(
+    ctx: PingchuContext<'_>,
+    #[description = "Server ID, defaults to this server."] server: Option<String>,
+) -> Result<()> {
+    let guild = target_guild(ctx, server)?;
+    let pingchu = ctx.data();
+    pingchu.servers.deny(&pingchu.database, guild).await?;
+    if let Some(delay) = pingchu.config.leave_disallowed_after {
+        servers::schedule_leave(ctx.discord().http.clone(), pingchu.servers.clone(), guild, delay);
+    }
+    ctx.say(format!("Ping'chu is no longer allowed on {}.", guild_name(ctx, guild)))
+        .await
+        .context("Failed to reply to /servers deny")?;
+    Ok(())
+}
+
+#[poise::command(slash_command, owners_only)]
+/// Set the channel @everyone pings are logged to.
+pub async fn logchannel(
+    ctx: PingchuContext<'_>,
+    #[description = "Channel to log @everyone pings to."] channel: Channel,
+    #[description = "Server ID, defaults to this server."] server: Option<String>,
+) -> Result<()> {
+    let guild = target_guild(ctx, server)?;
+    let pingchu = ctx.data();
+    pingchu
+        .servers
+        .set_log_channel(&pingchu.database, guild, channel.id())
+        .await?;
+    ctx.say(format!(
+        "@everyone pings on {} will be logged to {}.",
+        guild_name(ctx, guild),
+        channel
+    ))
+    .await
+    .context("Failed to reply to /servers logchannel")?;
+    Ok(())
+}
+
+#[poise::command(slash_command, owners_only)]
+/// List every server Ping'chu knows about.
+pub async fn list(ctx: PingchuContext<'_>) -> Result<()> {
+    let mut guilds = ctx.discord().cache.guilds();
+    guilds.extend(ctx.data().servers.known_guilds());
+    guilds.sort();
+    guilds.dedup();
+
+    let description = guilds
+        .iter()
+        .map(|guild| {
+            let status = match ctx.data().servers.status(*guild) {
+                ServerStatus::AllowedByConfig => "allowed (config)",
+                ServerStatus::Allowed => "allowed",
+                ServerStatus::Denied => "denied",
+                ServerStatus::Unknown => "not allowed",
+            };
+            format!("`{}` {}: **{}**", guild, guild_name(ctx, *guild), status)
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let timestamp = ctx.created_at();
+
+    ctx.send(|msg| {
+        msg.embed(|embed| {
+            apply_ui(embed, Some(ctx.author()), timestamp);
+            embed.title("Known Servers").description(description)
+        })
+    })
+    .await
+    .context("Failed to reply to /servers list")?;
+    Ok(())
+}
+
+fn target_guild(ctx: PingchuContext<'_>, server: Option<String>) -> Result<GuildId> {
+    match server {
+        Some(id) => id
+            .trim()
+            .parse::<u64>()
+            .map(GuildId)
+            .map_err(|_| anyhow!("`{}` isn't a valid server ID", id)),
+        None => ctx
+            .guild_id()
+            .ok_or_else(|| anyhow!("A server ID is required outside of servers")),
+    }
+}
+
+fn guild_name(ctx: PingchuContext<'_>, guild: GuildId) -> String {
+    guild
+        .name(&ctx.discord().cache)
+        .unwrap_or_else(|| "an unknown server".to_string())
+}
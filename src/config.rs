@@ -1,13 +1,26 @@
 use std::collections::HashMap;
+use std::fs;
 use std::fs::File;
+use std::net::SocketAddr;
 use std::path::Path;
-use std::{env, fs};
+use std::time::Duration;
 
-use anyhow::Result;
-use poise::serenity_prelude::{ActivityType, ChannelId, GuildId};
+use anyhow::{Context, Result};
+use poise::serenity_prelude::{ActivityType, ChannelId, GuildId, RoleId};
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 
-pub const CONFIG_FILE: &str = ".data/config.json";
+use crate::ping::PingKind;
+use crate::quotas::QuotaAction;
+use crate::responses::{Response, Selection};
+use crate::rules::{RuleAction, TimeOfDay};
+use crate::templates::Template;
+use crate::webhooks::WebhookEvent;
+
+pub const DEFAULT_CONFIG_DIR: &str = ".data";
+pub const CONFIG_FILE: &str = "config.json";
+/// Per-server scripts live in here, inside the config directory.
+pub const SCRIPTS_DIR: &str = "scripts";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
@@ -15,8 +28,23 @@ pub struct PingchuConfig {
     pub status: String,
     pub status_type: ActivityType,
     pub allowed_servers: HashMap<GuildId, ServerConfig>,
-    pub ping_responses: Vec<String>,
+    /// Replies to members who ping Pingchu, with placeholders like `{name}`.
+    pub ping_responses: Vec<Response>,
+    /// Whether responses are picked at random or go through a shuffled bag so they don't repeat.
+    pub response_selection: Selection,
+    pub mention_cooldowns: MentionCooldownConfig,
     pub uwu_chance: f64,
+    pub commands: CommandsConfig,
+    /// How long to wait before leaving a guild that isn't allowed. If unset, Pingchu stays in every guild.
+    #[serde(with = "crate::utils::humantime_option")]
+    pub leave_disallowed_after: Option<Duration>,
+    pub http: HttpConfig,
+    pub error_reports: ErrorReportConfig,
+    pub webhooks: WebhooksConfig,
+    pub interactions: InteractionsConfig,
+    pub lifecycle: LifecycleConfig,
+    pub raids: RaidConfig,
+    pub scripts: ScriptsConfig,
 }
 
 impl Default for PingchuConfig {
@@ -45,9 +73,20 @@ impl Default for PingchuConfig {
                 "_Ping'chu uses Thunderbolt! It wasn't very effective..._",
             ]
             .iter()
-            .map(|x| x.to_string())
+            .map(|x| Response::from(x.parse::<Template>().unwrap()))
             .collect(),
+            response_selection: Selection::Random,
+            mention_cooldowns: MentionCooldownConfig::default(),
             uwu_chance: 0.5,
+            commands: CommandsConfig::default(),
+            leave_disallowed_after: None,
+            http: HttpConfig::default(),
+            error_reports: ErrorReportConfig::default(),
+            webhooks: WebhooksConfig::default(),
+            interactions: InteractionsConfig::default(),
+            lifecycle: LifecycleConfig::default(),
+            raids: RaidConfig::default(),
+            scripts: ScriptsConfig::default(),
         }
     }
 }
@@ -55,25 +94,316 @@ impl Default for PingchuConfig {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub log_channel: ChannelId,
+    /// Limits on how often each member can ping.
+    #[serde(default)]
+    pub quotas: Vec<QuotaRule>,
+    /// Extra things to do with pings, checked in order. Every matching rule runs.
+    #[serde(default)]
+    pub rules: Vec<PingRule>,
+    /// Only logs which rules would run instead of running them.
+    #[serde(default)]
+    pub rules_dry_run: bool,
+    /// Replies to pings of Pingchu's own managed role as if Pingchu was pinged.
+    #[serde(default = "default_true")]
+    pub reply_to_role_pings: bool,
+    /// Replies to replies to Pingchu's messages that have reply-ping on, even if they don't mention Pingchu.
+    #[serde(default = "default_true")]
+    pub reply_to_reply_pings: bool,
+}
+
+impl ServerConfig {
+    pub fn new(log_channel: ChannelId) -> Self {
+        Self {
+            log_channel,
+            quotas: vec![],
+            rules: vec![],
+            rules_dry_run: false,
+            reply_to_role_pings: true,
+            reply_to_reply_pings: true,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Allows each member at most `max` pings of `kinds` within `per`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaRule {
+    pub kinds: Vec<PingKind>,
+    pub max: u32,
+    #[serde(with = "crate::utils::humantime_duration")]
+    pub per: Duration,
+    /// What to do when a member goes over the quota.
+    pub actions: Vec<QuotaAction>,
+    /// How long the `timeout` action times members out for.
+    #[serde(default = "default_quota_timeout", with = "crate::utils::humantime_duration")]
+    pub timeout: Duration,
+}
+
+fn default_quota_timeout() -> Duration {
+    Duration::from_secs(10 * 60)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PingRule {
+    /// Shows up in logs and forwarded messages.
+    pub name: String,
+    #[serde(default)]
+    pub when: RuleConditions,
+    pub actions: Vec<RuleAction>,
+}
+
+/// What a message has to look like for a rule to match. Empty lists match anything.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RuleConditions {
+    /// Kinds of pings to count. Messages without any of them don't match.
+    pub kinds: Vec<PingKind>,
+    /// Fewest pings of `kinds` in one message.
+    pub min_pings: Option<usize>,
+    /// Most pings of `kinds` in one message.
+    pub max_pings: Option<usize>,
+    pub channels: Vec<ChannelId>,
+    /// The author needs at least one of these roles.
+    pub author_roles: Vec<RoleId>,
+    /// The message needs to ping at least one of these roles.
+    pub target_roles: Vec<RoleId>,
+    /// Start and end times in UTC, like `["22:00", "06:00"]`.
+    pub between: Option<(TimeOfDay, TimeOfDay)>,
+}
+
+/// Limits how often Pingchu replies to pings, so spamming it doesn't get more replies.
+/// Pings during a cooldown get one reply once it's over, to the latest of them. Everything is off by default.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MentionCooldownConfig {
+    /// How long each member has to wait between replies. If unset, members don't have a cooldown.
+    #[serde(with = "crate::utils::humantime_option")]
+    pub per_user: Option<Duration>,
+    /// How long each channel has to wait between replies. If unset, channels don't have a cooldown.
+    #[serde(with = "crate::utils::humantime_option")]
+    pub per_channel: Option<Duration>,
+    /// Said the first time a cooldown is hit. If unset, Pingchu stays quiet.
+    pub tired_response: Option<String>,
+}
+
+/// Controls where slash commands get registered.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CommandsConfig {
+    /// Registers commands on guilds only instead of globally. Guild commands update instantly,
+    /// which makes this useful for testing.
+    pub dev_mode: bool,
+    /// Guilds to register commands on in dev mode. If empty, every allowed server is used.
+    pub test_guilds: Vec<GuildId>,
+    /// Removes commands left over from the other mode, i.e. global commands in dev mode
+    /// and guild commands otherwise.
+    pub clear_stale: bool,
+}
+
+/// Controls Pingchu's optional HTTP server.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Address to listen on, like `127.0.0.1:8080`. If unset, the HTTP server is disabled.
+    pub bind: Option<SocketAddr>,
+    /// Serves Prometheus metrics at `/metrics`.
+    pub metrics: bool,
+    /// Serves liveness and readiness checks at `/healthz` and `/readyz`.
+    pub health: bool,
+    /// Serves read-only ping stats under `/api/v1`.
+    pub api: bool,
+    /// Streams ping events over a WebSocket at `/feed`. This uses the same tokens as the API.
+    pub feed: bool,
+    /// API tokens, keyed by the name of whoever uses them.
+    pub api_tokens: HashMap<String, String>,
+}
+
+/// Controls where errors get reported.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ErrorReportConfig {
+    /// Channel to post full error reports to. If unset, errors are only logged.
+    pub channel: Option<ChannelId>,
+    /// Errors within this long of the last report are counted instead of posted.
+    #[serde(with = "crate::utils::humantime_option")]
+    pub min_interval: Option<Duration>,
+}
+
+impl Default for ErrorReportConfig {
+    fn default() -> Self {
+        Self {
+            channel: None,
+            min_interval: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+/// Controls outbound webhooks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhooksConfig {
+    pub endpoints: Vec<WebhookEndpoint>,
+    /// Total ping counts that trigger a `milestone` event when a member reaches them.
+    pub milestones: Vec<u32>,
+    /// How many times to try delivering a payload before giving up and recording it in the database.
+    pub max_attempts: u32,
 }
 
-pub fn load_config() -> PingchuConfig {
-    let config_file = env::current_dir().unwrap().join(Path::new(CONFIG_FILE));
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: vec![],
+            milestones: vec![100, 500, 1000],
+            max_attempts: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookEndpoint {
+    pub url: String,
+    /// Shared secret used to sign payloads.
+    pub secret: String,
+    pub events: Vec<WebhookEvent>,
+    /// Guilds to send events for. If empty, events from every allowed server are sent.
+    #[serde(default)]
+    pub guilds: Vec<GuildId>,
+}
+
+/// Controls the HTTP interactions endpoint started by `serve-interactions`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InteractionsConfig {
+    /// Address to listen on. Discord sends interactions to `/interactions` on this server.
+    pub bind: SocketAddr,
+    /// The application's public key from the developer portal, as hex.
+    pub public_key: Option<String>,
+}
+
+impl Default for InteractionsConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([127, 0, 0, 1], 8081)),
+            public_key: None,
+        }
+    }
+}
+
+/// What happens to stats once their member or guild is gone.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionPolicy {
+    /// Keep stats as if nothing happened.
+    Keep,
+    /// Keep stats, but show them as departed.
+    Mark,
+    /// Delete stats once `lifecycle.purge_after` has passed.
+    Purge,
+}
+
+/// Controls what happens to the stats of members who leave and guilds that remove Pingchu.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LifecycleConfig {
+    /// Anything other than `keep` needs the privileged server members intent.
+    pub departed_members: RetentionPolicy,
+    pub removed_guilds: RetentionPolicy,
+    /// How long to wait before purging, so members and guilds that come back keep their history.
+    /// If unset, stats are purged as soon as possible.
+    #[serde(with = "crate::utils::humantime_option")]
+    pub purge_after: Option<Duration>,
+}
+
+impl Default for LifecycleConfig {
+    fn default() -> Self {
+        Self {
+            departed_members: RetentionPolicy::Keep,
+            removed_guilds: RetentionPolicy::Keep,
+            purge_after: Some(Duration::from_secs(30 * 24 * 60 * 60)),
+        }
+    }
+}
+
+/// Controls mass-mention raid detection, which applies to every allowed server. It's off until a limit is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RaidConfig {
+    /// A single message mentioning more users than this is a raid. If unset, messages aren't checked on their own.
+    pub max_mentions_per_message: Option<usize>,
+    /// More mentions than this within `window`, across all members, is a raid. If unset, rates aren't checked.
+    pub max_mentions_per_window: Option<usize>,
+    #[serde(with = "crate::utils::humantime_duration")]
+    pub window: Duration,
+    /// How long to wait before alerting about another raid.
+    #[serde(with = "crate::utils::humantime_duration")]
+    pub alert_cooldown: Duration,
+    /// Turns on raid mode when a raid is detected.
+    pub raid_mode: bool,
+    /// In raid mode, messages mentioning at least this many users are deleted.
+    pub raid_mode_min_mentions: usize,
+}
+
+impl Default for RaidConfig {
+    fn default() -> Self {
+        Self {
+            max_mentions_per_message: None,
+            max_mentions_per_window: None,
+            window: Duration::from_secs(60),
+            alert_cooldown: Duration::from_secs(5 * 60),
+            raid_mode: false,
+            raid_mode_min_mentions: 3,
+        }
+    }
+}
+
+/// Limits for per-server scripts in `scripts/<guild id>.rhai`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScriptsConfig {
+    /// How many operations a script can run for each ping before it's stopped.
+    pub max_operations: u64,
+    /// How long a script can run for each ping before it's stopped.
+    #[serde(with = "crate::utils::humantime_duration")]
+    pub timeout: Duration,
+    pub max_call_levels: usize,
+    pub max_string_size: usize,
+    /// Applies to both arrays and object maps.
+    pub max_collection_size: usize,
+    /// How many actions a script can return for each ping.
+    pub max_actions: usize,
+}
+
+impl Default for ScriptsConfig {
+    fn default() -> Self {
+        Self {
+            max_operations: 100_000,
+            timeout: Duration::from_millis(500),
+            max_call_levels: 32,
+            max_string_size: 10_000,
+            max_collection_size: 1_000,
+            max_actions: 5,
+        }
+    }
+}
+
+/// Reads the config file in `config_dir` without falling back to defaults or writing anything back.
+pub fn read_config(config_dir: &Path) -> Result<PingchuConfig> {
+    let config_file = config_dir.join(CONFIG_FILE);
+    let file = File::open(&config_file).with_context(|| format!("Couldn't open {}", config_file.display()))?;
+    serde_json::from_reader(file).with_context(|| format!("Couldn't parse {}", config_file.display()))
+}
+
+/// Reads the config file in `config_dir`, or the defaults if there isn't one yet, and writes it back so new properties
+/// show up in the file. A config that doesn't parse is an error rather than silently replaced by the defaults.
+pub fn load_config(config_dir: &Path) -> Result<PingchuConfig> {
+    let config_file = config_dir.join(CONFIG_FILE);
 
     // Read config from disk
     let config = if config_file.exists() {
-        let result: Result<PingchuConfig> = try { serde_json::from_reader(File::open(&config_file)?)? };
-        match result {
-            Ok(config) => config,
-            Err(err) => {
-                eprintln!(
-                    "Couldn't load {} config, using defaults: {}",
-                    &config_file.display(),
-                    err
-                );
-                PingchuConfig::default()
-            }
-        }
+        read_config(config_dir)?
     } else {
         PingchuConfig::default()
     };
@@ -85,8 +415,31 @@ pub fn load_config() -> PingchuConfig {
         serde_json::to_writer_pretty(File::create(&config_file)?, &config)?
     };
     if let Err(err) = write_result {
-        eprintln!("Couldn't update {} config: {}", &config_file.display(), err);
+        warn!("Couldn't update {} config: {}", &config_file.display(), err);
     }
 
-    config
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_configs_arent_replaced() {
+        let config_dir = std::env::temp_dir().join(format!("pingchu-config-{}", std::process::id()));
+        let config_file = config_dir.join(CONFIG_FILE);
+
+        // a missing config starts from the defaults and is written out
+        load_config(&config_dir).unwrap();
+        assert!(read_config(&config_dir).is_ok());
+
+        let broken = r#"{"ping_responses": ["hi {nmae}"]}"#;
+        fs::write(&config_file, broken).unwrap();
+        let err = load_config(&config_dir).unwrap_err();
+        assert!(format!("{:#}", err).contains("nmae"), "{:#}", err);
+        assert_eq!(fs::read_to_string(&config_file).unwrap(), broken);
+
+        fs::remove_dir_all(&config_dir).unwrap();
+    }
 }
@@ -0,0 +1,221 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use poise::serenity_prelude::{ChannelId, GuildId, Http, MessageId, UserId};
+use poise::BoxFuture;
+use tracing::{debug, warn};
+
+use crate::config::MentionCooldownConfig;
+use crate::metrics;
+
+/// Where a reply to a message that pinged Pingchu goes, which decides the cooldowns it's under.
+#[derive(Debug, Copy, Clone)]
+pub struct MentionReply {
+    pub guild: GuildId,
+    pub user: UserId,
+    pub channel: ChannelId,
+    pub message: MessageId,
+}
+
+/// Writes a reply right before it's sent, so responses aren't used up by pings that never get one.
+/// Returns `None` if there's nothing to reply with.
+pub type Compose = Box<dyn FnOnce() -> BoxFuture<'static, Result<Option<String>>> + Send>;
+
+/// What a cooldown applies to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CooldownKey {
+    User(GuildId, UserId),
+    Channel(ChannelId),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Cooldown {
+    /// Nothing is on cooldown, so reply right away.
+    Ready,
+    /// `key` is on cooldown until `until`. `tired` is set for the first ping to hit the cooldown.
+    Blocked {
+        key: CooldownKey,
+        until: Instant,
+        tired: bool,
+    },
+}
+
+#[derive(Default)]
+struct State {
+    /// When each user or channel can get another reply.
+    ready_at: HashMap<CooldownKey, Instant>,
+    /// Cooldowns that were already hit, so Pingchu only says it's tired once.
+    warned: HashSet<CooldownKey>,
+    /// The latest ping that hit each cooldown, to answer once it's over.
+    pending: HashMap<CooldownKey, (MentionReply, Compose)>,
+}
+
+/// Keeps members from farming replies by spam-pinging Pingchu.
+#[derive(Clone)]
+pub struct MentionCooldowns {
+    config: MentionCooldownConfig,
+    state: Arc<Mutex<State>>,
+}
+
+impl MentionCooldowns {
+    pub fn new(config: MentionCooldownConfig) -> Self {
+        Self {
+            config,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    fn keys(&self, reply: &MentionReply) -> Vec<(CooldownKey, Duration)> {
+        let user = self
+            .config
+            .per_user
+            .map(|x| (CooldownKey::User(reply.guild, reply.user), x));
+        let channel = self
+            .config
+            .per_channel
+            .map(|x| (CooldownKey::Channel(reply.channel), x));
+        user.into_iter().chain(channel).collect()
+    }
+
+    /// Checks whether a reply can be sent now, starting its cooldowns if it can.
+    pub fn check(&self, reply: &MentionReply, now: Instant) -> Cooldown {
+        let keys = self.keys(reply);
+        let mut state = self.state.lock().unwrap();
+        state.ready_at.retain(|_, x| *x > now);
+        let blocking = keys
+            .iter()
+            .filter_map(|(key, _)| state.ready_at.get(key).map(|x| (*key, *x)))
+            .max_by_key(|(_, until)| *until);
+        match blocking {
+            Some((key, until)) => Cooldown::Blocked {
+                key,
+                until,
+                tired: state.warned.insert(key),
+            },
+            None => {
+                for (key, duration) in keys {
+                    state.ready_at.insert(key, now + duration);
+                    state.warned.remove(&key);
+                }
+                Cooldown::Ready
+            }
+        }
+    }
+
+    /// Answers `reply` once `key`'s cooldown is over, unless a later ping replaces it first.
+    pub fn defer(&self, http: Arc<Http>, key: CooldownKey, until: Instant, reply: MentionReply, compose: Compose) {
+        if self
+            .state
+            .lock()
+            .unwrap()
+            .pending
+            .insert(key, (reply, compose))
+            .is_some()
+        {
+            // the reply is already scheduled
+            return;
+        }
+        let cooldowns = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(until.into()).await;
+            let reply = {
+                let mut state = cooldowns.state.lock().unwrap();
+                state.warned.remove(&key);
+                state.pending.remove(&key)
+            };
+            if let Some((reply, compose)) = reply {
+                // this still counts as a reply, so the cooldown starts over
+                match cooldowns.check(&reply, Instant::now()) {
+                    Cooldown::Ready => {
+                        debug!(?key, "Answering the latest ping after cooldown");
+                        let result = match compose().await {
+                            Ok(Some(content)) => send(&http, &reply, &content).await,
+                            Ok(None) => Ok(()),
+                            Err(err) => Err(err),
+                        };
+                        if let Err(err) = result {
+                            warn!("Couldn't send delayed reply to mention: {:#}", err);
+                        }
+                    }
+                    // another cooldown is still going
+                    Cooldown::Blocked { key, until, .. } => cooldowns.defer(http, key, until, reply, compose),
+                }
+            }
+        });
+    }
+}
+
+pub async fn send(http: &Http, reply: &MentionReply, content: &str) -> Result<()> {
+    reply
+        .channel
+        .send_message(http, |msg| {
+            msg.reference_message((reply.channel, reply.message)).content(content)
+        })
+        .await
+        .context("Couldn't reply to mention")?;
+    metrics::MENTION_REPLIES.inc();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn reply(message: u64) -> MentionReply {
+        MentionReply {
+            guild: GuildId(1),
+            user: UserId(10),
+            channel: ChannelId(100),
+            message: MessageId(message),
+        }
+    }
+
+    /// Counts how often a reply was written, writing nothing so no message is sent.
+    fn counting(written: &Arc<AtomicUsize>, id: usize) -> Compose {
+        let written = written.clone();
+        Box::new(move || {
+            Box::pin(async move {
+                written.fetch_add(id, Ordering::SeqCst);
+                Ok(None)
+            })
+        })
+    }
+
+    #[test]
+    fn cooldowns_are_off_by_default() {
+        let cooldowns = MentionCooldowns::new(MentionCooldownConfig::default());
+        let now = Instant::now();
+        for message in 1..5 {
+            assert_eq!(cooldowns.check(&reply(message), now), Cooldown::Ready);
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_are_only_written_once_the_cooldown_is_over() {
+        let cooldowns = MentionCooldowns::new(MentionCooldownConfig {
+            per_user: Some(Duration::from_millis(100)),
+            ..MentionCooldownConfig::default()
+        });
+        let http = Arc::new(Http::new(""));
+        assert_eq!(cooldowns.check(&reply(1), Instant::now()), Cooldown::Ready);
+
+        let written = Arc::new(AtomicUsize::new(0));
+        for (message, id) in [(2, 1), (3, 10)] {
+            match cooldowns.check(&reply(message), Instant::now()) {
+                Cooldown::Blocked { key, until, .. } => {
+                    cooldowns.defer(http.clone(), key, until, reply(message), counting(&written, id))
+                }
+                Cooldown::Ready => panic!("message {} should be on cooldown", message),
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(written.load(Ordering::SeqCst), 0);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // only the latest ping gets answered
+        assert_eq!(written.load(Ordering::SeqCst), 10);
+    }
+}
@@ -1,33 +1,111 @@
+pub mod departed_member;
+pub mod guild_allowlist;
 pub mod guild_ping;
+pub mod guild_removal;
+pub mod moderation_action;
+pub mod ping_incident;
+pub mod privacy_setting;
+pub mod raid_mode;
+pub mod response_bag;
+pub mod webhook_dead_letter;
 
+use std::fs;
 use std::fs::File;
 use std::path::Path;
-use std::{env, fs};
 
-use anyhow::Result;
-use sea_orm::{ConnectionTrait, Database, DatabaseConnection, DbErr, ExecResult, Schema, StatementBuilder};
+use anyhow::{Context, Result};
+use sea_orm::entity::Iterable;
+use sea_orm::sea_query::{OnConflict, Query};
+use sea_orm::{
+    ConnectionTrait, Database, DatabaseConnection, DbErr, EntityName, EntityTrait, ExecResult, Schema, Statement,
+    StatementBuilder, TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
 
-pub const DB_FILE: &str = ".data/sqlite.db";
+pub const DEFAULT_DATA_DIR: &str = ".data";
+pub const DB_FILE: &str = "sqlite.db";
 
-pub async fn load_database() -> Result<DatabaseConnection> {
-    let path = env::current_dir().unwrap().join(Path::new(DB_FILE));
+/// A portable dump of everything Pingchu stores, used by `pingchu export` and `pingchu import`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Backup {
+    pub guild_pings: Vec<guild_ping::Model>,
+    pub guild_allowlist: Vec<guild_allowlist::Model>,
+    pub ping_incidents: Vec<ping_incident::Model>,
+    pub privacy_settings: Vec<privacy_setting::Model>,
+    pub moderation_actions: Vec<moderation_action::Model>,
+    pub webhook_dead_letters: Vec<webhook_dead_letter::Model>,
+    pub departed_members: Vec<departed_member::Model>,
+    pub guild_removals: Vec<guild_removal::Model>,
+    pub response_bags: Vec<response_bag::Model>,
+    pub raid_modes: Vec<raid_mode::Model>,
+}
+
+pub async fn load_database(data_dir: &Path) -> Result<DatabaseConnection> {
+    let path = data_dir.join(DB_FILE);
     if !path.exists() {
-        fs::create_dir_all(path.parent().unwrap())?;
-        File::create(path)?;
+        fs::create_dir_all(data_dir)?;
+        File::create(&path)?;
     }
 
-    let database = Database::connect(format!("sqlite:{}", DB_FILE)).await?;
-    execute_query(
-        &database,
-        Schema::new(database.get_database_backend())
-            .create_table_from_entity(guild_ping::Entity)
-            .if_not_exists(),
-    )
-    .await?;
+    let database = Database::connect(format!("sqlite:{}", path.display())).await?;
+    migrate(&database).await?;
+
+    Ok(database)
+}
+
+/// Opens an empty database that only lives in memory.
+#[cfg(test)]
+pub async fn load_memory_database() -> Result<DatabaseConnection> {
+    // every connection to `sqlite::memory:` gets its own database, so the pool can only have one
+    let mut options = sea_orm::ConnectOptions::new("sqlite::memory:".to_string());
+    options.max_connections(1);
+    let database = Database::connect(options).await?;
+    migrate(&database).await?;
 
     Ok(database)
 }
 
+/// Creates any tables that don't exist yet.
+pub async fn migrate(database: &DatabaseConnection) -> Result<()> {
+    let schema = Schema::new(database.get_database_backend());
+    create_table(database, &schema, guild_ping::Entity).await?;
+    create_table(database, &schema, guild_allowlist::Entity).await?;
+    create_table(database, &schema, ping_incident::Entity).await?;
+    create_table(database, &schema, webhook_dead_letter::Entity).await?;
+    create_table(database, &schema, privacy_setting::Entity).await?;
+    create_table(database, &schema, departed_member::Entity).await?;
+    create_table(database, &schema, guild_removal::Entity).await?;
+    create_table(database, &schema, moderation_action::Entity).await?;
+    create_table(database, &schema, response_bag::Entity).await?;
+    create_table(database, &schema, raid_mode::Entity).await?;
+    Ok(())
+}
+
+async fn create_table<E: EntityTrait>(database: &DatabaseConnection, schema: &Schema, entity: E) -> Result<()> {
+    execute_query(database, schema.create_table_from_entity(entity).if_not_exists()).await?;
+    Ok(())
+}
+
+/// Closes the database, checkpointing the write-ahead log so every write ends up in the database file.
+pub async fn close_database(database: DatabaseConnection) -> Result<()> {
+    let backend = database.get_database_backend();
+    database
+        .execute(Statement::from_string(backend, "PRAGMA optimize".to_string()))
+        .await
+        .context("Couldn't optimize database before closing")?;
+    database
+        .execute(Statement::from_string(
+            backend,
+            "PRAGMA wal_checkpoint(TRUNCATE)".to_string(),
+        ))
+        .await
+        .context("Couldn't checkpoint database before closing")?;
+    // sea-orm 0.8 can't close the pool explicitly, but its connections close once every handle is dropped
+    drop(database);
+    Ok(())
+}
+
 pub async fn execute_query<C, S>(database: &C, query: &S) -> Result<ExecResult, DbErr>
 where
     C: ConnectionTrait,
@@ -36,3 +114,250 @@ where
     let backend = database.get_database_backend();
     database.execute(backend.build(query)).await
 }
+
+/// Dumps every table into a [`Backup`].
+pub async fn export_backup(database: &DatabaseConnection) -> Result<Backup> {
+    Ok(Backup {
+        guild_pings: guild_ping::Entity::find()
+            .all(database)
+            .await
+            .context("Couldn't fetch guild ping data")?,
+        guild_allowlist: guild_allowlist::Entity::find()
+            .all(database)
+            .await
+            .context("Couldn't fetch guild allowlist")?,
+        ping_incidents: ping_incident::Entity::find()
+            .all(database)
+            .await
+            .context("Couldn't fetch ping incidents")?,
+        privacy_settings: privacy_setting::Entity::find()
+            .all(database)
+            .await
+            .context("Couldn't fetch privacy settings")?,
+        moderation_actions: moderation_action::Entity::find()
+            .all(database)
+            .await
+            .context("Couldn't fetch moderation actions")?,
+        webhook_dead_letters: webhook_dead_letter::Entity::find()
+            .all(database)
+            .await
+            .context("Couldn't fetch webhook dead letters")?,
+        departed_members: departed_member::Entity::find()
+            .all(database)
+            .await
+            .context("Couldn't fetch departed members")?,
+        guild_removals: guild_removal::Entity::find()
+            .all(database)
+            .await
+            .context("Couldn't fetch guild removals")?,
+        response_bags: response_bag::Entity::find()
+            .all(database)
+            .await
+            .context("Couldn't fetch response bags")?,
+        raid_modes: raid_mode::Entity::find()
+            .all(database)
+            .await
+            .context("Couldn't fetch raid modes")?,
+    })
+}
+
+/// Loads a [`Backup`] into the database. Existing rows are overwritten by rows in the backup
+/// with the same key, and if `replace` is set, all other rows are deleted first.
+pub async fn import_backup(database: &DatabaseConnection, backup: Backup, replace: bool) -> Result<()> {
+    database
+        .transaction::<_, _, DbErr>(move |txn| {
+            Box::pin(async move {
+                if replace {
+                    guild_ping::Entity::delete_many().exec(txn).await?;
+                    guild_allowlist::Entity::delete_many().exec(txn).await?;
+                    ping_incident::Entity::delete_many().exec(txn).await?;
+                    privacy_setting::Entity::delete_many().exec(txn).await?;
+                    moderation_action::Entity::delete_many().exec(txn).await?;
+                    webhook_dead_letter::Entity::delete_many().exec(txn).await?;
+                    departed_member::Entity::delete_many().exec(txn).await?;
+                    guild_removal::Entity::delete_many().exec(txn).await?;
+                    response_bag::Entity::delete_many().exec(txn).await?;
+                    raid_mode::Entity::delete_many().exec(txn).await?;
+                }
+                for row in backup.guild_pings {
+                    let query = Query::insert()
+                        .into_table(guild_ping::Entity.table_ref())
+                        .columns(guild_ping::Column::iter())
+                        .values_panic([
+                            row.guild_id.into(),
+                            row.user_id.into(),
+                            row.last_everyone_ping.into(),
+                            row.last_here_ping.into(),
+                            row.last_role_ping.into(),
+                            row.last_user_ping.into(),
+                            row.pings.into(),
+                        ])
+                        .on_conflict(
+                            OnConflict::columns([guild_ping::Column::GuildId, guild_ping::Column::UserId])
+                                .update_columns(guild_ping::Column::iter())
+                                .to_owned(),
+                        )
+                        .to_owned();
+                    execute_query(txn, &query).await?;
+                }
+                for row in backup.guild_allowlist {
+                    guild_allowlist::Entity::delete_by_id(row.guild_id).exec(txn).await?;
+                    guild_allowlist::Entity::insert(guild_allowlist::ActiveModel::from(row))
+                        .exec(txn)
+                        .await?;
+                }
+                for row in backup.ping_incidents {
+                    ping_incident::Entity::delete_by_id(row.id).exec(txn).await?;
+                    ping_incident::Entity::insert(ping_incident::ActiveModel::from(row))
+                        .exec(txn)
+                        .await?;
+                }
+                for row in backup.privacy_settings {
+                    privacy_setting::Entity::delete_by_id((row.guild_id, row.user_id))
+                        .exec(txn)
+                        .await?;
+                    privacy_setting::Entity::insert(privacy_setting::ActiveModel::from(row))
+                        .exec(txn)
+                        .await?;
+                }
+                for row in backup.moderation_actions {
+                    moderation_action::Entity::delete_by_id(row.id).exec(txn).await?;
+                    moderation_action::Entity::insert(moderation_action::ActiveModel::from(row))
+                        .exec(txn)
+                        .await?;
+                }
+                for row in backup.webhook_dead_letters {
+                    webhook_dead_letter::Entity::delete_by_id(row.id).exec(txn).await?;
+                    webhook_dead_letter::Entity::insert(webhook_dead_letter::ActiveModel::from(row))
+                        .exec(txn)
+                        .await?;
+                }
+                for row in backup.departed_members {
+                    departed_member::Entity::delete_by_id((row.guild_id, row.user_id))
+                        .exec(txn)
+                        .await?;
+                    departed_member::Entity::insert(departed_member::ActiveModel::from(row))
+                        .exec(txn)
+                        .await?;
+                }
+                for row in backup.guild_removals {
+                    guild_removal::Entity::delete_by_id(row.guild_id).exec(txn).await?;
+                    guild_removal::Entity::insert(guild_removal::ActiveModel::from(row))
+                        .exec(txn)
+                        .await?;
+                }
+                for row in backup.response_bags {
+                    response_bag::Entity::delete_by_id((row.guild_id, row.user_id))
+                        .exec(txn)
+                        .await?;
+                    response_bag::Entity::insert(response_bag::ActiveModel::from(row))
+                        .exec(txn)
+                        .await?;
+                }
+                for row in backup.raid_modes {
+                    raid_mode::Entity::delete_by_id(row.guild_id).exec(txn).await?;
+                    raid_mode::Entity::insert(raid_mode::ActiveModel::from(row))
+                        .exec(txn)
+                        .await?;
+                }
+                Ok(())
+            })
+        })
+        .await
+        .context("Failed to import backup")
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::prelude::DateTimeUtc;
+
+    use super::*;
+    use crate::ping::PingKind;
+    use crate::quotas::QuotaAction;
+
+    #[tokio::test]
+    async fn backup_round_trips() {
+        let time = "2022-06-01T12:00:00Z".parse::<DateTimeUtc>().unwrap();
+        let backup = Backup {
+            guild_pings: vec![guild_ping::Model {
+                guild_id: 1,
+                user_id: 10,
+                last_everyone_ping: Some(time),
+                last_here_ping: None,
+                last_role_ping: None,
+                last_user_ping: Some(time),
+                pings: 3,
+            }],
+            guild_allowlist: vec![guild_allowlist::Model {
+                guild_id: 1,
+                allowed: true,
+                log_channel: Some(100),
+                updated_at: time,
+            }],
+            ping_incidents: vec![ping_incident::Model {
+                id: 1,
+                guild_id: 1,
+                user_id: 10,
+                channel_id: 100,
+                message_id: 1000,
+                kind: PingKind::Everyone,
+                timestamp: time,
+            }],
+            privacy_settings: vec![privacy_setting::Model {
+                guild_id: 1,
+                user_id: 11,
+                opted_out: false,
+                hidden: true,
+                updated_at: time,
+            }],
+            moderation_actions: vec![moderation_action::Model {
+                id: 1,
+                guild_id: 1,
+                user_id: 10,
+                channel_id: 100,
+                message_id: 1000,
+                rule: "3 @everyone ping(s) per 1h".to_string(),
+                action: QuotaAction::Delete,
+                error: None,
+                timestamp: time,
+            }],
+            webhook_dead_letters: vec![webhook_dead_letter::Model {
+                id: 1,
+                url: "http://localhost/hook".to_string(),
+                payload: "{}".to_string(),
+                attempts: 5,
+                last_error: "Endpoint responded with 500".to_string(),
+                failed_at: time,
+            }],
+            departed_members: vec![departed_member::Model {
+                guild_id: 1,
+                user_id: 12,
+                departed_at: time,
+            }],
+            guild_removals: vec![guild_removal::Model {
+                guild_id: 2,
+                removed_at: time,
+            }],
+            response_bags: vec![response_bag::Model {
+                guild_id: 1,
+                user_id: 0,
+                fingerprint: 42,
+                remaining: "[0,2]".to_string(),
+            }],
+            raid_modes: vec![raid_mode::Model {
+                guild_id: 1,
+                enabled_at: time,
+            }],
+        };
+
+        let database = load_memory_database().await.unwrap();
+        import_backup(&database, backup.clone(), false).await.unwrap();
+        // importing again overwrites rows instead of duplicating them
+        import_backup(&database, backup.clone(), false).await.unwrap();
+        let exported = export_backup(&database).await.unwrap();
+        assert_eq!(
+            serde_json::to_value(exported).unwrap(),
+            serde_json::to_value(backup).unwrap()
+        );
+    }
+}
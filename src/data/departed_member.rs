@@ -0,0 +1,31 @@
+use sea_orm::entity::prelude::*;
+use sea_orm::prelude::DateTimeUtc;
+use serde::{Deserialize, Serialize};
+
+/// Members who left a guild, kept until they rejoin or their stats are purged.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "DepartedMembers")]
+pub struct Model {
+    pub guild_id: i64,
+    pub user_id: i64,
+    pub departed_at: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DerivePrimaryKey)]
+pub enum PrimaryKey {
+    GuildId,
+    UserId,
+}
+
+impl PrimaryKeyTrait for PrimaryKey {
+    type ValueType = (i64, i64);
+
+    fn auto_increment() -> bool {
+        false
+    }
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,19 @@
+use sea_orm::entity::prelude::*;
+use sea_orm::prelude::DateTimeUtc;
+use serde::{Deserialize, Serialize};
+
+/// Allowlist overrides set by bot owners at runtime, which take priority over `allowed_servers`.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "GuildAllowlist")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub guild_id: i64,
+    pub allowed: bool,
+    pub log_channel: Option<i64>,
+    pub updated_at: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
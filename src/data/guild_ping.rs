@@ -1,8 +1,9 @@
 use sea_orm::entity::prelude::*;
 use sea_orm::prelude::DateTimeUtc;
+use serde::{Deserialize, Serialize};
 
 // note: we store all u64s as i64 since sqlite doesn't technically support u64
-#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "GuildPings")]
 pub struct Model {
     pub guild_id: i64,
@@ -0,0 +1,17 @@
+use sea_orm::entity::prelude::*;
+use sea_orm::prelude::DateTimeUtc;
+use serde::{Deserialize, Serialize};
+
+/// Guilds that removed Pingchu, kept until Pingchu is added back or their stats are purged.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "GuildRemovals")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub guild_id: i64,
+    pub removed_at: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
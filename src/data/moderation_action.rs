@@ -0,0 +1,29 @@
+use sea_orm::entity::prelude::*;
+use sea_orm::prelude::DateTimeUtc;
+use serde::{Deserialize, Serialize};
+
+use crate::quotas::QuotaAction;
+
+/// Something Pingchu did to a member who went over a quota, whether or not it worked.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "ModerationActions")]
+pub struct Model {
+    // note: this is an i32 since sqlite only autoincrements INTEGER primary keys
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub guild_id: i64,
+    pub user_id: i64,
+    pub channel_id: i64,
+    pub message_id: i64,
+    /// The quota that was broken, like `3 @everyone/@here ping(s) per 1h`.
+    pub rule: String,
+    pub action: QuotaAction,
+    /// Why the action failed, like missing permissions.
+    pub error: Option<String>,
+    pub timestamp: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,25 @@
+use sea_orm::entity::prelude::*;
+use sea_orm::prelude::DateTimeUtc;
+use serde::{Deserialize, Serialize};
+
+use crate::ping::PingKind;
+
+/// A single @everyone or @here ping, kept so incidents can be listed later.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "PingIncidents")]
+pub struct Model {
+    // note: this is an i32 since sqlite only autoincrements INTEGER primary keys
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub guild_id: i64,
+    pub user_id: i64,
+    pub channel_id: i64,
+    pub message_id: i64,
+    pub kind: PingKind,
+    pub timestamp: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,35 @@
+use sea_orm::entity::prelude::*;
+use sea_orm::prelude::DateTimeUtc;
+use serde::{Deserialize, Serialize};
+
+/// A member's privacy choices on one guild, or on every guild if `guild_id` is [`crate::privacy::GLOBAL`].
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "PrivacySettings")]
+pub struct Model {
+    pub guild_id: i64,
+    pub user_id: i64,
+    /// Pings by this member aren't tracked at all.
+    pub opted_out: bool,
+    /// This member's stats are hidden from other members and leaderboards.
+    pub hidden: bool,
+    pub updated_at: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DerivePrimaryKey)]
+pub enum PrimaryKey {
+    GuildId,
+    UserId,
+}
+
+impl PrimaryKeyTrait for PrimaryKey {
+    type ValueType = (i64, i64);
+
+    fn auto_increment() -> bool {
+        false
+    }
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
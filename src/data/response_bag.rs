@@ -0,0 +1,34 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Responses left to say before any of them repeat, for a guild or one of its members.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "ResponseBags")]
+pub struct Model {
+    pub guild_id: i64,
+    /// `0` for the bag shared by the whole guild.
+    pub user_id: i64,
+    /// Identifies the `ping_responses` the bag was filled from, so it's thrown out when they change.
+    pub fingerprint: i64,
+    /// Indices into `ping_responses` left in the bag, as JSON.
+    pub remaining: String,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DerivePrimaryKey)]
+pub enum PrimaryKey {
+    GuildId,
+    UserId,
+}
+
+impl PrimaryKeyTrait for PrimaryKey {
+    type ValueType = (i64, i64);
+
+    fn auto_increment() -> bool {
+        false
+    }
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
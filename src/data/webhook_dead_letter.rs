@@ -0,0 +1,21 @@
+use sea_orm::entity::prelude::*;
+use sea_orm::prelude::DateTimeUtc;
+use serde::{Deserialize, Serialize};
+
+/// A webhook delivery that failed every attempt, kept so it can be inspected or replayed.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "WebhookDeadLetters")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub url: String,
+    pub payload: String,
+    pub attempts: u32,
+    pub last_error: String,
+    pub failed_at: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
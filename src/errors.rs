@@ -0,0 +1,131 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use poise::serenity_prelude::{ChannelId, Http, Timestamp};
+use poise::{BoxFuture, FrameworkError};
+use tracing::error;
+
+use crate::commands::apply_ui;
+use crate::config::ErrorReportConfig;
+use crate::{command_span, metrics, Pingchu};
+
+/// Discord's limit on embed descriptions.
+const MAX_DESCRIPTION_LENGTH: usize = 4096;
+
+/// Forwards errors to a debug channel, skipping reports that come in too quickly after each other.
+pub struct ErrorReporter {
+    channel: Option<ChannelId>,
+    min_interval: Option<Duration>,
+    state: Mutex<ReporterState>,
+}
+
+#[derive(Default)]
+struct ReporterState {
+    last_report: Option<Instant>,
+    suppressed: usize,
+}
+
+impl ErrorReporter {
+    pub fn new(config: &ErrorReportConfig) -> Self {
+        Self {
+            channel: config.channel,
+            min_interval: config.min_interval,
+            state: Mutex::new(ReporterState::default()),
+        }
+    }
+
+    /// Posts `error` and its whole chain of causes to the debug channel, if there is one.
+    pub async fn report(&self, http: &Http, context: &str, error: &Error) {
+        let channel = match self.channel {
+            Some(x) => x,
+            None => return,
+        };
+        let suppressed = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let throttled = matches!(
+                (state.last_report, self.min_interval),
+                (Some(last), Some(interval)) if now.duration_since(last) < interval
+            );
+            if throttled {
+                state.suppressed += 1;
+                return;
+            }
+            state.last_report = Some(now);
+            std::mem::take(&mut state.suppressed)
+        };
+
+        let mut description = format!("```\n{:?}", error);
+        let mut end = description.len().min(MAX_DESCRIPTION_LENGTH - 4);
+        while !description.is_char_boundary(end) {
+            end -= 1;
+        }
+        description.truncate(end);
+        description.push_str("\n```");
+        let result = channel
+            .send_message(http, |msg| {
+                msg.add_embed(|embed| {
+                    apply_ui(embed, None, Timestamp::now());
+                    embed.title(format!("Error in {}", context)).description(description);
+                    if suppressed > 0 {
+                        embed.field("Errors skipped since last report", suppressed, false);
+                    }
+                    embed
+                })
+            })
+            .await;
+        if let Err(err) = result {
+            error!("Couldn't report error to {}: {}", channel, err);
+        }
+    }
+}
+
+pub fn on_error(error: FrameworkError<'_, Pingchu, Error>) -> BoxFuture<'_, ()> {
+    Box::pin(async move {
+        match error {
+            FrameworkError::Listener { error, event, .. } => {
+                // the listener reports its own errors since it has access to an `Http`
+                error!(event = event.name(), "Error while handling event: {:?}", error);
+            }
+            FrameworkError::Command { error, ctx, .. } => {
+                command_span(ctx).in_scope(|| error!("Error while running command: {:?}", error));
+                metrics::COMMANDS
+                    .with_label_values(&[&ctx.command().qualified_name, "error"])
+                    .inc();
+
+                let timestamp = ctx.created_at();
+                let reply = ctx
+                    .send(|msg| {
+                        msg.ephemeral(true).embed(|embed| {
+                            apply_ui(embed, Some(ctx.author()), timestamp);
+                            embed
+                                .title("Ping'chu tripped over a wire!")
+                                .description(format!("Something went wrong: {}", error))
+                        })
+                    })
+                    .await;
+                if let Err(err) = reply {
+                    error!("Couldn't reply with error: {}", err);
+                }
+
+                let context = match ctx.guild_id() {
+                    Some(guild) => format!(
+                        "/{} (guild {}, channel {}, user {})",
+                        ctx.command().qualified_name,
+                        guild,
+                        ctx.channel_id(),
+                        ctx.author().id
+                    ),
+                    None => format!("/{} (DM, user {})", ctx.command().qualified_name, ctx.author().id),
+                };
+                ctx.data().errors.report(&ctx.discord().http, &context, &error).await;
+            }
+            error => {
+                if let Err(err) = poise::builtins::on_error(error).await {
+                    error!("Error while handling error: {:?}", err);
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,77 @@
+use poise::serenity_prelude::{Message, Timestamp};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::ping::PingKind;
+
+/// How many events can be buffered for a subscriber before it starts missing them.
+const CAPACITY: usize = 256;
+
+/// Something that happened in `ping_listener` that other parts of Pingchu might care about.
+// note: ids are serialized as strings since JSON numbers can't hold snowflakes
+#[derive(Debug, Clone, Serialize)]
+pub struct PingEvent {
+    #[serde(rename = "type")]
+    pub event_type: PingEventType,
+    pub guild_id: String,
+    pub channel_id: String,
+    pub message_id: String,
+    pub user_id: String,
+    pub kind: PingKind,
+    pub count: usize,
+    pub timestamp: Timestamp,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PingEventType {
+    /// Someone pinged something and it was recorded.
+    Ping,
+    /// Someone pinged @everyone or @here.
+    Incident,
+}
+
+impl PingEvent {
+    pub fn new(event_type: PingEventType, message: &Message, kind: PingKind, count: usize) -> Self {
+        Self {
+            event_type,
+            guild_id: message.guild_id.map(|x| x.to_string()).unwrap_or_default(),
+            channel_id: message.channel_id.to_string(),
+            message_id: message.id.to_string(),
+            user_id: message.author.id.to_string(),
+            kind,
+            count,
+            timestamp: message.timestamp,
+        }
+    }
+}
+
+/// Fans [`PingEvent`]s out to any number of subscribers. Publishing never waits on subscribers;
+/// ones that fall too far behind miss events instead.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<PingEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+        }
+    }
+
+    pub fn publish(&self, event: PingEvent) {
+        // this only fails if nobody is subscribed
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PingEvent> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
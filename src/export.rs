@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use poise::serenity_prelude::GuildId;
+use sea_orm::prelude::DateTimeUtc;
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder};
+use serde::Serialize;
+
+use crate::data::{guild_ping, ping_incident};
+use crate::names::Names;
+use crate::ping::PingKind;
+
+/// File formats for exports meant for spreadsheets and scripts, as opposed to backups.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// `pings.csv` and `incidents.csv`.
+    Csv,
+    /// A single `pings.json` holding both tables.
+    Json,
+}
+
+impl ExportFormat {
+    pub fn parse(text: &str) -> Result<Self> {
+        Self::from_str(text, true).map_err(|_| anyhow!("Unknown export format `{}`, expected csv or json", text))
+    }
+}
+
+/// Parses an RFC 3339 timestamp, or a `YYYY-MM-DD` date meaning midnight UTC.
+pub fn parse_date(text: &str) -> Result<DateTimeUtc> {
+    let text = text.trim();
+    text.parse()
+        .or_else(|_| format!("{}T00:00:00Z", text).parse())
+        .with_context(|| format!("Couldn't parse date `{}`, try YYYY-MM-DD", text))
+}
+
+/// Limits which rows end up in an export.
+#[derive(Debug, Default, Clone)]
+pub struct ExportFilter {
+    /// Only rows with pings at or after this time.
+    pub since: Option<DateTimeUtc>,
+    /// Only rows with pings before this time.
+    pub until: Option<DateTimeUtc>,
+    /// Only these kinds of pings. If empty, every kind is included.
+    pub kinds: Vec<PingKind>,
+}
+
+impl ExportFilter {
+    fn includes(&self, kind: PingKind, time: DateTimeUtc) -> bool {
+        (self.kinds.is_empty() || self.kinds.contains(&kind))
+            && self.since.map_or(true, |x| time >= x)
+            && self.until.map_or(true, |x| time < x)
+    }
+
+    /// Members only have their last ping of each kind stored, so they're included if any of those matches.
+    pub fn matches_member(&self, row: &guild_ping::Model) -> bool {
+        [
+            (PingKind::Everyone, row.last_everyone_ping),
+            (PingKind::Here, row.last_here_ping),
+            (PingKind::Role, row.last_role_ping),
+            (PingKind::User, row.last_user_ping),
+        ]
+        .into_iter()
+        .any(|(kind, time)| time.map_or(false, |time| self.includes(kind, time)))
+    }
+
+    pub fn matches_incident(&self, row: &ping_incident::Model) -> bool {
+        self.includes(row.kind, row.timestamp)
+    }
+}
+
+#[derive(Serialize)]
+pub struct MemberRow {
+    pub guild_id: String,
+    pub user_id: String,
+    pub name: String,
+    pub pings: u32,
+    pub last_everyone_ping: Option<DateTimeUtc>,
+    pub last_here_ping: Option<DateTimeUtc>,
+    pub last_role_ping: Option<DateTimeUtc>,
+    pub last_user_ping: Option<DateTimeUtc>,
+}
+
+#[derive(Serialize)]
+pub struct IncidentRow {
+    pub guild_id: String,
+    pub user_id: String,
+    pub name: String,
+    pub channel_id: String,
+    pub message_id: String,
+    pub kind: PingKind,
+    pub timestamp: DateTimeUtc,
+}
+
+/// Ping stats and @everyone/@here history, flattened for analysis.
+#[derive(Serialize)]
+pub struct Export {
+    pub pings: Vec<MemberRow>,
+    pub incidents: Vec<IncidentRow>,
+}
+
+impl Export {
+    /// Fetches matching rows for one guild, or every guild if `guild` is `None`.
+    pub async fn load(database: &DatabaseConnection, guild: Option<GuildId>, filter: &ExportFilter) -> Result<Self> {
+        let mut pings = guild_ping::Entity::find().order_by_desc(guild_ping::Column::Pings);
+        let mut incidents = ping_incident::Entity::find().order_by_asc(ping_incident::Column::Timestamp);
+        if let Some(guild) = guild {
+            pings = pings.filter(guild_ping::Column::GuildId.eq(guild.0 as i64));
+            incidents = incidents.filter(ping_incident::Column::GuildId.eq(guild.0 as i64));
+        }
+        let pings = pings.all(database).await.context("Couldn't fetch guild ping data")?;
+        let incidents = incidents.all(database).await.context("Couldn't fetch ping incidents")?;
+
+        Ok(Self {
+            pings: pings
+                .into_iter()
+                .filter(|x| filter.matches_member(x))
+                .map(|x| MemberRow {
+                    guild_id: (x.guild_id as u64).to_string(),
+                    user_id: (x.user_id as u64).to_string(),
+                    name: (x.user_id as u64).to_string(),
+                    pings: x.pings,
+                    last_everyone_ping: x.last_everyone_ping,
+                    last_here_ping: x.last_here_ping,
+                    last_role_ping: x.last_role_ping,
+                    last_user_ping: x.last_user_ping,
+                })
+                .collect(),
+            incidents: incidents
+                .into_iter()
+                .filter(|x| filter.matches_incident(x))
+                .map(|x| IncidentRow {
+                    guild_id: (x.guild_id as u64).to_string(),
+                    user_id: (x.user_id as u64).to_string(),
+                    name: (x.user_id as u64).to_string(),
+                    channel_id: (x.channel_id as u64).to_string(),
+                    message_id: (x.message_id as u64).to_string(),
+                    kind: x.kind,
+                    timestamp: x.timestamp,
+                })
+                .collect(),
+        })
+    }
+
+    /// Every `(guild, user)` in the export, to look up names for.
+    pub fn members(&self) -> HashMap<GuildId, Vec<u64>> {
+        let mut members: HashMap<GuildId, Vec<u64>> = HashMap::new();
+        let ids = self
+            .pings
+            .iter()
+            .map(|x| (&x.guild_id, &x.user_id))
+            .chain(self.incidents.iter().map(|x| (&x.guild_id, &x.user_id)));
+        for (guild, user) in ids {
+            if let (Ok(guild), Ok(user)) = (guild.parse(), user.parse()) {
+                members.entry(GuildId(guild)).or_default().push(user);
+            }
+        }
+        for users in members.values_mut() {
+            users.sort_unstable();
+            users.dedup();
+        }
+        members
+    }
+
+    /// Replaces user ids in the `name` columns with display names, where `names` knows them.
+    pub fn resolve_names(&mut self, names: &HashMap<GuildId, Names>) {
+        let lookup = |guild: &str, user: &str| {
+            let names = guild.parse().ok().and_then(|x| names.get(&GuildId(x)))?;
+            user.parse().ok().map(|x| names.get(x))
+        };
+        for row in &mut self.pings {
+            if let Some(name) = lookup(&row.guild_id, &row.user_id) {
+                row.name = name;
+            }
+        }
+        for row in &mut self.incidents {
+            if let Some(name) = lookup(&row.guild_id, &row.user_id) {
+                row.name = name;
+            }
+        }
+    }
+
+    /// Renders the export as `(file name, contents)` pairs.
+    pub fn files(&self, format: ExportFormat) -> Result<Vec<(String, Vec<u8>)>> {
+        Ok(match format {
+            ExportFormat::Csv => vec![
+                ("pings.csv".to_string(), to_csv(&self.pings)?),
+                ("incidents.csv".to_string(), to_csv(&self.incidents)?),
+            ],
+            ExportFormat::Json => vec![(
+                "pings.json".to_string(),
+                serde_json::to_vec_pretty(self).context("Couldn't serialize export")?,
+            )],
+        })
+    }
+}
+
+fn to_csv<T: Serialize>(rows: &[T]) -> Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for row in rows {
+        writer.serialize(row).context("Couldn't serialize export")?;
+    }
+    writer.into_inner().context("Couldn't serialize export")
+}
@@ -0,0 +1,79 @@
+pub mod api;
+pub mod feed;
+pub mod health;
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use axum::extract::Extension;
+use axum::routing::get;
+use axum::Router;
+use sea_orm::DatabaseConnection;
+use tracing::{error, info};
+
+use crate::config::HttpConfig;
+use crate::events::EventBus;
+use crate::http::api::ApiTokens;
+use crate::http::health::Health;
+use crate::metrics;
+use crate::servers::ServerRegistry;
+
+/// Everything HTTP handlers can get at, which exists before Pingchu connects to Discord.
+#[derive(Clone)]
+pub struct HttpState {
+    pub database: DatabaseConnection,
+    pub health: Arc<Health>,
+    pub servers: Arc<ServerRegistry>,
+    pub events: EventBus,
+}
+
+/// Builds the router for every enabled endpoint.
+pub fn router(config: &HttpConfig, state: HttpState) -> Router {
+    let mut router = Router::new();
+    if config.metrics {
+        router = router.route("/metrics", get(|| async { metrics::render() }));
+    }
+    if config.health {
+        router = router
+            .route("/healthz", get(health::healthz))
+            .route("/readyz", get(health::readyz));
+    }
+    if config.api {
+        router = router.nest("/api/v1", api::router());
+    }
+    if config.feed {
+        router = router.route("/feed", get(feed::feed));
+    }
+    router
+        .layer(Extension(state.database))
+        .layer(Extension(state.health))
+        .layer(Extension(state.servers))
+        .layer(Extension(state.events))
+        .layer(Extension(ApiTokens::new(&config.api_tokens)))
+}
+
+/// Serves HTTP in the background if the config asks for it.
+pub fn spawn(config: &HttpConfig, state: HttpState) {
+    if let Some(bind) = config.bind {
+        let router = router(config, state);
+        tokio::spawn(async move {
+            info!("Serving HTTP on {}", bind);
+            let result: Result<()> = axum::Server::bind(&bind)
+                .serve(router.into_make_service())
+                .await
+                .context("HTTP server crashed");
+            if let Err(err) = result {
+                error!("{:?}", err);
+            }
+        });
+    }
+}
+
+/// Serves `router` on a free local port, for tests that need a real HTTP server.
+#[cfg(test)]
+pub fn serve_locally(router: Router) -> std::net::SocketAddr {
+    let server = axum::Server::bind(&([127, 0, 0, 1], 0).into()).serve(router.into_make_service());
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::Error;
+use axum::extract::{Extension, FromRequest, Path, Query, RequestParts};
+use axum::http::header::AUTHORIZATION;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{async_trait, Json, Router};
+use poise::serenity_prelude::{GuildId, UserId};
+use sea_orm::prelude::DateTimeUtc;
+use sea_orm::DatabaseConnection;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::data::{guild_ping, ping_incident};
+use crate::ping::{self, PingKind};
+use crate::servers::ServerRegistry;
+use crate::{lifecycle, privacy};
+
+/// The most rows a single list request can return.
+const MAX_LIMIT: u64 = 100;
+
+/// API tokens, keyed by token, with the consumer's name as the value.
+#[derive(Clone)]
+pub struct ApiTokens(pub Arc<HashMap<String, String>>);
+
+impl ApiTokens {
+    /// Inverts `consumer -> token` from the config.
+    pub fn new(tokens: &HashMap<String, String>) -> Self {
+        Self(Arc::new(
+            tokens
+                .iter()
+                .map(|(consumer, token)| (token.clone(), consumer.clone()))
+                .collect(),
+        ))
+    }
+
+    /// Gets the name of the consumer a token belongs to.
+    pub fn consumer(&self, token: &str) -> Option<&str> {
+        self.0.get(token.trim()).map(String::as_str)
+    }
+}
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/guilds/:guild/summary", get(guild_summary))
+        .route("/guilds/:guild/leaderboard", get(guild_leaderboard))
+        .route("/guilds/:guild/incidents", get(recent_incidents))
+        .route("/guilds/:guild/members/:user", get(member_stats))
+}
+
+/// A request authenticated with `Authorization: Bearer <token>`.
+pub struct Consumer(pub String);
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Consumer {
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(tokens) = Extension::<ApiTokens>::from_request(req)
+            .await
+            .map_err(|_| ApiError::Unauthorized)?;
+        req.headers()
+            .get(AUTHORIZATION)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.strip_prefix("Bearer "))
+            .and_then(|token| tokens.consumer(token))
+            .map(|consumer| Consumer(consumer.to_string()))
+            .ok_or(ApiError::Unauthorized)
+    }
+}
+
+pub enum ApiError {
+    BadRequest(&'static str),
+    Unauthorized,
+    NotFound,
+    Internal(Error),
+}
+
+impl From<Error> for ApiError {
+    fn from(err: Error) -> Self {
+        Self::Internal(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            Self::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            Self::Unauthorized => (StatusCode::UNAUTHORIZED, "missing or invalid API token"),
+            Self::NotFound => (StatusCode::NOT_FOUND, "not found"),
+            Self::Internal(err) => {
+                error!("Error while handling API request: {:?}", err);
+                (StatusCode::INTERNAL_SERVER_ERROR, "internal error")
+            }
+        };
+        (status, Json(serde_json::json!({ "error": message }))).into_response()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Limit {
+    limit: Option<u64>,
+}
+
+impl Limit {
+    fn get(&self, default: u64) -> u64 {
+        self.limit.unwrap_or(default).min(MAX_LIMIT)
+    }
+}
+
+// note: ids are serialized as strings since JSON numbers can't hold snowflakes
+#[derive(Serialize)]
+pub struct MemberStats {
+    pub user_id: String,
+    pub pings: u32,
+    pub last_everyone_ping: Option<DateTimeUtc>,
+    pub last_here_ping: Option<DateTimeUtc>,
+    pub last_role_ping: Option<DateTimeUtc>,
+    pub last_user_ping: Option<DateTimeUtc>,
+    /// Whether the member left the guild.
+    pub departed: bool,
+}
+
+impl From<guild_ping::Model> for MemberStats {
+    fn from(x: guild_ping::Model) -> Self {
+        Self {
+            user_id: (x.user_id as u64).to_string(),
+            pings: x.pings,
+            last_everyone_ping: x.last_everyone_ping,
+            last_here_ping: x.last_here_ping,
+            last_role_ping: x.last_role_ping,
+            last_user_ping: x.last_user_ping,
+            departed: false,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Incident {
+    pub user_id: String,
+    pub channel_id: String,
+    pub message_id: String,
+    pub kind: PingKind,
+    pub timestamp: DateTimeUtc,
+}
+
+impl From<ping_incident::Model> for Incident {
+    fn from(x: ping_incident::Model) -> Self {
+        Self {
+            user_id: (x.user_id as u64).to_string(),
+            channel_id: (x.channel_id as u64).to_string(),
+            message_id: (x.message_id as u64).to_string(),
+            kind: x.kind,
+            timestamp: x.timestamp,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct GuildSummary {
+    pub guild_id: String,
+    pub members: usize,
+    pub total_pings: u64,
+    pub last_everyone_ping: Option<DateTimeUtc>,
+    pub last_here_ping: Option<DateTimeUtc>,
+}
+
+/// Parses a guild id from the path, pretending guilds that aren't allowed don't exist.
+fn allowed_guild(servers: &ServerRegistry, guild: &str) -> Result<GuildId, ApiError> {
+    guild
+        .parse()
+        .ok()
+        .map(GuildId)
+        .filter(|x| servers.is_allowed(*x))
+        .ok_or(ApiError::NotFound)
+}
+
+async fn guild_summary(
+    _: Consumer,
+    Path(guild): Path<String>,
+    Extension(database): Extension<DatabaseConnection>,
+    Extension(servers): Extension<Arc<ServerRegistry>>,
+) -> Result<Json<GuildSummary>, ApiError> {
+    let guild = allowed_guild(&servers, &guild)?;
+    let rows = ping::guild_pings(&database, guild).await?;
+    Ok(Json(GuildSummary {
+        guild_id: guild.to_string(),
+        members: rows.len(),
+        total_pings: rows.iter().map(|x| x.pings as u64).sum(),
+        last_everyone_ping: rows.iter().filter_map(|x| x.last_everyone_ping).max(),
+        last_here_ping: rows.iter().filter_map(|x| x.last_here_ping).max(),
+    }))
+}
+
+async fn guild_leaderboard(
+    _: Consumer,
+    Path(guild): Path<String>,
+    Query(limit): Query<Limit>,
+    Extension(database): Extension<DatabaseConnection>,
+    Extension(servers): Extension<Arc<ServerRegistry>>,
+) -> Result<Json<Vec<MemberStats>>, ApiError> {
+    let guild = allowed_guild(&servers, &guild)?;
+    let leaderboard = ping::guild_leaderboard(&database, guild, limit.get(10)).await?;
+    let departed = lifecycle::departed_users(&database, guild).await?;
+    Ok(Json(
+        leaderboard
+            .into_iter()
+            .map(|x| MemberStats {
+                departed: departed.contains(&x.user_id),
+                ..MemberStats::from(x)
+            })
+            .collect(),
+    ))
+}
+
+async fn recent_incidents(
+    _: Consumer,
+    Path(guild): Path<String>,
+    Query(limit): Query<Limit>,
+    Extension(database): Extension<DatabaseConnection>,
+    Extension(servers): Extension<Arc<ServerRegistry>>,
+) -> Result<Json<Vec<Incident>>, ApiError> {
+    let guild = allowed_guild(&servers, &guild)?;
+    let incidents = ping::recent_incidents(&database, guild, limit.get(20)).await?;
+    Ok(Json(incidents.into_iter().map(Incident::from).collect()))
+}
+
+async fn member_stats(
+    _: Consumer,
+    Path((guild, user)): Path<(String, String)>,
+    Extension(database): Extension<DatabaseConnection>,
+    Extension(servers): Extension<Arc<ServerRegistry>>,
+) -> Result<Json<MemberStats>, ApiError> {
+    let guild = allowed_guild(&servers, &guild)?;
+    let user = user.parse().map(UserId).map_err(|_| ApiError::NotFound)?;
+    if privacy::settings(&database, guild, user).await?.hidden {
+        return Err(ApiError::NotFound);
+    }
+    let departed = lifecycle::departed_users(&database, guild).await?;
+    ping::member_ping_info(&database, guild, user)
+        .await?
+        .map(|x| {
+            Json(MemberStats {
+                departed: departed.contains(&x.user_id),
+                ..MemberStats::from(x)
+            })
+        })
+        .ok_or(ApiError::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use poise::serenity_prelude::ChannelId;
+    use sea_orm::{EntityTrait, Set};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::config::{HttpConfig, PingchuConfig, ServerConfig};
+    use crate::data;
+    use crate::events::EventBus;
+    use crate::http::health::Health;
+    use crate::http::{self, HttpState};
+
+    const ALLOWED: u64 = 1;
+    const DISALLOWED: u64 = 2;
+    const TOKEN: &str = "hunter2";
+
+    /// Serves the API with two members on an allowed guild and one on a guild that isn't allowed.
+    async fn serve() -> String {
+        let database = data::load_memory_database().await.unwrap();
+        let everyone_ping = "2022-06-01T12:00:00Z".parse::<DateTimeUtc>().unwrap();
+        for (guild, user, pings, last_everyone_ping) in [
+            (ALLOWED, 10, 3, None),
+            (ALLOWED, 11, 5, Some(everyone_ping)),
+            (DISALLOWED, 12, 7, None),
+        ] {
+            guild_ping::Entity::insert(guild_ping::ActiveModel {
+                guild_id: Set(guild as i64),
+                user_id: Set(user),
+                last_everyone_ping: Set(last_everyone_ping),
+                last_here_ping: Set(None),
+                last_role_ping: Set(None),
+                last_user_ping: Set(None),
+                pings: Set(pings),
+            })
+            .exec(&database)
+            .await
+            .unwrap();
+        }
+        // member 13 hid their stats, and their incident is the most recent one
+        for (user, day) in [(11, 1), (13, 2)] {
+            ping_incident::Entity::insert(ping_incident::ActiveModel {
+                guild_id: Set(ALLOWED as i64),
+                user_id: Set(user),
+                channel_id: Set(100),
+                message_id: Set(user * 10),
+                kind: Set(PingKind::Everyone),
+                timestamp: Set(format!("2022-06-0{}T12:00:00Z", day).parse().unwrap()),
+                ..Default::default()
+            })
+            .exec(&database)
+            .await
+            .unwrap();
+        }
+        privacy::update(&database, None, UserId(13), |x| x.hidden = true)
+            .await
+            .unwrap();
+
+        let mut config = PingchuConfig::default();
+        config
+            .allowed_servers
+            .insert(GuildId(ALLOWED), ServerConfig::new(ChannelId(100)));
+        let servers = Arc::new(ServerRegistry::load(&config, &database).await.unwrap());
+        let http_config = HttpConfig {
+            api: true,
+            api_tokens: HashMap::from([("tests".to_string(), TOKEN.to_string())]),
+            ..HttpConfig::default()
+        };
+        let state = HttpState {
+            database,
+            health: Health::new(),
+            servers,
+            events: EventBus::new(),
+        };
+        format!(
+            "http://{}/api/v1",
+            http::serve_locally(http::router(&http_config, state))
+        )
+    }
+
+    async fn get(url: &str, token: Option<&str>) -> (u16, Value) {
+        let mut request = reqwest::Client::new().get(url);
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.unwrap();
+        let status = response.status().as_u16();
+        (status, serde_json::from_str(&response.text().await.unwrap()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_and_invalid_tokens() {
+        let api = serve().await;
+        let url = format!("{}/guilds/{}/summary", api, ALLOWED);
+        let unauthorized = (401, json!({ "error": "missing or invalid API token" }));
+        assert_eq!(get(&url, None).await, unauthorized);
+        assert_eq!(get(&url, Some("hunter3")).await, unauthorized);
+        let response = reqwest::Client::new()
+            .get(&url)
+            .header(AUTHORIZATION, TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 401);
+    }
+
+    #[tokio::test]
+    async fn hides_guilds_that_arent_allowed() {
+        let api = serve().await;
+        let not_found = (404, json!({ "error": "not found" }));
+        for guild in [DISALLOWED.to_string(), "3".to_string(), "pingchu".to_string()] {
+            let url = format!("{}/guilds/{}/leaderboard", api, guild);
+            assert_eq!(get(&url, Some(TOKEN)).await, not_found);
+        }
+        let url = format!("{}/guilds/{}/leaderboard", api, ALLOWED);
+        assert_eq!(get(&url, Some(TOKEN)).await.0, 200);
+    }
+
+    #[tokio::test]
+    async fn serves_leaderboard() {
+        let api = serve().await;
+        let member = |user: &str, pings: u32, last_everyone_ping: Value| {
+            json!({
+                "user_id": user,
+                "pings": pings,
+                "last_everyone_ping": last_everyone_ping,
+                "last_here_ping": null,
+                "last_role_ping": null,
+                "last_user_ping": null,
+                "departed": false,
+            })
+        };
+        let url = format!("{}/guilds/{}/leaderboard", api, ALLOWED);
+        assert_eq!(
+            get(&url, Some(TOKEN)).await,
+            (
+                200,
+                json!([
+                    member("11", 5, json!("2022-06-01T12:00:00Z")),
+                    member("10", 3, Value::Null),
+                ])
+            )
+        );
+        let url = format!("{}/guilds/{}/leaderboard?limit=1", api, ALLOWED);
+        assert_eq!(
+            get(&url, Some(TOKEN)).await,
+            (200, json!([member("11", 5, json!("2022-06-01T12:00:00Z"))]))
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_out_hidden_members_incidents() {
+        let api = serve().await;
+        let url = format!("{}/guilds/{}/incidents?limit=1", api, ALLOWED);
+        let (status, incidents) = get(&url, Some(TOKEN)).await;
+        assert_eq!(status, 200);
+        let users = incidents
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["user_id"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(users, ["11"]);
+    }
+
+    #[tokio::test]
+    async fn serves_summary() {
+        let api = serve().await;
+        let url = format!("{}/guilds/{}/summary", api, ALLOWED);
+        assert_eq!(
+            get(&url, Some(TOKEN)).await,
+            (
+                200,
+                json!({
+                    "guild_id": ALLOWED.to_string(),
+                    "members": 2,
+                    "total_pings": 8,
+                    "last_everyone_ping": "2022-06-01T12:00:00Z",
+                    "last_here_ping": null,
+                })
+            )
+        );
+    }
+}
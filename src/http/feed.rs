@@ -0,0 +1,123 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Extension, Query};
+use axum::http::header::AUTHORIZATION;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use poise::serenity_prelude::GuildId;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::debug;
+
+use crate::events::{EventBus, PingEvent};
+use crate::http::api::{ApiError, ApiTokens};
+use crate::ping::PingKind;
+use crate::servers::ServerRegistry;
+
+#[derive(Deserialize)]
+pub struct FeedParams {
+    /// Comma-separated guild ids to receive events for. If unset, events from every allowed guild are sent.
+    guild: Option<String>,
+    /// Comma-separated ping kinds to receive events for. If unset, every kind is sent.
+    kind: Option<String>,
+    /// API token, for clients that can't set headers on WebSocket requests.
+    token: Option<String>,
+}
+
+/// Which events a subscriber wants to hear about.
+struct Filter {
+    guilds: Option<HashSet<String>>,
+    kinds: Option<HashSet<PingKind>>,
+}
+
+impl Filter {
+    fn parse(params: &FeedParams) -> Result<Self, ApiError> {
+        let guilds = params
+            .guild
+            .as_ref()
+            .map(|x| x.split(',').map(|x| x.trim().to_string()).collect());
+        let kinds = params
+            .kind
+            .as_ref()
+            .map(|x| {
+                x.split(',')
+                    .map(|x| {
+                        serde_json::from_value(json!(x.trim())).map_err(|_| ApiError::BadRequest("unknown ping kind"))
+                    })
+                    .collect::<Result<_, _>>()
+            })
+            .transpose()?;
+        Ok(Self { guilds, kinds })
+    }
+
+    fn matches(&self, servers: &ServerRegistry, event: &PingEvent) -> bool {
+        let allowed = event
+            .guild_id
+            .parse::<u64>()
+            .map_or(false, |x| servers.is_allowed(GuildId(x)));
+        allowed
+            && self.guilds.as_ref().map_or(true, |x| x.contains(&event.guild_id))
+            && self.kinds.as_ref().map_or(true, |x| x.contains(&event.kind))
+    }
+}
+
+/// `/feed`: streams [`PingEvent`]s as JSON over a WebSocket.
+pub async fn feed(
+    upgrade: WebSocketUpgrade,
+    headers: HeaderMap,
+    Query(params): Query<FeedParams>,
+    Extension(tokens): Extension<ApiTokens>,
+    Extension(events): Extension<EventBus>,
+    Extension(servers): Extension<Arc<ServerRegistry>>,
+) -> Result<Response, ApiError> {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .or(params.token.as_deref());
+    let consumer = token
+        .and_then(|x| tokens.consumer(x))
+        .ok_or(ApiError::Unauthorized)?
+        .to_string();
+    let filter = Filter::parse(&params)?;
+    Ok(upgrade
+        .on_upgrade(move |socket| stream_events(socket, consumer, filter, events, servers))
+        .into_response())
+}
+
+async fn stream_events(
+    mut socket: WebSocket,
+    consumer: String,
+    filter: Filter,
+    events: EventBus,
+    servers: Arc<ServerRegistry>,
+) {
+    debug!(consumer = %consumer, "Feed subscriber connected");
+    let mut receiver = events.subscribe();
+    loop {
+        tokio::select! {
+            event = receiver.recv() => {
+                let message = match event {
+                    Ok(event) if filter.matches(&servers, &event) => serde_json::to_string(&event).unwrap(),
+                    Ok(_) => continue,
+                    // this subscriber was too slow, so tell it what it missed and carry on
+                    Err(RecvError::Lagged(skipped)) => json!({ "type": "lagged", "skipped": skipped }).to_string(),
+                    Err(RecvError::Closed) => break,
+                };
+                if socket.send(Message::Text(message)).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => {
+                match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+    debug!(consumer = %consumer, "Feed subscriber disconnected");
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::Extension;
+use axum::http::StatusCode;
+use axum::Json;
+use poise::serenity::gateway::ConnectionStage;
+use sea_orm::{ConnectionTrait, DatabaseConnection, Statement};
+use serde::Serialize;
+
+/// How long a database ping can take before the database counts as unreachable.
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Tracks what Pingchu needs before it can be considered ready.
+#[derive(Default)]
+pub struct Health {
+    setup_complete: AtomicBool,
+    shards: Mutex<HashMap<u64, ConnectionStage>>,
+}
+
+impl Health {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub fn mark_setup_complete(&self) {
+        self.setup_complete.store(true, Ordering::SeqCst);
+    }
+
+    pub fn record_shard_stage(&self, shard: u64, stage: ConnectionStage) {
+        self.shards.lock().unwrap().insert(shard, stage);
+    }
+
+    /// Checks every readiness condition, including pinging the database.
+    pub async fn readiness(&self, database: &DatabaseConnection) -> Readiness {
+        let backend = database.get_database_backend();
+        let ping = database.execute(Statement::from_string(backend, "SELECT 1".to_string()));
+        Readiness {
+            setup_complete: self.setup_complete.load(Ordering::SeqCst),
+            shards_connected: self.shards_connected(),
+            database_reachable: matches!(tokio::time::timeout(DATABASE_TIMEOUT, ping).await, Ok(Ok(_))),
+        }
+    }
+
+    fn shards_connected(&self) -> bool {
+        let shards = self.shards.lock().unwrap();
+        !shards.is_empty() && shards.values().all(|x| *x == ConnectionStage::Connected)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub struct Readiness {
+    pub setup_complete: bool,
+    pub shards_connected: bool,
+    pub database_reachable: bool,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.setup_complete && self.shards_connected && self.database_reachable
+    }
+}
+
+/// `/healthz`: if this answers at all, the process is alive.
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// `/readyz`: answers 200 once Pingchu is fully up, and 503 otherwise.
+pub async fn readyz(
+    Extension(health): Extension<Arc<Health>>,
+    Extension(database): Extension<DatabaseConnection>,
+) -> (StatusCode, Json<Readiness>) {
+    let readiness = health.readiness(&database).await;
+    let status = if readiness.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::TransactionTrait;
+
+    use super::*;
+    use crate::data;
+
+    fn health(setup_complete: bool, shards: &[ConnectionStage]) -> Health {
+        let health = Health::default();
+        if setup_complete {
+            health.mark_setup_complete();
+        }
+        for (shard, stage) in shards.iter().enumerate() {
+            health.record_shard_stage(shard as u64, *stage);
+        }
+        health
+    }
+
+    #[tokio::test]
+    async fn not_ready_before_setup() {
+        let database = data::load_memory_database().await.unwrap();
+        let readiness = health(false, &[ConnectionStage::Connected]).readiness(&database).await;
+        assert_eq!(
+            readiness,
+            Readiness {
+                setup_complete: false,
+                shards_connected: true,
+                database_reachable: true,
+            }
+        );
+        assert!(!readiness.is_ready());
+    }
+
+    #[tokio::test]
+    async fn not_ready_with_disconnected_shard() {
+        let database = data::load_memory_database().await.unwrap();
+        for shards in [
+            &[][..],
+            &[ConnectionStage::Connected, ConnectionStage::Resuming],
+            &[ConnectionStage::Disconnected],
+        ] {
+            let readiness = health(true, shards).readiness(&database).await;
+            assert!(!readiness.shards_connected, "{:?} counted as connected", shards);
+            assert!(!readiness.is_ready());
+        }
+    }
+
+    #[tokio::test]
+    async fn not_ready_with_database_down() {
+        let database = data::load_memory_database().await.unwrap();
+        // the in-memory pool has one connection, so holding it makes the database unreachable
+        let _txn = database.begin().await.unwrap();
+        let readiness = health(true, &[ConnectionStage::Connected]).readiness(&database).await;
+        assert_eq!(
+            readiness,
+            Readiness {
+                setup_complete: true,
+                shards_connected: true,
+                database_reachable: false,
+            }
+        );
+        assert!(!readiness.is_ready());
+    }
+
+    #[tokio::test]
+    async fn ready() {
+        let database = data::load_memory_database().await.unwrap();
+        let readiness = health(true, &[ConnectionStage::Connected, ConnectionStage::Connected])
+            .readiness(&database)
+            .await;
+        assert!(readiness.is_ready());
+    }
+}
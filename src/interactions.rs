@@ -0,0 +1,703 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use axum::extract::Extension;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+use poise::serenity::model::interactions::application_command::{
+    ApplicationCommandInteraction, ApplicationCommandInteractionDataOption as DataOption,
+    ApplicationCommandInteractionDataOptionValue as OptionValue, ApplicationCommandOptionType,
+};
+use poise::serenity::model::interactions::{
+    Interaction, InteractionApplicationCommandCallbackDataFlags, InteractionResponseType,
+};
+use poise::serenity_prelude::{AttachmentType, GuildId, Http, Permissions};
+use sea_orm::DatabaseConnection;
+use serde_json::json;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use crate::commands::privacy::PrivacyChange;
+use crate::commands::{self, apply_ui, ping_stats_embed};
+use crate::export::Export;
+use crate::names::Names;
+use crate::servers::ServerRegistry;
+use crate::shutdown::{self, Shutdown};
+use crate::{config, data, metrics, ping, privacy, raids, utils, SHUTDOWN_TIMEOUT};
+
+pub const SIGNATURE_HEADER: &str = "X-Signature-Ed25519";
+pub const TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+
+/// Everything needed to answer interactions without a gateway connection.
+pub struct InteractionsState {
+    pub public_key: PublicKey,
+    pub http: Http,
+    pub database: DatabaseConnection,
+    pub servers: Arc<ServerRegistry>,
+    pub shutdown: Arc<Shutdown>,
+    pub uwu_supported: bool,
+}
+
+/// Serves slash commands over HTTP until asked to stop.
+pub async fn serve(config_dir: &Path, data_dir: &Path) -> Result<()> {
+    let config = config::load_config(config_dir)?;
+    let public_key = config
+        .interactions
+        .public_key
+        .as_deref()
+        .context("`interactions.public_key` must be set to serve interactions")?;
+    let public_key = parse_public_key(public_key)?;
+    let http = crate::application_http(&crate::read_token()?).await?;
+    let database = data::load_database(data_dir).await.context("Couldn't load database!")?;
+    let servers = Arc::new(ServerRegistry::load(&config, &database).await?);
+    let shutdown = Shutdown::new();
+    let state = Arc::new(InteractionsState {
+        public_key,
+        http,
+        database: database.clone(),
+        servers,
+        shutdown: shutdown.clone(),
+        uwu_supported: utils::check_uwu_support(),
+    });
+
+    let bind = config.interactions.bind;
+    info!("Serving interactions on {}", bind);
+    axum::Server::bind(&bind)
+        .serve(router(state).into_make_service())
+        .with_graceful_shutdown(shutdown::wait_for_signal())
+        .await
+        .context("Interactions server crashed")?;
+
+    info!("Shutting down...");
+    // commands that were already deferred still need their responses
+    if !shutdown.drain(SHUTDOWN_TIMEOUT).await {
+        warn!(
+            "Some commands were still running after {:?}, shutting down anyways",
+            SHUTDOWN_TIMEOUT
+        );
+    }
+    data::close_database(database).await?;
+    info!("Goodbye!");
+    Ok(())
+}
+
+pub fn router(state: Arc<InteractionsState>) -> Router {
+    Router::new()
+        .route("/interactions", post(interaction))
+        .layer(Extension(state))
+}
+
+pub fn parse_public_key(public_key: &str) -> Result<PublicKey> {
+    let bytes = hex::decode(public_key.trim()).context("Public key isn't valid hex")?;
+    PublicKey::from_bytes(&bytes).context("Public key isn't a valid Ed25519 key")
+}
+
+/// Checks that Discord signed `timestamp` followed by `body`.
+pub fn verify(public_key: &PublicKey, signature: &str, timestamp: &str, body: &[u8]) -> bool {
+    let signature = match hex::decode(signature)
+        .ok()
+        .and_then(|x| Signature::try_from(x.as_slice()).ok())
+    {
+        Some(x) => x,
+        None => return false,
+    };
+    let mut message = timestamp.as_bytes().to_vec();
+    message.extend_from_slice(body);
+    public_key.verify(&message, &signature).is_ok()
+}
+
+async fn interaction(Extension(state): Extension<Arc<InteractionsState>>, headers: HeaderMap, body: Bytes) -> Response {
+    let header = |name| headers.get(name).and_then(|x| x.to_str().ok());
+    let verified = match (header(SIGNATURE_HEADER), header(TIMESTAMP_HEADER)) {
+        (Some(signature), Some(timestamp)) => verify(&state.public_key, signature, timestamp, &body),
+        _ => false,
+    };
+    if !verified {
+        return (StatusCode::UNAUTHORIZED, "invalid request signature").into_response();
+    }
+
+    match serde_json::from_slice::<Interaction>(&body) {
+        Ok(Interaction::Ping(_)) => Json(json!({ "type": InteractionResponseType::Pong as u8 })).into_response(),
+        Ok(Interaction::ApplicationCommand(command)) => handle_command(state, command).await,
+        Ok(_) => (StatusCode::BAD_REQUEST, "unsupported interaction").into_response(),
+        Err(err) => {
+            warn!("Couldn't parse interaction: {}", err);
+            (StatusCode::BAD_REQUEST, "invalid interaction").into_response()
+        }
+    }
+}
+
+/// Answers right away with a deferred response, then runs the command in the background
+/// and edits the response once it's done. Discord only waits 3 seconds for the first answer.
+async fn handle_command(state: Arc<InteractionsState>, command: ApplicationCommandInteraction) -> Response {
+    let (path, _) = subcommand(&command);
+    let supported = match path.as_slice() {
+        ["pinginfo"] | ["export"] => true,
+        ["pingchu", "privacy", "optout" | "optin" | "hide" | "show" | "delete" | "list"] => true,
+        ["pingchu", "raid", "status" | "enable" | "clear"] => true,
+        ["uwuify"] => state.uwu_supported,
+        _ => false,
+    };
+    if !supported {
+        return ephemeral(format!("/{} only works over the gateway.", path.join(" ")));
+    }
+    // servers are allowed and denied through the bot, which runs in another process
+    if let Err(err) = state.servers.reload(&state.database).await {
+        warn!("Couldn't reload allowed servers: {:#}", err);
+    }
+    let guild = match command.guild_id {
+        Some(guild) if state.servers.is_allowed(guild) => guild,
+        // pingchu makes no sense in DMs
+        _ => return ephemeral("Ping'chu isn't allowed here!".to_string()),
+    };
+    // the same permissions the gateway commands require
+    let manage_guild = matches!(
+        path.as_slice(),
+        ["export"] | ["pingchu", "privacy", "list"] | ["pingchu", "raid", _]
+    );
+    let permissions = command.member.as_ref().and_then(|x| x.permissions);
+    if manage_guild && !permissions.map_or(false, |x| x.manage_guild()) {
+        return ephemeral(format!(
+            "You're lacking permissions for `/{}`: {}",
+            path.join(" "),
+            Permissions::MANAGE_GUILD
+        ));
+    }
+    // a deferred response can't be made ephemeral later, so this has to be decided now
+    let ephemeral_defer = match is_ephemeral(&state, guild, &command).await {
+        Ok(x) => x,
+        Err(err) => {
+            error!("Couldn't check whether /{} is ephemeral: {:?}", command.data.name, err);
+            return ephemeral(format!("Something went wrong: {}", err));
+        }
+    };
+    let in_flight = match state.shutdown.enter() {
+        Some(x) => x,
+        None => return ephemeral("Ping'chu is shutting down, try again in a bit!".to_string()),
+    };
+
+    let span = info_span!(
+        "command",
+        name = %path.join(" "),
+        guild = guild.0,
+        channel = command.channel_id.0,
+        user = command.user.id.0,
+    );
+    tokio::spawn(
+        async move {
+            info!("Running command");
+            match run_command(&state, guild, &command).await {
+                Ok(()) => {
+                    debug!("Finished command");
+                    metrics::COMMANDS.with_label_values(&[&command.data.name, "ok"]).inc();
+                }
+                Err(err) => {
+                    error!("Error while running command: {:?}", err);
+                    metrics::COMMANDS
+                        .with_label_values(&[&command.data.name, "error"])
+                        .inc();
+                    let timestamp = command.id.created_at();
+                    let reply = command
+                        .edit_original_interaction_response(&state.http, |msg| {
+                            msg.embed(|embed| {
+                                apply_ui(embed, Some(&command.user), timestamp);
+                                embed
+                                    .title("Ping'chu tripped over a wire!")
+                                    .description(format!("Something went wrong: {}", err))
+                            })
+                        })
+                        .await;
+                    if let Err(err) = reply {
+                        error!("Couldn't reply with error: {}", err);
+                    }
+                }
+            }
+            drop(in_flight);
+        }
+        .instrument(span),
+    );
+
+    let flags = if ephemeral_defer {
+        InteractionApplicationCommandCallbackDataFlags::EPHEMERAL
+    } else {
+        InteractionApplicationCommandCallbackDataFlags::empty()
+    };
+    Json(json!({
+        "type": InteractionResponseType::DeferredChannelMessageWithSource as u8,
+        "data": { "flags": flags.bits() },
+    }))
+    .into_response()
+}
+
+/// Whether only the member who ran a command should see its response, like over the gateway.
+async fn is_ephemeral(
+    state: &InteractionsState,
+    guild: GuildId,
+    command: &ApplicationCommandInteraction,
+) -> Result<bool> {
+    let (path, options) = subcommand(command);
+    match path.as_slice() {
+        // only the note that someone hid their stats is ephemeral
+        ["pinginfo"] => match option(options, "user") {
+            Some(OptionValue::User(user, _)) if user.id != command.user.id => {
+                Ok(privacy::settings(&state.database, guild, user.id).await?.hidden)
+            }
+            _ => Ok(false),
+        },
+        ["export"] | ["pingchu", "privacy", _] | ["pingchu", "raid", "status"] => Ok(true),
+        _ => Ok(false),
+    }
+}
+
+fn ephemeral(content: String) -> Response {
+    Json(json!({
+        "type": InteractionResponseType::ChannelMessageWithSource as u8,
+        "data": { "content": content, "flags": InteractionApplicationCommandCallbackDataFlags::EPHEMERAL.bits() },
+    }))
+    .into_response()
+}
+
+/// The names of the command and subcommands that were run, like `["pingchu", "privacy", "optout"]`,
+/// and the options passed to the last one.
+fn subcommand(command: &ApplicationCommandInteraction) -> (Vec<&str>, &[DataOption]) {
+    let mut path = vec![command.data.name.as_str()];
+    let mut options = command.data.options.as_slice();
+    while let [option] = options {
+        if !matches!(
+            option.kind,
+            ApplicationCommandOptionType::SubCommand | ApplicationCommandOptionType::SubCommandGroup
+        ) {
+            break;
+        }
+        path.push(option.name.as_str());
+        options = &option.options;
+    }
+    (path, options)
+}
+
+fn option<'a>(options: &'a [DataOption], name: &str) -> Option<&'a OptionValue> {
+    options
+        .iter()
+        .find(|x| x.name == name)
+        .and_then(|x| x.resolved.as_ref())
+}
+
+fn string_option<'a>(options: &'a [DataOption], name: &str) -> Option<&'a str> {
+    match option(options, name) {
+        Some(OptionValue::String(x)) => Some(x),
+        _ => None,
+    }
+}
+
+fn bool_option(options: &[DataOption], name: &str) -> Option<bool> {
+    match option(options, name) {
+        Some(OptionValue::Boolean(x)) => Some(*x),
+        _ => None,
+    }
+}
+
+async fn run_command(state: &InteractionsState, guild: GuildId, command: &ApplicationCommandInteraction) -> Result<()> {
+    let timestamp = command.id.created_at();
+    let (path, options) = subcommand(command);
+    let context = format!("Failed to reply to /{}", path.join(" "));
+    match path.as_slice() {
+        ["pinginfo"] => {
+            let (user, display_name) = match option(options, "user") {
+                Some(OptionValue::User(user, member)) => {
+                    let member = member.as_ref().context("Couldn't find that member, maybe they left?")?;
+                    (user, member.nick.clone().unwrap_or_else(|| user.name.clone()))
+                }
+                _ => {
+                    let member = command
+                        .member
+                        .as_ref()
+                        .context("Couldn't find that member, maybe they left?")?;
+                    (&command.user, member.display_name().into_owned())
+                }
+            };
+            if user.id != command.user.id && privacy::settings(&state.database, guild, user.id).await?.hidden {
+                command
+                    .edit_original_interaction_response(&state.http, |msg| {
+                        msg.content(format!("{} has hidden their ping stats.", display_name))
+                    })
+                    .await
+                    .context(context)?;
+                return Ok(());
+            }
+            let info = ping::member_ping_info(&state.database, guild, user.id).await?;
+            command
+                .edit_original_interaction_response(&state.http, |msg| {
+                    msg.embed(|embed| ping_stats_embed(embed, user, &display_name, info, timestamp))
+                })
+                .await
+                .context(context)?;
+        }
+        ["uwuify"] => {
+            let text = string_option(options, "text").unwrap_or("");
+            command
+                .edit_original_interaction_response(&state.http, |msg| msg.content(uwuifier::uwuify_str_sse(text)))
+                .await
+                .context(context)?;
+        }
+        ["pingchu", "privacy", "list"] => {
+            let description = commands::privacy::list_description(&state.database, guild).await?;
+            command
+                .edit_original_interaction_response(&state.http, |msg| {
+                    msg.embed(|embed| {
+                        apply_ui(embed, Some(&command.user), timestamp);
+                        embed.title("Privacy Settings").description(description)
+                    })
+                })
+                .await
+                .context(context)?;
+        }
+        ["pingchu", "privacy", name] => {
+            let change = match *name {
+                "optout" => PrivacyChange::OptOut,
+                "optin" => PrivacyChange::OptIn,
+                "hide" => PrivacyChange::Hide,
+                "show" => PrivacyChange::Show,
+                _ => PrivacyChange::Delete {
+                    confirm: bool_option(options, "confirm").unwrap_or(false),
+                },
+            };
+            let scope = commands::privacy::scope(guild, bool_option(options, "everywhere"));
+            let content = change.apply(&state.database, scope, command.user.id).await?;
+            command
+                .edit_original_interaction_response(&state.http, |msg| msg.content(content))
+                .await
+                .context(context)?;
+        }
+        ["pingchu", "raid", name] => {
+            let content = match *name {
+                "enable" => {
+                    raids::set_raid_mode(&state.database, guild, true).await?;
+                    commands::raid::ENABLED
+                }
+                "clear" => {
+                    raids::set_raid_mode(&state.database, guild, false).await?;
+                    commands::raid::CLEARED
+                }
+                _ => commands::raid::status_message(&state.database, guild).await?,
+            };
+            command
+                .edit_original_interaction_response(&state.http, |msg| msg.content(content))
+                .await
+                .context(context)?;
+        }
+        ["export"] => {
+            let (format, filter) = commands::export::parse_options(
+                string_option(options, "format"),
+                string_option(options, "since"),
+                string_option(options, "until"),
+                string_option(options, "kind"),
+            )?;
+            let mut export = Export::load(&state.database, Some(guild), &filter).await?;
+            // there's no cache without a gateway connection, so names are looked up one by one
+            let mut names = HashMap::new();
+            for (guild, users) in export.members() {
+                let mut guild_names = Names::default();
+                guild_names.fetch_members(&state.http, guild, users).await;
+                names.insert(guild, guild_names);
+            }
+            export.resolve_names(&names);
+            let files = export.files(format)?;
+            if commands::export::too_big(&files) {
+                command
+                    .edit_original_interaction_response(&state.http, |msg| msg.content(commands::export::TOO_BIG))
+                    .await
+                    .context(context)?;
+                return Ok(());
+            }
+            // edited responses can't have attachments, so they're sent in a follow-up
+            command
+                .edit_original_interaction_response(&state.http, |msg| msg.content(commands::export::summary(&export)))
+                .await
+                .context(context.clone())?;
+            command
+                .create_followup_message(&state.http, |msg| {
+                    msg.ephemeral(true);
+                    for (filename, data) in files {
+                        msg.add_file(AttachmentType::Bytes {
+                            data: Cow::Owned(data),
+                            filename,
+                        });
+                    }
+                    msg
+                })
+                .await
+                .context(context)?;
+        }
+        path => unreachable!("/{} should have been rejected before deferring", path.join(" ")),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Keypair, SecretKey, Signer};
+    use poise::serenity_prelude::{ChannelId, UserId};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::config::PingchuConfig;
+    use crate::http;
+
+    const PING: &str = r#"{"id":"1","application_id":"2","type":1,"token":"t","version":1}"#;
+    const TIMESTAMP: &str = "1656000000";
+
+    fn keypair() -> Keypair {
+        let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+        Keypair {
+            public: PublicKey::from(&secret),
+            secret,
+        }
+    }
+
+    fn signature(keypair: &Keypair, timestamp: &str, body: &str) -> String {
+        hex::encode(keypair.sign(format!("{}{}", timestamp, body).as_bytes()).to_bytes())
+    }
+
+    #[test]
+    fn verifies_signatures() {
+        let keypair = keypair();
+        let signature = signature(&keypair, TIMESTAMP, PING);
+        assert!(verify(&keypair.public, &signature, TIMESTAMP, PING.as_bytes()));
+        // a different body, timestamp or signature must all fail
+        let tampered = PING.replace("\"t\"", "\"u\"");
+        assert!(!verify(&keypair.public, &signature, TIMESTAMP, tampered.as_bytes()));
+        assert!(!verify(&keypair.public, &signature, "1656000001", PING.as_bytes()));
+        let mut flipped = hex::decode(&signature).unwrap();
+        flipped[0] ^= 1;
+        assert!(!verify(
+            &keypair.public,
+            &hex::encode(flipped),
+            TIMESTAMP,
+            PING.as_bytes()
+        ));
+        assert!(!verify(&keypair.public, "not hex", TIMESTAMP, PING.as_bytes()));
+        let other = SecretKey::from_bytes(&[8; 32]).unwrap();
+        assert!(!verify(
+            &PublicKey::from(&other),
+            &signature,
+            TIMESTAMP,
+            PING.as_bytes()
+        ));
+    }
+
+    async fn state(public_key: PublicKey) -> Arc<InteractionsState> {
+        let database = data::load_memory_database().await.unwrap();
+        Arc::new(InteractionsState {
+            public_key,
+            http: Http::new(""),
+            servers: Arc::new(
+                ServerRegistry::load(&PingchuConfig::default(), &database)
+                    .await
+                    .unwrap(),
+            ),
+            database,
+            shutdown: Shutdown::new(),
+            uwu_supported: false,
+        })
+    }
+
+    fn user(id: u64) -> Value {
+        json!({
+            "id": id.to_string(),
+            "username": "someone",
+            "discriminator": "0001",
+            "avatar": null,
+        })
+    }
+
+    /// A command with `data` run by user 10 on guild 1, who has `permissions`.
+    fn command(data: Value, permissions: Permissions) -> Value {
+        json!({
+            "id": "3",
+            "application_id": "2",
+            "type": 2,
+            "data": data,
+            "guild_id": "1",
+            "channel_id": "5",
+            "member": {
+                "user": user(10),
+                "roles": [],
+                "joined_at": "2022-06-01T00:00:00Z",
+                "deaf": false,
+                "mute": false,
+                "permissions": permissions.bits().to_string(),
+            },
+            "user": user(10),
+            "token": "t",
+            "version": 1,
+            "locale": "en-US",
+        })
+    }
+
+    fn parse(command: Value) -> ApplicationCommandInteraction {
+        serde_json::from_value(command).unwrap()
+    }
+
+    /// `/pinginfo`, optionally about another user.
+    fn pinginfo(target: Option<u64>) -> ApplicationCommandInteraction {
+        let (options, resolved) = match target {
+            Some(id) => (
+                json!([{ "name": "user", "type": 6, "value": id.to_string() }]),
+                json!({
+                    "users": { id.to_string(): user(id) },
+                    "members": { id.to_string(): { "roles": [], "joined_at": "2022-06-01T00:00:00Z" } },
+                }),
+            ),
+            None => (json!([]), json!({})),
+        };
+        parse(command(
+            json!({ "id": "4", "name": "pinginfo", "type": 1, "options": options, "resolved": resolved }),
+            Permissions::empty(),
+        ))
+    }
+
+    /// `/pingchu <group> <name>` with `options`.
+    fn pingchu(group: &str, name: &str, options: Value, permissions: Permissions) -> Value {
+        let data = json!({
+            "id": "4",
+            "name": "pingchu",
+            "type": 1,
+            "options": [{
+                "name": group,
+                "type": 2,
+                "options": [{ "name": name, "type": 1, "options": options }],
+            }],
+        });
+        command(data, permissions)
+    }
+
+    #[test]
+    fn parses_subcommands() {
+        let options = json!([
+            { "name": "confirm", "type": 5, "value": true },
+            { "name": "everywhere", "type": 5, "value": false },
+        ]);
+        let command = parse(pingchu("privacy", "delete", options, Permissions::empty()));
+        let (path, options) = subcommand(&command);
+        assert_eq!(path, ["pingchu", "privacy", "delete"]);
+        assert_eq!(bool_option(options, "confirm"), Some(true));
+        assert_eq!(bool_option(options, "everywhere"), Some(false));
+        assert_eq!(bool_option(options, "missing"), None);
+
+        let command = pinginfo(None);
+        let (path, options) = subcommand(&command);
+        assert_eq!(path, ["pinginfo"]);
+        assert!(options.is_empty());
+    }
+
+    #[tokio::test]
+    async fn decides_ephemeral_defers() {
+        let state = state(keypair().public).await;
+        let guild = GuildId(1);
+        privacy::update(&state.database, Some(guild), UserId(20), |x| x.hidden = true)
+            .await
+            .unwrap();
+        privacy::update(&state.database, Some(guild), UserId(10), |x| x.hidden = true)
+            .await
+            .unwrap();
+
+        assert!(is_ephemeral(&state, guild, &pinginfo(Some(20))).await.unwrap());
+        // members can always see their own stats, and others' stats are public unless hidden
+        assert!(!is_ephemeral(&state, guild, &pinginfo(None)).await.unwrap());
+        assert!(!is_ephemeral(&state, guild, &pinginfo(Some(10))).await.unwrap());
+        assert!(!is_ephemeral(&state, guild, &pinginfo(Some(30))).await.unwrap());
+
+        let run = |group, name| parse(pingchu(group, name, json!([]), Permissions::MANAGE_GUILD));
+        assert!(is_ephemeral(&state, guild, &run("privacy", "optout")).await.unwrap());
+        assert!(is_ephemeral(&state, guild, &run("raid", "status")).await.unwrap());
+        // moderators should see when raid mode changes
+        assert!(!is_ephemeral(&state, guild, &run("raid", "enable")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn rejects_commands_before_deferring() {
+        let keypair = keypair();
+        let state = state(keypair.public).await;
+        let guild = GuildId(1);
+        state
+            .servers
+            .allow(&state.database, guild, Some(ChannelId(100)))
+            .await
+            .unwrap();
+        let url = format!("http://{}/interactions", http::serve_locally(router(state.clone())));
+        let post = |body: Value| {
+            let body = body.to_string();
+            reqwest::Client::new()
+                .post(&url)
+                .header(SIGNATURE_HEADER, signature(&keypair, TIMESTAMP, &body))
+                .header(TIMESTAMP_HEADER, TIMESTAMP)
+                .body(body)
+                .send()
+        };
+        let content = |body: &Value| {
+            assert_eq!(body["type"], 4);
+            assert_eq!(body["data"]["flags"], 64);
+            body["data"]["content"].as_str().unwrap().to_string()
+        };
+
+        let response = post(pingchu("raid", "enable", json!([]), Permissions::SEND_MESSAGES))
+            .await
+            .unwrap();
+        let body = serde_json::from_str::<Value>(&response.text().await.unwrap()).unwrap();
+        assert!(content(&body).starts_with("You're lacking permissions for `/pingchu raid enable`"));
+        assert!(!raids::raid_mode(&state.database, guild).await.unwrap());
+
+        let response = post(command(
+            json!({ "id": "4", "name": "servers", "type": 1 }),
+            Permissions::ADMINISTRATOR,
+        ))
+        .await
+        .unwrap();
+        let body = serde_json::from_str::<Value>(&response.text().await.unwrap()).unwrap();
+        assert_eq!(content(&body), "/servers only works over the gateway.");
+
+        // denying the guild from the bot's process applies here without a restart
+        let gateway = ServerRegistry::load(&PingchuConfig::default(), &state.database)
+            .await
+            .unwrap();
+        gateway.deny(&state.database, guild).await.unwrap();
+        let response = post(command(
+            json!({ "id": "4", "name": "pinginfo", "type": 1 }),
+            Permissions::empty(),
+        ))
+        .await
+        .unwrap();
+        let body = serde_json::from_str::<Value>(&response.text().await.unwrap()).unwrap();
+        assert_eq!(content(&body), "Ping'chu isn't allowed here!");
+    }
+
+    #[tokio::test]
+    async fn answers_signed_pings_only() {
+        let keypair = keypair();
+        let state = state(keypair.public).await;
+        let url = format!("http://{}/interactions", http::serve_locally(router(state)));
+        let post = |signature: String, body: &'static str| {
+            reqwest::Client::new()
+                .post(&url)
+                .header(SIGNATURE_HEADER, signature)
+                .header(TIMESTAMP_HEADER, TIMESTAMP)
+                .body(body)
+                .send()
+        };
+
+        let response = post(signature(&keypair, TIMESTAMP, PING), PING).await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        let body = serde_json::from_str::<Value>(&response.text().await.unwrap()).unwrap();
+        assert_eq!(body, json!({ "type": 1 }));
+
+        let tampered = r#"{"id":"1","application_id":"2","type":1,"token":"u","version":1}"#;
+        let response = post(signature(&keypair, TIMESTAMP, PING), tampered).await.unwrap();
+        assert_eq!(response.status().as_u16(), 401);
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use poise::serenity_prelude::{GuildId, Timestamp, UserId};
+use sea_orm::prelude::DateTimeUtc;
+use sea_orm::sea_query::{OnConflict, Query};
+use sea_orm::{ColumnTrait, DatabaseConnection, DbErr, EntityName, EntityTrait, QueryFilter, TransactionTrait};
+use tracing::{error, info};
+
+use crate::config::{LifecycleConfig, RetentionPolicy};
+use crate::data::{
+    departed_member, execute_query, guild_allowlist, guild_ping, guild_removal, moderation_action, ping_incident,
+    privacy_setting, raid_mode, response_bag, webhook_dead_letter,
+};
+use crate::servers::ServerRegistry;
+use crate::shutdown::Shutdown;
+use crate::{metrics, privacy, webhooks};
+
+/// How often to look for stats that should be purged.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Records that a member left, unless their stats are kept as-is.
+pub async fn member_left(
+    database: &DatabaseConnection,
+    config: &LifecycleConfig,
+    guild: GuildId,
+    user: UserId,
+) -> Result<()> {
+    if config.departed_members == RetentionPolicy::Keep {
+        return Ok(());
+    }
+    let query = Query::insert()
+        .into_table(departed_member::Entity.table_ref())
+        .columns([
+            departed_member::Column::GuildId,
+            departed_member::Column::UserId,
+            departed_member::Column::DepartedAt,
+        ])
+        .values_panic([
+            (guild.0 as i64).into(),
+            (user.0 as i64).into(),
+            (*Timestamp::now()).into(),
+        ])
+        .on_conflict(
+            OnConflict::columns([departed_member::Column::GuildId, departed_member::Column::UserId])
+                .update_column(departed_member::Column::DepartedAt)
+                .to_owned(),
+        )
+        .to_owned();
+    execute_query(database, &query)
+        .await
+        .context("Couldn't record departed member")?;
+    Ok(())
+}
+
+/// Reconnects a member's history when they rejoin.
+pub async fn member_joined(database: &DatabaseConnection, guild: GuildId, user: UserId) -> Result<()> {
+    departed_member::Entity::delete_by_id((guild.0 as i64, user.0 as i64))
+        .exec(database)
+        .await
+        .context("Couldn't clear departed member")?;
+    Ok(())
+}
+
+/// Records that Pingchu was removed from a guild, unless its stats are kept as-is.
+pub async fn guild_removed(database: &DatabaseConnection, config: &LifecycleConfig, guild: GuildId) -> Result<()> {
+    if config.removed_guilds == RetentionPolicy::Keep {
+        return Ok(());
+    }
+    let query = Query::insert()
+        .into_table(guild_removal::Entity.table_ref())
+        .columns([guild_removal::Column::GuildId, guild_removal::Column::RemovedAt])
+        .values_panic([(guild.0 as i64).into(), (*Timestamp::now()).into()])
+        .on_conflict(
+            OnConflict::column(guild_removal::Column::GuildId)
+                .update_column(guild_removal::Column::RemovedAt)
+                .to_owned(),
+        )
+        .to_owned();
+    execute_query(database, &query)
+        .await
+        .context("Couldn't record guild removal")?;
+    Ok(())
+}
+
+/// Reconnects a guild's history when Pingchu is added back.
+pub async fn guild_joined(database: &DatabaseConnection, guild: GuildId) -> Result<()> {
+    guild_removal::Entity::delete_by_id(guild.0 as i64)
+        .exec(database)
+        .await
+        .context("Couldn't clear guild removal")?;
+    Ok(())
+}
+
+/// Members of `guild` who left and haven't come back.
+pub async fn departed_users(database: &DatabaseConnection, guild: GuildId) -> Result<HashSet<i64>> {
+    let rows = metrics::time_query(
+        "departed_users",
+        departed_member::Entity::find()
+            .filter(departed_member::Column::GuildId.eq(guild.0 as i64))
+            .all(database),
+    )
+    .await
+    .context("Couldn't fetch departed members")?;
+    Ok(rows.into_iter().map(|x| x.user_id).collect())
+}
+
+/// Deletes stats of members and guilds that have been gone for longer than `purge_after`,
+/// if the config says to purge them. Returns how many members and guilds were purged.
+pub async fn purge_expired(
+    database: &DatabaseConnection,
+    config: &LifecycleConfig,
+    servers: &ServerRegistry,
+) -> Result<(usize, usize)> {
+    let cutoff = purge_cutoff(config)?;
+    let mut members = 0;
+    let mut guilds = 0;
+
+    if config.departed_members == RetentionPolicy::Purge {
+        let expired = departed_member::Entity::find()
+            .filter(departed_member::Column::DepartedAt.lte(cutoff))
+            .all(database)
+            .await
+            .context("Couldn't fetch departed members")?;
+        for row in expired {
+            // this also deletes the departure itself
+            let (guild, user) = (GuildId(row.guild_id as u64), UserId(row.user_id as u64));
+            privacy::delete_user_data(database, Some(guild), user)
+                .await
+                .context("Couldn't purge departed member")?;
+            members += 1;
+        }
+    }
+
+    if config.removed_guilds == RetentionPolicy::Purge {
+        let expired = guild_removal::Entity::find()
+            .filter(guild_removal::Column::RemovedAt.lte(cutoff))
+            .all(database)
+            .await
+            .context("Couldn't fetch guild removals")?;
+        for row in expired {
+            database
+                .transaction::<_, _, DbErr>(move |txn| {
+                    Box::pin(async move {
+                        guild_ping::Entity::delete_many()
+                            .filter(guild_ping::Column::GuildId.eq(row.guild_id))
+                            .exec(txn)
+                            .await?;
+                        ping_incident::Entity::delete_many()
+                            .filter(ping_incident::Column::GuildId.eq(row.guild_id))
+                            .exec(txn)
+                            .await?;
+                        privacy_setting::Entity::delete_many()
+                            .filter(privacy_setting::Column::GuildId.eq(row.guild_id))
+                            .exec(txn)
+                            .await?;
+                        response_bag::Entity::delete_many()
+                            .filter(response_bag::Column::GuildId.eq(row.guild_id))
+                            .exec(txn)
+                            .await?;
+                        departed_member::Entity::delete_many()
+                            .filter(departed_member::Column::GuildId.eq(row.guild_id))
+                            .exec(txn)
+                            .await?;
+                        moderation_action::Entity::delete_many()
+                            .filter(moderation_action::Column::GuildId.eq(row.guild_id))
+                            .exec(txn)
+                            .await?;
+                        guild_allowlist::Entity::delete_by_id(row.guild_id).exec(txn).await?;
+                        raid_mode::Entity::delete_by_id(row.guild_id).exec(txn).await?;
+                        // dead letters only have the guild in their payload
+                        let dead_letters = webhook_dead_letter::Entity::find()
+                            .all(txn)
+                            .await?
+                            .into_iter()
+                            .filter(|x| webhooks::dead_letter_guild(x) == Some(GuildId(row.guild_id as u64)))
+                            .map(|x| x.id);
+                        webhook_dead_letter::Entity::delete_many()
+                            .filter(webhook_dead_letter::Column::Id.is_in(dead_letters))
+                            .exec(txn)
+                            .await?;
+                        guild_removal::Entity::delete_by_id(row.guild_id).exec(txn).await?;
+                        Ok(())
+                    })
+                })
+                .await
+                .context("Couldn't purge removed guild")?;
+            servers.forget(GuildId(row.guild_id as u64));
+            guilds += 1;
+        }
+    }
+    Ok((members, guilds))
+}
+
+fn purge_cutoff(config: &LifecycleConfig) -> Result<DateTimeUtc> {
+    let grace = time_v1::Duration::from_std(config.purge_after.unwrap_or_default())
+        .context("`lifecycle.purge_after` is too long")?;
+    Ok(*Timestamp::now() - grace)
+}
+
+/// Purges expired stats in the background every [`PURGE_INTERVAL`], if anything is set to be purged.
+pub fn spawn_purge_task(
+    database: DatabaseConnection,
+    config: LifecycleConfig,
+    servers: Arc<ServerRegistry>,
+    shutdown: Arc<Shutdown>,
+) {
+    if config.departed_members != RetentionPolicy::Purge && config.removed_guilds != RetentionPolicy::Purge {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let _in_flight = match shutdown.enter() {
+                Some(x) => x,
+                None => return,
+            };
+            match purge_expired(&database, &config, &servers).await {
+                Ok((0, 0)) => {}
+                Ok((members, guilds)) => {
+                    info!("Purged stats of {} departed member(s) and {} guild(s)", members, guilds)
+                }
+                Err(err) => error!("Couldn't purge expired stats: {:?}", err),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PingchuConfig;
+    use crate::data::{self, Backup};
+    use crate::ping::PingKind;
+    use crate::quotas::QuotaAction;
+    use crate::servers::ServerStatus;
+
+    /// Stats, an incident, a moderation action and a response bag for `user` on `guild`.
+    fn member_data(backup: &mut Backup, guild: i64, user: i64) {
+        let time = "2022-06-01T12:00:00Z".parse::<DateTimeUtc>().unwrap();
+        let id = backup.ping_incidents.len() as i32 + 1;
+        backup.guild_pings.push(guild_ping::Model {
+            guild_id: guild,
+            user_id: user,
+            last_everyone_ping: Some(time),
+            last_here_ping: None,
+            last_role_ping: None,
+            last_user_ping: None,
+            pings: 1,
+        });
+        backup.ping_incidents.push(ping_incident::Model {
+            id,
+            guild_id: guild,
+            user_id: user,
+            channel_id: 100,
+            message_id: 1000,
+            kind: PingKind::Everyone,
+            timestamp: time,
+        });
+        backup.moderation_actions.push(moderation_action::Model {
+            id,
+            guild_id: guild,
+            user_id: user,
+            channel_id: 100,
+            message_id: 1000,
+            rule: "1 @everyone ping(s) per 1h".to_string(),
+            action: QuotaAction::Warn,
+            error: None,
+            timestamp: time,
+        });
+        backup.response_bags.push(response_bag::Model {
+            guild_id: guild,
+            user_id: user,
+            fingerprint: 1,
+            remaining: "[0]".to_string(),
+        });
+    }
+
+    /// Everything about a guild that isn't tied to a member.
+    fn guild_data(backup: &mut Backup, guild: i64) {
+        let time = "2022-06-01T12:00:00Z".parse::<DateTimeUtc>().unwrap();
+        backup.guild_allowlist.push(guild_allowlist::Model {
+            guild_id: guild,
+            allowed: true,
+            log_channel: Some(100),
+            updated_at: time,
+        });
+        backup.raid_modes.push(raid_mode::Model {
+            guild_id: guild,
+            enabled_at: time,
+        });
+        backup.webhook_dead_letters.push(webhook_dead_letter::Model {
+            id: guild as i32,
+            url: "http://localhost/webhook".to_string(),
+            payload: format!(r#"{{"event":"milestone","guild_id":"{}","user_id":"10"}}"#, guild),
+            attempts: 3,
+            last_error: "500 Internal Server Error".to_string(),
+            failed_at: time,
+        });
+    }
+
+    fn config(departed_members: RetentionPolicy, removed_guilds: RetentionPolicy) -> LifecycleConfig {
+        LifecycleConfig {
+            departed_members,
+            removed_guilds,
+            purge_after: None,
+        }
+    }
+
+    async fn load(backup: Backup) -> (DatabaseConnection, ServerRegistry) {
+        let database = data::load_memory_database().await.unwrap();
+        data::import_backup(&database, backup, false).await.unwrap();
+        let servers = ServerRegistry::load(&PingchuConfig::default(), &database)
+            .await
+            .unwrap();
+        (database, servers)
+    }
+
+    #[tokio::test]
+    async fn purges_departed_members() {
+        let mut backup = Backup::default();
+        for user in [10, 11, 12] {
+            member_data(&mut backup, 1, user);
+        }
+        member_data(&mut backup, 2, 10);
+        let (database, servers) = load(backup).await;
+        let config = config(RetentionPolicy::Purge, RetentionPolicy::Keep);
+
+        member_left(&database, &config, GuildId(1), UserId(10)).await.unwrap();
+        // coming back before the purge keeps everything
+        member_left(&database, &config, GuildId(1), UserId(11)).await.unwrap();
+        member_joined(&database, GuildId(1), UserId(11)).await.unwrap();
+        assert_eq!(purge_expired(&database, &config, &servers).await.unwrap(), (1, 0));
+
+        let left = data::export_backup(&database).await.unwrap();
+        let members = [(1, 11), (1, 12), (2, 10)];
+        assert_eq!(
+            left.guild_pings
+                .iter()
+                .map(|x| (x.guild_id, x.user_id))
+                .collect::<Vec<_>>(),
+            members
+        );
+        for rows in [
+            left.ping_incidents
+                .iter()
+                .map(|x| (x.guild_id, x.user_id))
+                .collect::<Vec<_>>(),
+            left.moderation_actions
+                .iter()
+                .map(|x| (x.guild_id, x.user_id))
+                .collect(),
+            left.response_bags.iter().map(|x| (x.guild_id, x.user_id)).collect(),
+        ] {
+            assert_eq!(rows, members);
+        }
+        assert!(left.departed_members.is_empty());
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_grace_period() {
+        let mut backup = Backup::default();
+        member_data(&mut backup, 1, 10);
+        let (database, servers) = load(backup).await;
+        let config = LifecycleConfig {
+            purge_after: Some(Duration::from_secs(60 * 60)),
+            ..config(RetentionPolicy::Purge, RetentionPolicy::Purge)
+        };
+
+        member_left(&database, &config, GuildId(1), UserId(10)).await.unwrap();
+        guild_removed(&database, &config, GuildId(1)).await.unwrap();
+        assert_eq!(purge_expired(&database, &config, &servers).await.unwrap(), (0, 0));
+        let left = data::export_backup(&database).await.unwrap();
+        assert_eq!(left.guild_pings.len(), 1);
+        assert_eq!(left.departed_members.len(), 1);
+        assert_eq!(left.guild_removals.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_stats_unless_told_to_purge() {
+        let mut backup = Backup::default();
+        member_data(&mut backup, 1, 10);
+        member_data(&mut backup, 2, 10);
+        let (database, servers) = load(backup).await;
+
+        let keep = config(RetentionPolicy::Keep, RetentionPolicy::Keep);
+        member_left(&database, &keep, GuildId(1), UserId(10)).await.unwrap();
+        guild_removed(&database, &keep, GuildId(2)).await.unwrap();
+        assert!(departed_users(&database, GuildId(1)).await.unwrap().is_empty());
+
+        // marked members are shown as departed but never purged
+        let mark = config(RetentionPolicy::Mark, RetentionPolicy::Mark);
+        member_left(&database, &mark, GuildId(1), UserId(10)).await.unwrap();
+        guild_removed(&database, &mark, GuildId(2)).await.unwrap();
+        assert_eq!(purge_expired(&database, &mark, &servers).await.unwrap(), (0, 0));
+        assert_eq!(
+            departed_users(&database, GuildId(1)).await.unwrap(),
+            HashSet::from([10])
+        );
+        assert_eq!(data::export_backup(&database).await.unwrap().guild_pings.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn purges_removed_guilds() {
+        let mut backup = Backup::default();
+        for guild in [1, 2] {
+            member_data(&mut backup, guild, 10);
+            guild_data(&mut backup, guild);
+        }
+        let (database, servers) = load(backup).await;
+        let config = config(RetentionPolicy::Keep, RetentionPolicy::Purge);
+
+        guild_removed(&database, &config, GuildId(1)).await.unwrap();
+        guild_removed(&database, &config, GuildId(2)).await.unwrap();
+        // being added back before the purge keeps everything
+        guild_joined(&database, GuildId(2)).await.unwrap();
+        assert_eq!(purge_expired(&database, &config, &servers).await.unwrap(), (0, 1));
+
+        let left = data::export_backup(&database).await.unwrap();
+        for guilds in [
+            left.guild_pings.iter().map(|x| x.guild_id).collect::<Vec<_>>(),
+            left.ping_incidents.iter().map(|x| x.guild_id).collect(),
+            left.moderation_actions.iter().map(|x| x.guild_id).collect(),
+            left.response_bags.iter().map(|x| x.guild_id).collect(),
+            left.guild_allowlist.iter().map(|x| x.guild_id).collect(),
+            left.raid_modes.iter().map(|x| x.guild_id).collect(),
+            left.webhook_dead_letters.iter().map(|x| x.id as i64).collect(),
+        ] {
+            assert_eq!(guilds, [2]);
+        }
+        assert!(left.guild_removals.is_empty());
+        assert_eq!(servers.status(GuildId(1)), ServerStatus::Unknown);
+        assert_eq!(servers.status(GuildId(2)), ServerStatus::Allowed);
+    }
+}
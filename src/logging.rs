@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{anyhow, Result};
+use clap::{Args, ValueEnum};
+use tracing_subscriber::EnvFilter;
+
+static LOG_MESSAGE_CONTENT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Args)]
+pub struct LoggingArgs {
+    /// Log filter, like `info` or `pingchu=debug,serenity=warn`. Defaults to `RUST_LOG` or `info`.
+    #[clap(long, global = true)]
+    pub log_level: Option<String>,
+    /// How log lines are formatted.
+    #[clap(long, global = true, value_enum, default_value = "pretty")]
+    pub log_format: LogFormat,
+    /// Include message content in logs. Off by default since messages can be private.
+    #[clap(long, global = true)]
+    pub log_message_content: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Pretty,
+    Compact,
+    Json,
+}
+
+/// Installs the global `tracing` subscriber.
+pub fn init(args: &LoggingArgs) -> Result<()> {
+    let filter = match &args.log_level {
+        Some(level) => EnvFilter::try_new(level)?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match args.log_format {
+        LogFormat::Pretty => builder.pretty().try_init(),
+        LogFormat::Compact => builder.compact().try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    }
+    .map_err(|err| anyhow!("Couldn't initialize logging: {}", err))?;
+    LOG_MESSAGE_CONTENT.store(args.log_message_content, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Whether message content may be logged.
+pub fn log_message_content() -> bool {
+    LOG_MESSAGE_CONTENT.load(Ordering::Relaxed)
+}
@@ -7,28 +7,79 @@
 #![feature(yeet_expr)]
 #![allow(clippy::too_many_arguments)]
 
+pub mod cli;
 pub mod commands;
 pub mod config;
+pub mod cooldowns;
 pub mod data;
+pub mod errors;
+pub mod events;
+pub mod export;
+pub mod http;
+pub mod interactions;
+pub mod lifecycle;
+pub mod logging;
+pub mod metrics;
+pub mod names;
 pub mod ping;
+pub mod privacy;
+pub mod quotas;
+pub mod raids;
+pub mod register;
+pub mod responses;
+pub mod rules;
+pub mod scripts;
+pub mod servers;
+pub mod shutdown;
+pub mod site;
+pub mod templates;
 pub mod utils;
+pub mod webhooks;
 
 use std::default::default;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{env, fs};
 
 use anyhow::{anyhow, Context as AnyhowContext, Error, Result};
-use poise::builtins::create_application_commands;
+use clap::Parser;
 use poise::serenity::prelude::GatewayIntents;
-use poise::serenity_prelude::{Activity, ActivityType, ApplicationCommand};
+use poise::serenity_prelude::{Activity, ActivityType, Http};
 use poise::{BoxFuture, Context, Framework, FrameworkOptions};
 use sea_orm::DatabaseConnection;
+use tracing::{debug, info, info_span, warn, Span};
 
-use crate::config::PingchuConfig;
+use crate::cli::Cli;
+use crate::config::{PingchuConfig, RetentionPolicy};
+use crate::cooldowns::MentionCooldowns;
+use crate::errors::ErrorReporter;
+use crate::events::EventBus;
+use crate::http::health::Health;
+use crate::http::HttpState;
+use crate::quotas::QuotaTracker;
+use crate::raids::RaidDetector;
+use crate::scripts::ScriptHost;
+use crate::servers::ServerRegistry;
+use crate::shutdown::Shutdown;
+use crate::webhooks::WebhookDispatcher;
+
+/// How long to wait for in-flight events to finish when shutting down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Pingchu {
     pub config: PingchuConfig,
     pub database: DatabaseConnection,
+    pub servers: Arc<ServerRegistry>,
+    pub shutdown: Arc<Shutdown>,
+    pub health: Arc<Health>,
+    pub errors: ErrorReporter,
+    pub events: EventBus,
+    pub webhooks: WebhookDispatcher,
+    pub quotas: QuotaTracker,
+    pub raids: RaidDetector,
+    pub scripts: ScriptHost,
+    pub cooldowns: MentionCooldowns,
     pub uwu_supported: bool,
 }
 
@@ -36,61 +87,135 @@ pub type PingchuContext<'a> = Context<'a, Pingchu, Error>;
 
 #[tokio::main]
 pub async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    logging::init(&cli.logging)?;
+    cli.run().await
+}
+
+/// Connects to Discord and runs the bot until it crashes or is asked to stop.
+pub async fn run(config_dir: &Path, data_dir: &Path) -> Result<()> {
     let token = read_token()?;
-    let config = config::load_config();
-    let database = data::load_database().await.context("Couldn't load database!")?;
+    let config = config::load_config(config_dir)?;
+    let database = data::load_database(data_dir).await.context("Couldn't load database!")?;
+    let servers = Arc::new(ServerRegistry::load(&config, &database).await?);
+    let shutdown = Shutdown::new();
+    let health = Health::new();
+    let events = EventBus::new();
+    let webhooks = WebhookDispatcher::new(config.webhooks.clone(), database.clone(), shutdown.clone())?;
+    http::spawn(
+        &config.http,
+        HttpState {
+            database: database.clone(),
+            health: health.clone(),
+            servers: servers.clone(),
+            events: events.clone(),
+        },
+    );
+    lifecycle::spawn_purge_task(
+        database.clone(),
+        config.lifecycle.clone(),
+        servers.clone(),
+        shutdown.clone(),
+    );
+    let mut intents = GatewayIntents::non_privileged().union(GatewayIntents::MESSAGE_CONTENT);
+    if config.lifecycle.departed_members != RetentionPolicy::Keep {
+        // member join and leave events need the privileged server members intent
+        intents |= GatewayIntents::GUILD_MEMBERS;
+    }
+    let scripts_dir = config_dir.join(config::SCRIPTS_DIR);
     let uwu_supported = utils::check_uwu_support();
     if !uwu_supported {
-        eprintln!("WARNING: YOUR CPU CANNOT HANDLE THE UWU, DISABLING UWU FEATURES");
+        warn!("YOUR CPU CANNOT HANDLE THE UWU, DISABLING UWU FEATURES");
     }
 
-    Framework::build()
+    let framework = Framework::build()
         .options(FrameworkOptions {
-            commands: {
-                let mut commands = vec![commands::pinginfo()];
-                if uwu_supported {
-                    commands.push(commands::uwuify());
-                }
-                commands
-            },
+            commands: commands::all(uwu_supported),
             command_check: Some(allow_on_server),
+            pre_command: |ctx| {
+                Box::pin(async move {
+                    command_span(ctx).in_scope(|| info!("Running command"));
+                })
+            },
+            post_command: |ctx| {
+                Box::pin(async move {
+                    command_span(ctx).in_scope(|| debug!("Finished command"));
+                    metrics::COMMANDS
+                        .with_label_values(&[&ctx.command().qualified_name, "ok"])
+                        .inc();
+                })
+            },
+            on_error: errors::on_error,
             listener: ping::ping_listener,
             ..default()
         })
         .token(token)
-        .intents(GatewayIntents::non_privileged().union(GatewayIntents::MESSAGE_CONTENT))
-        .user_data_setup(move |ctx, ready, framework| {
-            Box::pin(async move {
-                ApplicationCommand::set_global_application_commands(&ctx.http, |x| {
-                    *x = create_application_commands(&framework.options().commands);
-                    x
-                })
-                .await?;
-
-                ctx.shard.set_activity(Some(match config.status_type {
-                    ActivityType::Listening => Activity::listening(config.status.clone()),
-                    ActivityType::Watching => Activity::watching(config.status.clone()),
-                    _ => Activity::playing(config.status.clone()),
-                }));
-
-                println!(
-                    "Whomst pinged @everyone? Logged in as `{}#{}`!",
-                    ready.user.name, ready.user.discriminator
-                );
-
-                Ok(Pingchu {
-                    config,
-                    database,
-                    uwu_supported,
+        .intents(intents)
+        .user_data_setup({
+            let database = database.clone();
+            let shutdown = shutdown.clone();
+            let health = health.clone();
+            move |ctx, ready, framework| {
+                Box::pin(async move {
+                    register::register_from_config(&ctx.http, &config, &framework.options().commands).await?;
+
+                    ctx.shard.set_activity(Some(match config.status_type {
+                        ActivityType::Listening => Activity::listening(config.status.clone()),
+                        ActivityType::Watching => Activity::watching(config.status.clone()),
+                        _ => Activity::playing(config.status.clone()),
+                    }));
+
+                    info!(
+                        "Whomst pinged @everyone? Logged in as `{}#{}`!",
+                        ready.user.name, ready.user.discriminator
+                    );
+
+                    health.mark_setup_complete();
+                    Ok(Pingchu {
+                        errors: ErrorReporter::new(&config.error_reports),
+                        raids: RaidDetector::new(config.raids.clone()),
+                        scripts: ScriptHost::new(scripts_dir, config.scripts.clone()),
+                        cooldowns: MentionCooldowns::new(config.mention_cooldowns.clone()),
+                        config,
+                        database,
+                        servers,
+                        shutdown,
+                        health,
+                        events,
+                        webhooks,
+                        quotas: QuotaTracker::new(),
+                        uwu_supported,
+                    })
                 })
-            })
+            }
         })
-        .run()
+        .build()
         .await
-        .context("Pingchu crashed :(")
+        .context("Couldn't start Pingchu")?;
+
+    let shard_manager = framework.shard_manager();
+    let mut client = tokio::spawn(framework.start());
+    tokio::select! {
+        result = &mut client => return result?.context("Pingchu crashed :("),
+        _ = shutdown::wait_for_signal() => {},
+    }
+
+    info!("Shutting down...");
+    // stop handling new events, then let the ones already running finish
+    if !shutdown.drain(SHUTDOWN_TIMEOUT).await {
+        warn!(
+            "Some events were still running after {:?}, shutting down anyways",
+            SHUTDOWN_TIMEOUT
+        );
+    }
+    shard_manager.lock().await.shutdown_all().await;
+    client.await?.context("Pingchu crashed while shutting down :(")?;
+    data::close_database(database).await?;
+    info!("Goodbye!");
+    Ok(())
 }
 
-fn read_token() -> Result<String> {
+pub fn read_token() -> Result<String> {
     // search through env variables first
     return if let Ok(token) = std::env::var("TOKEN") {
         Ok(token)
@@ -107,12 +232,36 @@ fn read_token() -> Result<String> {
     };
 }
 
+/// Creates an HTTP client that can manage and respond to this bot's slash commands.
+pub async fn application_http(token: &str) -> Result<Http> {
+    let application = Http::new(token)
+        .get_current_application_info()
+        .await
+        .context("Couldn't fetch application info")?;
+    Ok(Http::new_with_application_id(token, application.id.0))
+}
+
 fn allow_on_server(ctx: PingchuContext<'_>) -> BoxFuture<Result<bool>> {
     Box::pin(async move {
+        if ctx.data().shutdown.is_stopping() {
+            return Ok(false);
+        }
         match ctx.guild_id() {
-            Some(guild) => Ok(ctx.data().config.allowed_servers.contains_key(&guild)),
+            // owner commands need to work on servers that aren't allowed yet
+            _ if ctx.command().owners_only => Ok(true),
+            Some(guild) => Ok(ctx.data().servers.is_allowed(guild)),
             // pingchu makes no sense in DMs
             None => Ok(false),
         }
     })
 }
+
+pub fn command_span(ctx: PingchuContext<'_>) -> Span {
+    info_span!(
+        "command",
+        name = %ctx.command().qualified_name,
+        guild = ?ctx.guild_id().map(|x| x.0),
+        channel = ctx.channel_id().0,
+        user = ctx.author().id.0,
+    )
+}
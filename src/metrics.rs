@@ -0,0 +1,123 @@
+use std::future::Future;
+
+use lazy_static::lazy_static;
+use poise::serenity_prelude::{Cache, GuildId};
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+use crate::ping::PingKind;
+
+lazy_static! {
+    pub static ref MESSAGES_PROCESSED: IntCounter = register_int_counter!(
+        "pingchu_messages_processed_total",
+        "Messages checked for pings on allowed servers"
+    )
+    .unwrap();
+    pub static ref PINGS_DETECTED: IntCounterVec = register_int_counter_vec!(
+        "pingchu_pings_detected_total",
+        "Pings detected, by kind and guild",
+        &["kind", "guild"]
+    )
+    .unwrap();
+    pub static ref MENTION_REPLIES: IntCounter = register_int_counter!(
+        "pingchu_mention_replies_total",
+        "Replies sent to users who pinged Pingchu"
+    )
+    .unwrap();
+    pub static ref LOG_CHANNEL_POSTS: IntCounterVec = register_int_counter_vec!(
+        "pingchu_log_channel_posts_total",
+        "Embeds posted to log channels, by guild",
+        &["guild"]
+    )
+    .unwrap();
+    pub static ref COMMANDS: IntCounterVec = register_int_counter_vec!(
+        "pingchu_commands_total",
+        "Commands run, by command and outcome",
+        &["command", "outcome"]
+    )
+    .unwrap();
+    pub static ref DB_QUERY_SECONDS: HistogramVec = register_histogram_vec!(
+        "pingchu_db_query_seconds",
+        "Database query latency, by query",
+        &["query"]
+    )
+    .unwrap();
+    pub static ref DB_QUERY_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "pingchu_db_query_errors_total",
+        "Failed database queries, by query",
+        &["query"]
+    )
+    .unwrap();
+    pub static ref MODERATION_ACTIONS: IntCounterVec = register_int_counter_vec!(
+        "pingchu_moderation_actions_total",
+        "Actions taken against members who went over a quota, by action and outcome",
+        &["action", "outcome"]
+    )
+    .unwrap();
+    pub static ref RULE_ACTIONS: IntCounterVec = register_int_counter_vec!(
+        "pingchu_rule_actions_total",
+        "Actions run by ping rules, by action and outcome",
+        &["action", "outcome"]
+    )
+    .unwrap();
+    pub static ref SCRIPT_RUNS: IntCounterVec = register_int_counter_vec!(
+        "pingchu_script_runs_total",
+        "Per-server script runs, by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref CONNECTED_SHARDS: IntGauge =
+        register_int_gauge!("pingchu_connected_shards", "Shards currently connected to the gateway").unwrap();
+    pub static ref CACHE_ENTRIES: IntGaugeVec = register_int_gauge_vec!(
+        "pingchu_cache_entries",
+        "Entries in serenity's cache, by kind",
+        &["kind"]
+    )
+    .unwrap();
+}
+
+/// Records a detected ping of `kind`, `count` times.
+pub fn record_pings(kind: PingKind, guild: GuildId, count: usize) {
+    if count > 0 {
+        PINGS_DETECTED
+            .with_label_values(&[kind.as_str(), &guild.to_string()])
+            .inc_by(count as u64);
+    }
+}
+
+/// Awaits a database query, recording how long it took and whether it failed.
+pub async fn time_query<T, E, F>(query: &str, future: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    let timer = DB_QUERY_SECONDS.with_label_values(&[query]).start_timer();
+    let result = future.await;
+    timer.observe_duration();
+    if result.is_err() {
+        DB_QUERY_ERRORS.with_label_values(&[query]).inc();
+    }
+    result
+}
+
+pub fn update_cache_sizes(cache: &Cache) {
+    CACHE_ENTRIES
+        .with_label_values(&["guilds"])
+        .set(cache.guild_count() as i64);
+    CACHE_ENTRIES
+        .with_label_values(&["channels"])
+        .set(cache.guild_channel_count() as i64);
+    CACHE_ENTRIES
+        .with_label_values(&["users"])
+        .set(cache.user_count() as i64);
+}
+
+/// Renders every metric in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("Metrics should always encode");
+    String::from_utf8(buffer).expect("Metrics should always be UTF-8")
+}
@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+
+use poise::serenity_prelude::{Cache, GuildId, Http, UserId};
+use tracing::warn;
+
+/// Display names for guilds and users, falling back to their ids.
+#[derive(Default)]
+pub struct Names(HashMap<u64, String>);
+
+impl Names {
+    /// Names for a guild and every member of it that's in serenity's cache.
+    pub fn from_cache(cache: &Cache, guild: GuildId) -> Self {
+        let mut names = HashMap::new();
+        if let Some(guild) = cache.guild(guild) {
+            names.insert(guild.id.0, guild.name.clone());
+            for (user, member) in &guild.members {
+                names.insert(user.0, member.display_name().into_owned());
+            }
+        }
+        Self(names)
+    }
+
+    pub fn get(&self, id: u64) -> String {
+        self.0.get(&id).cloned().unwrap_or_else(|| id.to_string())
+    }
+
+    pub async fn fetch_guild(&mut self, http: &Http, guild: GuildId) {
+        match guild.to_partial_guild(http).await {
+            Ok(x) => {
+                self.0.insert(guild.0, x.name);
+            }
+            Err(err) => warn!("Couldn't fetch name of guild {}: {}", guild, err),
+        }
+    }
+
+    /// Looks up the display names of `users` on `guild`, skipping users that already have one.
+    pub async fn fetch_members(&mut self, http: &Http, guild: GuildId, users: impl IntoIterator<Item = u64>) {
+        for user in users {
+            if self.0.contains_key(&user) {
+                continue;
+            }
+            // members who left can't be looked up anymore, so they just keep their id
+            if let Ok(member) = guild.member(http, UserId(user)).await {
+                self.0.insert(user, member.display_name().into_owned());
+            }
+        }
+    }
+}
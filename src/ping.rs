@@ -1,23 +1,82 @@
-use anyhow::{Context as AnyhowContext, Error, Result};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Instant;
+
+use anyhow::{anyhow, Context as AnyhowContext, Error, Result};
 use itertools::Itertools;
 use lazy_static::lazy_static;
-use poise::serenity_prelude::{Context, GuildId, Member, Mentionable, Permissions, RoleId, Timestamp, UserId};
+use poise::serenity::gateway::ConnectionStage;
+use poise::serenity_prelude::{
+    ChannelId, Context, GuildId, Member, Mentionable, Message, Permissions, Role, RoleId, Timestamp, UserId,
+};
 use poise::{BoxFuture, Event, FrameworkContext};
-use rand::seq::SliceRandom;
+use rand::seq::IteratorRandom;
 use rand::Rng;
 use regex::Regex;
+use sea_orm::entity::prelude::{DeriveActiveEnum, EnumIter};
 use sea_orm::entity::Iterable;
 use sea_orm::prelude::DateTimeUtc;
 use sea_orm::sea_query::{Expr, OnConflict, Query};
-use sea_orm::{ColumnTrait, EntityName, EntityTrait, QueryFilter, QueryOrder, TransactionTrait};
+use sea_orm::{
+    ColumnTrait, DatabaseConnection, EntityName, EntityTrait, QueryFilter, QueryOrder, QuerySelect, Set,
+    TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, field, info, info_span, trace, Instrument};
 use unicode_segmentation::UnicodeSegmentation;
 
-use crate::data::{execute_query, guild_ping};
-use crate::{commands, utils, Pingchu};
+use crate::config::ServerConfig;
+use crate::cooldowns::{Cooldown, MentionReply};
+use crate::data::{execute_query, guild_ping, ping_incident};
+use crate::events::{PingEvent, PingEventType};
+use crate::responses::{Response, ResponseContext, Selection};
+use crate::rules::{PingFacts, TimeOfDay};
+use crate::templates::{Values, Variable};
+use crate::webhooks::{WebhookEvent, WebhookPayload};
+use crate::{
+    commands, cooldowns, lifecycle, logging, metrics, privacy, quotas, raids, responses, rules, scripts, servers,
+    utils, Pingchu,
+};
 
 const EVERYONE_PING: &str = "@everyone";
 const HERE_PING: &str = "@here";
 
+/// The different kinds of pings Pingchu tracks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
+#[sea_orm(rs_type = "String", db_type = "String(Some(16))")]
+#[serde(rename_all = "lowercase")]
+pub enum PingKind {
+    #[sea_orm(string_value = "everyone")]
+    Everyone,
+    #[sea_orm(string_value = "here")]
+    Here,
+    #[sea_orm(string_value = "role")]
+    Role,
+    #[sea_orm(string_value = "user")]
+    User,
+}
+
+impl PingKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Everyone => "everyone",
+            Self::Here => "here",
+            Self::Role => "role",
+            Self::User => "user",
+        }
+    }
+}
+
+impl FromStr for PingKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        PingKind::iter()
+            .find(|x| x.as_str() == s.trim().to_lowercase())
+            .ok_or_else(|| anyhow!("Unknown ping kind `{}`, expected everyone, here, role or user", s))
+    }
+}
+
 lazy_static! {
     static ref ROLE_PING: Regex = Regex::new(r"<@&(\d*?)>").unwrap();
 }
@@ -28,151 +87,606 @@ pub fn ping_listener<'a>(
     framework: FrameworkContext<'a, Pingchu, Error>,
     pingchu: &'a Pingchu,
 ) -> BoxFuture<'a, Result<()>> {
-    Box::pin(async move {
-        if let Event::Message { new_message } = event {
-            match new_message.guild_id {
-                Some(guild) if pingchu.config.allowed_servers.contains_key(&guild) => {
-                    let content = &new_message.content;
-                    let member = guild.member(&ctx.http, new_message.author.id).await?;
-                    let guild_roles = guild.roles(&ctx.http).await?;
-
-                    // note: the @everyone role has the same id as the guild (clever, Discord)
-                    let member_can_ping_everyone = member
-                        .roles
-                        .iter()
-                        .chain(std::iter::once(&RoleId(guild.0)))
-                        .filter_map(|x| guild_roles.get(x))
-                        .any(|role| {
-                            role.has_permission(Permissions::MENTION_EVERYONE)
-                                || role.has_permission(Permissions::ADMINISTRATOR)
-                        });
-
-                    let user_pings = new_message.mentions.len();
-                    let role_pings = {
-                        let possible_pings = (*ROLE_PING).find_iter(content);
-                        if member_can_ping_everyone {
-                            // user can ping all roles
-                            possible_pings.map(|x| x.as_str()).unique().count()
-                        } else {
-                            // time to figure out which roles were actually pinged
-                            possible_pings
-                                .filter_map(|x| x.as_str().parse::<u64>().ok())
-                                .unique()
-                                .filter_map(|x| guild_roles.get(&RoleId(x)))
-                                .filter(|role| role.mentionable)
-                                .count()
-                        }
-                    };
-                    // note: `new_message.mention_everyone` returns true for both @here and @everyone
-                    let everyone_ping = member_can_ping_everyone && content.contains(EVERYONE_PING);
-                    let here_ping = member_can_ping_everyone && content.contains(HERE_PING);
-
-                    let pings = user_pings + role_pings + everyone_ping as usize + here_ping as usize;
-                    if pings > 0 {
-                        // save previous state for logging @everyone pings
-                        let previous_everyone = if everyone_ping {
-                            Some(everyone_ping_history(pingchu, &member).await?)
-                        } else {
-                            None
-                        };
-
-                        upsert_guild_ping(
-                            pingchu,
-                            &member,
-                            new_message.timestamp,
-                            pings,
-                            user_pings,
-                            role_pings,
-                            everyone_ping,
-                            here_ping,
+    let span = info_span!(
+        "event",
+        name = event.name(),
+        guild = field::Empty,
+        channel = field::Empty,
+        message = field::Empty,
+    );
+    if let Event::Message { new_message } = event {
+        span.record("guild", &new_message.guild_id.map(|x| x.0));
+        span.record("channel", &new_message.channel_id.0);
+        span.record("message", &new_message.id.0);
+    }
+
+    Box::pin(
+        async move {
+            let _in_flight = match pingchu.shutdown.enter() {
+                Some(x) => x,
+                // pingchu is shutting down
+                None => return Ok(()),
+            };
+            metrics::update_cache_sizes(&ctx.cache);
+            let result = handle_event(ctx, event, framework, pingchu).await;
+            if let Err(err) = &result {
+                let context = match event {
+                    Event::Message { new_message } => format!(
+                        "{} (guild {}, channel {}, message {})",
+                        event.name(),
+                        new_message.guild_id.map(|x| x.to_string()).unwrap_or_default(),
+                        new_message.channel_id,
+                        new_message.id
+                    ),
+                    _ => event.name().to_string(),
+                };
+                pingchu.errors.report(&ctx.http, &context, err).await;
+            }
+            result
+        }
+        .instrument(span),
+    )
+}
+
+async fn handle_event(
+    ctx: &Context,
+    event: &Event<'_>,
+    framework: FrameworkContext<'_, Pingchu, Error>,
+    pingchu: &Pingchu,
+) -> Result<()> {
+    match event {
+        Event::GuildCreate { guild, .. } => {
+            lifecycle::guild_joined(&pingchu.database, guild.id).await?;
+            if let Some(delay) = pingchu.config.leave_disallowed_after {
+                if !pingchu.servers.is_allowed(guild.id) {
+                    servers::schedule_leave(ctx.http.clone(), pingchu.servers.clone(), guild.id, delay);
+                }
+            }
+        }
+        Event::GuildDelete { incomplete, .. } => {
+            // guilds also get deleted during outages, but then they're marked unavailable
+            if !incomplete.unavailable {
+                info!(guild = incomplete.id.0, "Removed from guild");
+                lifecycle::guild_removed(&pingchu.database, &pingchu.config.lifecycle, incomplete.id).await?;
+            }
+        }
+        Event::GuildMemberAddition { new_member } => {
+            lifecycle::member_joined(&pingchu.database, new_member.guild_id, new_member.user.id).await?;
+        }
+        Event::GuildMemberRemoval { guild_id, user, .. } => {
+            lifecycle::member_left(&pingchu.database, &pingchu.config.lifecycle, *guild_id, user.id).await?;
+        }
+        Event::ShardStageUpdate { update } => {
+            pingchu.health.record_shard_stage(update.shard_id.0, update.new);
+            if update.new == ConnectionStage::Connected && update.old != ConnectionStage::Connected {
+                metrics::CONNECTED_SHARDS.inc();
+            } else if update.old == ConnectionStage::Connected && update.new != ConnectionStage::Connected {
+                metrics::CONNECTED_SHARDS.dec();
+            }
+        }
+        Event::Message { new_message } => {
+            // pingchu makes no sense in DMs
+            if let Some(guild) = new_message.guild_id {
+                if let Some(server) = pingchu.servers.get(guild) {
+                    on_message(ctx, framework, pingchu, new_message, guild, &server).await?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+async fn on_message(
+    ctx: &Context,
+    framework: FrameworkContext<'_, Pingchu, Error>,
+    pingchu: &Pingchu,
+    new_message: &Message,
+    guild: GuildId,
+    server: &ServerConfig,
+) -> Result<()> {
+    metrics::MESSAGES_PROCESSED.inc();
+    let content = &new_message.content;
+    if logging::log_message_content() {
+        trace!(author = new_message.author.id.0, content = %content, "Received message");
+    }
+    let member = guild
+        .member(&ctx.http, new_message.author.id)
+        .await
+        .context("Couldn't fetch message author")?;
+    let guild_roles = guild.roles(&ctx.http).await.context("Couldn't fetch guild roles")?;
+
+    // note: the @everyone role has the same id as the guild (clever, Discord)
+    let member_can_ping_everyone = member
+        .roles
+        .iter()
+        .chain(std::iter::once(&RoleId(guild.0)))
+        .filter_map(|x| guild_roles.get(x))
+        .any(|role| {
+            role.has_permission(Permissions::MENTION_EVERYONE) || role.has_permission(Permissions::ADMINISTRATOR)
+        });
+
+    let user_pings = new_message.mentions.len();
+    let pinged_roles = {
+        let possible_pings = (*ROLE_PING)
+            .captures_iter(content)
+            .filter_map(|x| x[1].parse::<u64>().ok())
+            .map(RoleId)
+            .unique();
+        if member_can_ping_everyone {
+            // user can ping all roles
+            possible_pings.collect::<Vec<_>>()
+        } else {
+            // time to figure out which roles were actually pinged
+            possible_pings
+                .filter(|x| guild_roles.get(x).map_or(false, |role| role.mentionable))
+                .collect()
+        }
+    };
+    let role_pings = pinged_roles.len();
+    // note: `new_message.mention_everyone` returns true for both @here and @everyone
+    let everyone_ping = member_can_ping_everyone && content.contains(EVERYONE_PING);
+    let here_ping = member_can_ping_everyone && content.contains(HERE_PING);
+
+    let pings = user_pings + role_pings + everyone_ping as usize + here_ping as usize;
+    if pings == 0 {
+        return Ok(());
+    }
+    if raids::check_message(ctx, pingchu, server, new_message).await? {
+        return Ok(());
+    }
+    debug!(
+        author = new_message.author.id.0,
+        user_pings, role_pings, everyone_ping, here_ping, "Detected pings"
+    );
+    let counts = [
+        (PingKind::User, user_pings),
+        (PingKind::Role, role_pings),
+        (PingKind::Everyone, everyone_ping as usize),
+        (PingKind::Here, here_ping as usize),
+    ];
+    for (kind, count) in counts {
+        metrics::record_pings(kind, guild, count);
+    }
+    // quotas apply even to members who opted out of tracking
+    if quotas::enforce(ctx, pingchu, server, &member, new_message, &counts).await? {
+        // like in raid mode, deleted messages aren't tracked or replied to
+        return Ok(());
+    }
+    let facts = PingFacts {
+        channel: new_message.channel_id,
+        author_roles: &member.roles,
+        pinged_roles: &pinged_roles,
+        counts: &counts,
+        time: TimeOfDay::of(new_message.timestamp),
+    };
+    if rules::evaluate(ctx, pingchu, server, &member, new_message, &facts).await? {
+        return Ok(());
+    }
+    scripts::run(ctx, pingchu, server, &member, new_message, &facts).await?;
+
+    // stats from before this message, for filling in replies to Pingchu
+    let mention_stats = if pings_pingchu(framework.bot_id, server, new_message, &pinged_roles, &guild_roles) {
+        Some(member_ping_info(&pingchu.database, guild, new_message.author.id).await?)
+    } else {
+        None
+    };
+
+    if privacy::settings(&pingchu.database, guild, new_message.author.id)
+        .await?
+        .opted_out
+    {
+        debug!(author = new_message.author.id.0, "Not tracking pings, author opted out");
+        if let Some(previous) = mention_stats {
+            reply_to_mention(ctx, pingchu, &member, new_message, previous, 0, everyone_ping).await?;
+        }
+        return Ok(());
+    }
+
+    // save previous state for logging @everyone pings
+    let previous_everyone = if everyone_ping {
+        Some(everyone_ping_history(pingchu, &member).await?)
+    } else {
+        None
+    };
+
+    upsert_guild_ping(
+        pingchu,
+        &member,
+        new_message.timestamp,
+        pings,
+        user_pings,
+        role_pings,
+        everyone_ping,
+        here_ping,
+    )
+    .await?;
+    for (kind, count) in counts {
+        if count > 0 {
+            pingchu
+                .events
+                .publish(PingEvent::new(PingEventType::Ping, new_message, kind, count));
+        }
+    }
+    for (kind, pinged) in [(PingKind::Everyone, everyone_ping), (PingKind::Here, here_ping)] {
+        if pinged {
+            record_incident(&pingchu.database, new_message, kind).await?;
+            let event = PingEvent::new(PingEventType::Incident, new_message, kind, 1);
+            pingchu.events.publish(event.clone());
+            if let Some(payload) = WebhookPayload::incident(event) {
+                pingchu.webhooks.dispatch(payload);
+            }
+        }
+    }
+    if pingchu.webhooks.wants(WebhookEvent::Milestone) {
+        let total = member_ping_info(&pingchu.database, guild, new_message.author.id)
+            .await?
+            .map(|x| x.pings)
+            .unwrap_or_default();
+        for milestone in pingchu
+            .webhooks
+            .milestones_between(total.saturating_sub(pings as u32), total)
+        {
+            pingchu.webhooks.dispatch(WebhookPayload::Milestone {
+                guild_id: guild.to_string(),
+                user_id: new_message.author.id.to_string(),
+                milestone,
+                pings: total,
+                timestamp: new_message.timestamp,
+            });
+        }
+    }
+
+    if let Some((last_global, last_member, last_pings)) = previous_everyone {
+        info!(author = new_message.author.id.0, "Logging @everyone ping");
+        server
+            .log_channel
+            .send_message(&ctx.http, |msg| {
+                msg.add_embed(|embed| {
+                    commands::apply_ui(embed, Some(&new_message.author), new_message.timestamp);
+                    embed
+                        .title(format!("_{} pinged @everyone!_", member.display_name()))
+                        .url(new_message.link())
+                        .field(
+                            "Message",
+                            new_message.content.graphemes(true).take(100).collect::<String>(),
+                            false,
                         )
-                        .await?;
-
-                        if let Some((last_global, last_member, last_pings)) = previous_everyone {
-                            pingchu.config.allowed_servers[&guild]
-                                .log_channel
-                                .send_message(&ctx.http, |msg| {
-                                    msg.add_embed(|embed| {
-                                        commands::apply_ui(embed, Some(&new_message.author), new_message.timestamp);
-                                        embed
-                                            .title(format!("_{} pinged @everyone!_", member.display_name()))
-                                            .url(new_message.link())
-                                            .field(
-                                                "Message",
-                                                new_message.content.graphemes(true).take(100).collect::<String>(),
-                                                false,
-                                            )
-                                            .field("Author", new_message.author.mention(), true)
-                                            .field("Total Pings", last_pings + pings as u32, true);
-                                        if let Some(time) = last_global {
-                                            embed.field(
-                                                "Time since last @everyone",
-                                                utils::format_time_v1_duration(*new_message.timestamp - time),
-                                                false,
-                                            );
-                                        }
-                                        if let Some(time) = last_member {
-                                            embed.field(
-                                                format!("Time since {} last pinged @everyone", member.display_name()),
-                                                utils::format_time_v1_duration(*new_message.timestamp - time),
-                                                false,
-                                            );
-                                        }
-                                        embed
-                                    })
-                                })
-                                .await?;
-                        } else if new_message.mentions.iter().any(|x| x.id == framework.bot_id) {
-                            let (maybe_response, should_uwu) = {
-                                // this is in a block since `ThreadRng` is `!Send`
-                                let mut rng = rand::thread_rng();
-                                (
-                                    pingchu.config.ping_responses.choose(&mut rng).cloned(),
-                                    pingchu.uwu_supported && rng.gen::<f64>() < pingchu.config.uwu_chance,
-                                )
-                            };
-                            if let Some(response) = maybe_response {
-                                let content = if should_uwu {
-                                    uwuifier::uwuify_str_sse(&response)
-                                } else {
-                                    response
-                                };
-                                new_message
-                                    .channel_id
-                                    .send_message(&ctx.http, |msg| msg.reference_message(new_message).content(content))
-                                    .await?;
-                            }
-                        }
+                        .field("Author", new_message.author.mention(), true)
+                        .field("Total Pings", last_pings + pings as u32, true);
+                    if let Some(time) = last_global {
+                        embed.field(
+                            "Time since last @everyone",
+                            utils::format_time_v1_duration(*new_message.timestamp - time),
+                            false,
+                        );
+                    }
+                    if let Some(time) = last_member {
+                        embed.field(
+                            format!("Time since {} last pinged @everyone", member.display_name()),
+                            utils::format_time_v1_duration(*new_message.timestamp - time),
+                            false,
+                        );
                     }
+                    embed
+                })
+            })
+            .await
+            .context("Couldn't log @everyone ping")?;
+        metrics::LOG_CHANNEL_POSTS
+            .with_label_values(&[&guild.to_string()])
+            .inc();
+    } else if let Some(previous) = mention_stats {
+        reply_to_mention(ctx, pingchu, &member, new_message, previous, pings, everyone_ping).await?;
+    }
+    Ok(())
+}
+
+/// Whether a message pinged Pingchu, either directly, through its managed role, or by replying to it
+/// with reply-ping on, depending on the server's config.
+fn pings_pingchu(
+    bot_id: UserId,
+    server: &ServerConfig,
+    message: &Message,
+    pinged_roles: &[RoleId],
+    guild_roles: &HashMap<RoleId, Role>,
+) -> bool {
+    // reply-pings show up in `mentions`, but not in the message content
+    let mentioned = message.mentions.iter().any(|x| x.id == bot_id);
+    let in_content =
+        message.content.contains(&format!("<@{}>", bot_id)) || message.content.contains(&format!("<@!{}>", bot_id));
+    let replied_to = message
+        .referenced_message
+        .as_ref()
+        .map_or(false, |x| x.author.id == bot_id);
+    let reply_ping_only = replied_to && !in_content;
+    let role_ping = pinged_roles
+        .iter()
+        .filter_map(|x| guild_roles.get(x))
+        .any(|x| x.tags.bot_id == Some(bot_id));
+
+    (mentioned && (!reply_ping_only || server.reply_to_reply_pings)) || (role_ping && server.reply_to_role_pings)
+}
+
+/// Replies with a random response to a message that pinged Pingchu. `previous` has the author's stats
+/// from before the message, and `pings` is how many of its pings were tracked.
+async fn reply_to_mention(
+    ctx: &Context,
+    pingchu: &Pingchu,
+    member: &Member,
+    new_message: &Message,
+    previous: Option<guild_ping::Model>,
+    pings: usize,
+    everyone_ping: bool,
+) -> Result<()> {
+    let total_pings = previous.as_ref().map_or(0, |x| x.pings) + pings as u32;
+    let context = ResponseContext {
+        guild: member.guild_id,
+        pings: total_pings,
+        everyone: everyone_ping,
+        time: TimeOfDay::of(new_message.timestamp),
+    };
+    if !responses::any_allowed(&pingchu.config.ping_responses, &context) {
+        return Ok(());
+    }
+    let last_ping = previous.as_ref().and_then(last_ping);
+    let writer = ReplyWriter {
+        ctx: ctx.clone(),
+        database: pingchu.database.clone(),
+        responses: pingchu.config.ping_responses.clone(),
+        selection: pingchu.config.response_selection,
+        uwu_chance: if pingchu.uwu_supported {
+            pingchu.config.uwu_chance
+        } else {
+            0.0
+        },
+        context,
+        member: member.clone(),
+        channel: new_message.channel_id,
+        since_last_ping: last_ping.map(|x| utils::format_time_v1_duration(*new_message.timestamp - x)),
+    };
+    let reply = MentionReply {
+        guild: member.guild_id,
+        user: member.user.id,
+        channel: new_message.channel_id,
+        message: new_message.id,
+    };
+    // responses are only picked once the reply is sent, so pings on cooldown don't use up shuffle bags
+    match pingchu.cooldowns.check(&reply, Instant::now()) {
+        Cooldown::Ready => {
+            if let Some(content) = writer.write().await? {
+                debug!("Replying to mention");
+                cooldowns::send(&ctx.http, &reply, &content).await?;
+            }
+        }
+        Cooldown::Blocked { key, until, tired } => {
+            debug!(?key, "Mention reply is on cooldown");
+            match &pingchu.config.mention_cooldowns.tired_response {
+                Some(tired_response) if tired => {
+                    new_message
+                        .channel_id
+                        .send_message(&ctx.http, |msg| {
+                            msg.reference_message(new_message).content(tired_response)
+                        })
+                        .await
+                        .context("Couldn't reply to mention")?;
                 }
-                // pingchu makes no sense in DMs
                 _ => {}
             }
+            let compose = Box::new(move || Box::pin(writer.write()) as BoxFuture<_>);
+            pingchu.cooldowns.defer(ctx.http.clone(), key, until, reply, compose);
         }
-        Ok(())
-    })
+    }
+    Ok(())
 }
 
-pub async fn member_ping_info(pingchu: &Pingchu, guild: GuildId, user: UserId) -> Result<Option<guild_ping::Model>> {
-    guild_ping::Entity::find_by_id((guild.0 as i64, user.0 as i64))
-        .one(&pingchu.database)
-        .await
-        .context("Couldn't fetch member ping history")
+/// Everything needed to write a reply to a mention, which can happen after a cooldown is over.
+struct ReplyWriter {
+    ctx: Context,
+    database: DatabaseConnection,
+    responses: Vec<Response>,
+    selection: Selection,
+    /// 0 if uwuifying isn't supported on this machine.
+    uwu_chance: f64,
+    context: ResponseContext,
+    member: Member,
+    channel: ChannelId,
+    since_last_ping: Option<String>,
+}
+
+impl ReplyWriter {
+    /// Picks a response and fills it in, or returns `None` if no response can be picked.
+    async fn write(self) -> Result<Option<String>> {
+        let guild = self.member.guild_id;
+        let all_responses = &self.responses;
+        let maybe_response = match self.selection {
+            Selection::Random => {
+                // this is in a block since `ThreadRng` is `!Send`
+                let mut rng = rand::thread_rng();
+                responses::choose(all_responses, &self.context, &mut rng)
+            }
+            Selection::ShuffleGuild => {
+                responses::choose_from_bag(&self.database, all_responses, guild, None, &self.context)
+                    .await?
+                    .map(|x| &all_responses[x])
+            }
+            Selection::ShuffleUser => {
+                let user = Some(self.member.user.id);
+                responses::choose_from_bag(&self.database, all_responses, guild, user, &self.context)
+                    .await?
+                    .map(|x| &all_responses[x])
+            }
+        };
+        let template = match maybe_response {
+            Some(x) => &x.template,
+            None => return Ok(None),
+        };
+        let values = Values {
+            name: self.member.display_name().to_string(),
+            pings: self.context.pings,
+            since_last_ping: self.since_last_ping.clone(),
+            server: guild.name(&self.ctx).unwrap_or_default(),
+            random_member: if template.uses(Variable::RandomMember) {
+                random_member(&self.ctx, &self.member)
+            } else {
+                String::new()
+            },
+            author: self.member.mention().to_string(),
+            channel: self.channel.mention().to_string(),
+            // responses can't use `{rule}`
+            rule: String::new(),
+        };
+        let response = template.render(&values);
+        let should_uwu = rand::thread_rng().gen::<f64>() < self.uwu_chance;
+        Ok(Some(if should_uwu {
+            uwuifier::uwuify_str_sse(&response)
+        } else {
+            response
+        }))
+    }
+}
+
+/// When a member last pinged anything.
+pub fn last_ping(stats: &guild_ping::Model) -> Option<DateTimeUtc> {
+    [
+        stats.last_everyone_ping,
+        stats.last_here_ping,
+        stats.last_role_ping,
+        stats.last_user_ping,
+    ]
+    .into_iter()
+    .flatten()
+    .max()
+}
+
+/// Picks a random cached member of `member`'s guild, or `member` if none are cached.
+pub fn random_member(ctx: &Context, member: &Member) -> String {
+    ctx.cache
+        .guild_field(member.guild_id, |guild| {
+            guild
+                .members
+                .values()
+                .choose(&mut rand::thread_rng())
+                .map(|x| x.display_name().to_string())
+        })
+        .flatten()
+        .unwrap_or_else(|| member.display_name().to_string())
+}
+
+pub async fn member_ping_info(
+    database: &DatabaseConnection,
+    guild: GuildId,
+    user: UserId,
+) -> Result<Option<guild_ping::Model>> {
+    metrics::time_query(
+        "member_ping_info",
+        guild_ping::Entity::find_by_id((guild.0 as i64, user.0 as i64)).one(database),
+    )
+    .await
+    .context("Couldn't fetch member ping history")
+}
+
+/// Fetches the `limit` members of a guild with the most pings, most pings first.
+/// Members who hid their stats are left out.
+pub async fn guild_leaderboard(
+    database: &DatabaseConnection,
+    guild: GuildId,
+    limit: u64,
+) -> Result<Vec<guild_ping::Model>> {
+    let hidden = privacy::hidden_users(database, guild).await?;
+    metrics::time_query(
+        "guild_leaderboard",
+        guild_ping::Entity::find()
+            .filter(guild_ping::Column::GuildId.eq(guild.0 as i64))
+            .filter(guild_ping::Column::UserId.is_not_in(hidden))
+            .order_by_desc(guild_ping::Column::Pings)
+            .limit(limit)
+            .all(database),
+    )
+    .await
+    .context("Couldn't fetch guild leaderboard")
+}
+
+/// Fetches every member's ping stats in a guild.
+pub async fn guild_pings(database: &DatabaseConnection, guild: GuildId) -> Result<Vec<guild_ping::Model>> {
+    metrics::time_query(
+        "guild_pings",
+        guild_ping::Entity::find()
+            .filter(guild_ping::Column::GuildId.eq(guild.0 as i64))
+            .all(database),
+    )
+    .await
+    .context("Couldn't fetch guild ping data")
+}
+
+/// Fetches the `limit` most recent @everyone and @here pings in a guild, newest first.
+pub async fn recent_incidents(
+    database: &DatabaseConnection,
+    guild: GuildId,
+    limit: u64,
+) -> Result<Vec<ping_incident::Model>> {
+    let hidden = privacy::hidden_users(database, guild).await?;
+    metrics::time_query(
+        "recent_incidents",
+        ping_incident::Entity::find()
+            .filter(ping_incident::Column::GuildId.eq(guild.0 as i64))
+            .filter(ping_incident::Column::UserId.is_not_in(hidden))
+            .order_by_desc(ping_incident::Column::Timestamp)
+            .limit(limit)
+            .all(database),
+    )
+    .await
+    .context("Couldn't fetch ping incidents")
+}
+
+/// Fetches every @everyone and @here ping in a guild, newest first.
+pub async fn guild_incidents(database: &DatabaseConnection, guild: GuildId) -> Result<Vec<ping_incident::Model>> {
+    metrics::time_query(
+        "guild_incidents",
+        ping_incident::Entity::find()
+            .filter(ping_incident::Column::GuildId.eq(guild.0 as i64))
+            .order_by_desc(ping_incident::Column::Timestamp)
+            .all(database),
+    )
+    .await
+    .context("Couldn't fetch ping incidents")
+}
+
+async fn record_incident(database: &DatabaseConnection, message: &Message, kind: PingKind) -> Result<()> {
+    let incident = ping_incident::ActiveModel {
+        guild_id: Set(message.guild_id.map(|x| x.0 as i64).unwrap_or_default()),
+        user_id: Set(message.author.id.0 as i64),
+        channel_id: Set(message.channel_id.0 as i64),
+        message_id: Set(message.id.0 as i64),
+        kind: Set(kind),
+        timestamp: Set(*message.timestamp),
+        ..Default::default()
+    };
+    metrics::time_query(
+        "record_incident",
+        ping_incident::Entity::insert(incident).exec(database),
+    )
+    .await
+    .context("Couldn't record ping incident")?;
+    Ok(())
 }
 
 async fn everyone_ping_history(
     pingchu: &Pingchu,
     member: &Member,
 ) -> Result<(Option<DateTimeUtc>, Option<DateTimeUtc>, u32)> {
-    let last_global = guild_ping::Entity::find()
-        .filter(guild_ping::Column::GuildId.eq(member.guild_id.0 as i64))
-        .order_by_desc(guild_ping::Column::LastEveryonePing)
-        .one(&pingchu.database)
-        .await
-        .context("Couldn't fetch guild ping history")?
-        .and_then(|x| x.last_everyone_ping);
+    let last_global = metrics::time_query(
+        "everyone_ping_history",
+        guild_ping::Entity::find()
+            .filter(guild_ping::Column::GuildId.eq(member.guild_id.0 as i64))
+            .order_by_desc(guild_ping::Column::LastEveryonePing)
+            .one(&pingchu.database),
+    )
+    .await
+    .context("Couldn't fetch guild ping history")?
+    .and_then(|x| x.last_everyone_ping);
 
-    let (last_member, pings) = member_ping_info(pingchu, member.guild_id, member.user.id)
+    let (last_member, pings) = member_ping_info(&pingchu.database, member.guild_id, member.user.id)
         .await?
         .map(|x| (x.last_everyone_ping, x.pings))
         .unwrap_or_default();
@@ -204,51 +718,50 @@ async fn upsert_guild_ping(
     let last_user_ping = (user_pings > 0).then_some(time);
     let pings = pings as u32;
 
-    pingchu
-        .database
-        .transaction(|txn| {
-            Box::pin(async move {
-                // apparently sea_orm doesn't support upserts yet like wtf
-                let query = Query::insert()
-                    .into_table(guild_ping::Entity.table_ref())
-                    .columns(guild_ping::Column::iter())
-                    .values_panic([
-                        guild_id.into(),
-                        user_id.into(),
-                        last_everyone_ping.into(),
-                        last_here_ping.into(),
-                        last_role_ping.into(),
-                        last_user_ping.into(),
-                        pings.into(),
-                    ])
-                    .on_conflict(
-                        OnConflict::columns([guild_ping::Column::GuildId, guild_ping::Column::UserId])
-                            .update_exprs({
-                                let mut to_update = vec![];
-                                if let Some(time) = last_everyone_ping {
-                                    to_update.push((guild_ping::Column::LastEveryonePing, Expr::val(time).into()));
-                                }
-                                if let Some(time) = last_here_ping {
-                                    to_update.push((guild_ping::Column::LastHerePing, Expr::val(time).into()));
-                                }
-                                if let Some(time) = last_role_ping {
-                                    to_update.push((guild_ping::Column::LastRolePing, Expr::val(time).into()));
-                                }
-                                if let Some(time) = last_user_ping {
-                                    to_update.push((guild_ping::Column::LastUserPing, Expr::val(time).into()));
-                                }
-                                to_update.push((
-                                    guild_ping::Column::Pings,
-                                    Expr::col(guild_ping::Column::Pings).add(pings),
-                                ));
-                                to_update
-                            })
-                            .to_owned(),
-                    )
-                    .to_owned();
-                execute_query(txn, &query).await.map(|_| ())
-            })
+    let upsert = pingchu.database.transaction(|txn| {
+        Box::pin(async move {
+            // apparently sea_orm doesn't support upserts yet like wtf
+            let query = Query::insert()
+                .into_table(guild_ping::Entity.table_ref())
+                .columns(guild_ping::Column::iter())
+                .values_panic([
+                    guild_id.into(),
+                    user_id.into(),
+                    last_everyone_ping.into(),
+                    last_here_ping.into(),
+                    last_role_ping.into(),
+                    last_user_ping.into(),
+                    pings.into(),
+                ])
+                .on_conflict(
+                    OnConflict::columns([guild_ping::Column::GuildId, guild_ping::Column::UserId])
+                        .update_exprs({
+                            let mut to_update = vec![];
+                            if let Some(time) = last_everyone_ping {
+                                to_update.push((guild_ping::Column::LastEveryonePing, Expr::val(time).into()));
+                            }
+                            if let Some(time) = last_here_ping {
+                                to_update.push((guild_ping::Column::LastHerePing, Expr::val(time).into()));
+                            }
+                            if let Some(time) = last_role_ping {
+                                to_update.push((guild_ping::Column::LastRolePing, Expr::val(time).into()));
+                            }
+                            if let Some(time) = last_user_ping {
+                                to_update.push((guild_ping::Column::LastUserPing, Expr::val(time).into()));
+                            }
+                            to_update.push((
+                                guild_ping::Column::Pings,
+                                Expr::col(guild_ping::Column::Pings).add(pings),
+                            ));
+                            to_update
+                        })
+                        .to_owned(),
+                )
+                .to_owned();
+            execute_query(txn, &query).await.map(|_| ())
         })
+    });
+    metrics::time_query("upsert_guild_ping", upsert)
         .await
         .context("Failed to upsert guild ping data")
 }
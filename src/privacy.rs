@@ -0,0 +1,296 @@
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use poise::serenity_prelude::{GuildId, Timestamp, UserId};
+use sea_orm::entity::Iterable;
+use sea_orm::sea_query::{OnConflict, Query};
+use sea_orm::{
+    ColumnTrait, Condition, DatabaseConnection, DbErr, EntityName, EntityTrait, QueryFilter, TransactionTrait,
+};
+
+use crate::data::{
+    departed_member, execute_query, guild_ping, moderation_action, ping_incident, privacy_setting, response_bag,
+};
+use crate::metrics;
+
+/// The `guild_id` of settings that apply to every guild.
+pub const GLOBAL: i64 = 0;
+
+/// What a member has chosen for one guild. Their settings for that guild override their global ones.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Privacy {
+    pub opted_out: bool,
+    pub hidden: bool,
+}
+
+fn scope_id(guild: Option<GuildId>) -> i64 {
+    guild.map_or(GLOBAL, |x| x.0 as i64)
+}
+
+/// Picks the row that applies out of one member's rows for a guild and their global row.
+fn effective<'a>(rows: impl IntoIterator<Item = &'a privacy_setting::Model>) -> Privacy {
+    rows.into_iter()
+        .max_by_key(|x| x.guild_id != GLOBAL)
+        .map_or_else(Privacy::default, |x| Privacy {
+            opted_out: x.opted_out,
+            hidden: x.hidden,
+        })
+}
+
+/// Gets a member's effective privacy settings on `guild`.
+pub async fn settings(database: &DatabaseConnection, guild: GuildId, user: UserId) -> Result<Privacy> {
+    let rows = metrics::time_query(
+        "privacy_settings",
+        privacy_setting::Entity::find()
+            .filter(privacy_setting::Column::UserId.eq(user.0 as i64))
+            .filter(privacy_setting::Column::GuildId.is_in([guild.0 as i64, GLOBAL]))
+            .all(database),
+    )
+    .await
+    .context("Couldn't fetch privacy settings")?;
+    Ok(effective(&rows))
+}
+
+/// Changes a member's settings for one guild, or globally if `guild` is `None`.
+/// Global changes also apply to the member's settings for each guild, so they really apply everywhere.
+pub async fn update(
+    database: &DatabaseConnection,
+    guild: Option<GuildId>,
+    user: UserId,
+    change: impl Fn(&mut privacy_setting::Model),
+) -> Result<()> {
+    let mut query = privacy_setting::Entity::find().filter(privacy_setting::Column::UserId.eq(user.0 as i64));
+    if let Some(guild) = guild {
+        query = query.filter(privacy_setting::Column::GuildId.is_in([guild.0 as i64, GLOBAL]));
+    }
+    let mut rows = query.all(database).await.context("Couldn't fetch privacy settings")?;
+    let guild_id = scope_id(guild);
+    if !rows.iter().any(|x| x.guild_id == guild_id) {
+        // new settings for a guild start out as the global ones, so only what changed overrides them
+        let privacy = effective(&rows);
+        rows.push(privacy_setting::Model {
+            guild_id,
+            user_id: user.0 as i64,
+            opted_out: privacy.opted_out,
+            hidden: privacy.hidden,
+            updated_at: *Timestamp::now(),
+        });
+    }
+
+    for mut model in rows {
+        if guild.is_some() && model.guild_id != guild_id {
+            continue;
+        }
+        change(&mut model);
+        model.updated_at = *Timestamp::now();
+        save(database, model).await?;
+    }
+    Ok(())
+}
+
+async fn save(database: &DatabaseConnection, model: privacy_setting::Model) -> Result<()> {
+    let query = Query::insert()
+        .into_table(privacy_setting::Entity.table_ref())
+        .columns(privacy_setting::Column::iter())
+        .values_panic([
+            model.guild_id.into(),
+            model.user_id.into(),
+            model.opted_out.into(),
+            model.hidden.into(),
+            model.updated_at.into(),
+        ])
+        .on_conflict(
+            OnConflict::columns([privacy_setting::Column::GuildId, privacy_setting::Column::UserId])
+                .update_columns([
+                    privacy_setting::Column::OptedOut,
+                    privacy_setting::Column::Hidden,
+                    privacy_setting::Column::UpdatedAt,
+                ])
+                .to_owned(),
+        )
+        .to_owned();
+    execute_query(database, &query)
+        .await
+        .context("Couldn't update privacy settings")?;
+    Ok(())
+}
+
+/// Members whose stats shouldn't show up on `guild`'s leaderboards.
+pub async fn hidden_users(database: &DatabaseConnection, guild: GuildId) -> Result<Vec<i64>> {
+    let rows = metrics::time_query(
+        "hidden_users",
+        privacy_setting::Entity::find()
+            .filter(privacy_setting::Column::GuildId.is_in([guild.0 as i64, GLOBAL]))
+            .all(database),
+    )
+    .await
+    .context("Couldn't fetch privacy settings")?;
+    Ok(rows
+        .iter()
+        .into_group_map_by(|x| x.user_id)
+        .into_iter()
+        .filter(|(_, rows)| effective(rows.iter().copied()).hidden)
+        .map(|(user, _)| user)
+        .collect())
+}
+
+/// Every setting that applies on `guild`, including global ones, for admins to review.
+pub async fn guild_settings(database: &DatabaseConnection, guild: GuildId) -> Result<Vec<privacy_setting::Model>> {
+    privacy_setting::Entity::find()
+        .filter(privacy_setting::Column::GuildId.is_in([guild.0 as i64, GLOBAL]))
+        .filter(
+            Condition::any()
+                .add(privacy_setting::Column::OptedOut.eq(true))
+                .add(privacy_setting::Column::Hidden.eq(true)),
+        )
+        .all(database)
+        .await
+        .context("Couldn't fetch privacy settings")
+}
+
+/// Deletes everything tracked about a member on one guild, or on every guild if `guild` is `None`.
+/// Returns how many rows were deleted.
+pub async fn delete_user_data(database: &DatabaseConnection, guild: Option<GuildId>, user: UserId) -> Result<u64> {
+    database
+        .transaction::<_, _, DbErr>(move |txn| {
+            Box::pin(async move {
+                let mut pings = guild_ping::Entity::delete_many().filter(guild_ping::Column::UserId.eq(user.0 as i64));
+                let mut incidents =
+                    ping_incident::Entity::delete_many().filter(ping_incident::Column::UserId.eq(user.0 as i64));
+                let mut actions = moderation_action::Entity::delete_many()
+                    .filter(moderation_action::Column::UserId.eq(user.0 as i64));
+                let mut departures =
+                    departed_member::Entity::delete_many().filter(departed_member::Column::UserId.eq(user.0 as i64));
+                let mut bags =
+                    response_bag::Entity::delete_many().filter(response_bag::Column::UserId.eq(user.0 as i64));
+                if let Some(guild) = guild {
+                    pings = pings.filter(guild_ping::Column::GuildId.eq(guild.0 as i64));
+                    incidents = incidents.filter(ping_incident::Column::GuildId.eq(guild.0 as i64));
+                    actions = actions.filter(moderation_action::Column::GuildId.eq(guild.0 as i64));
+                    departures = departures.filter(departed_member::Column::GuildId.eq(guild.0 as i64));
+                    bags = bags.filter(response_bag::Column::GuildId.eq(guild.0 as i64));
+                }
+                // bags only remember which responses were used, so they aren't counted
+                bags.exec(txn).await?;
+                let pings = pings.exec(txn).await?.rows_affected;
+                let incidents = incidents.exec(txn).await?.rows_affected;
+                let actions = actions.exec(txn).await?.rows_affected;
+                let departures = departures.exec(txn).await?.rows_affected;
+                Ok(pings + incidents + actions + departures)
+            })
+        })
+        .await
+        .context("Couldn't delete member data")
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::prelude::DateTimeUtc;
+
+    use super::*;
+    use crate::data::{self, Backup};
+    use crate::ping::PingKind;
+    use crate::quotas::QuotaAction;
+
+    /// Pings, an incident, a moderation action and a departure for `user` on `guild`.
+    fn member_data(backup: &mut Backup, guild: i64, user: i64) {
+        let time = "2022-06-01T12:00:00Z".parse::<DateTimeUtc>().unwrap();
+        let id = backup.ping_incidents.len() as i32 + 1;
+        backup.guild_pings.push(guild_ping::Model {
+            guild_id: guild,
+            user_id: user,
+            last_everyone_ping: Some(time),
+            last_here_ping: None,
+            last_role_ping: None,
+            last_user_ping: None,
+            pings: 1,
+        });
+        backup.ping_incidents.push(ping_incident::Model {
+            id,
+            guild_id: guild,
+            user_id: user,
+            channel_id: 100,
+            message_id: 1000,
+            kind: PingKind::Everyone,
+            timestamp: time,
+        });
+        backup.moderation_actions.push(moderation_action::Model {
+            id,
+            guild_id: guild,
+            user_id: user,
+            channel_id: 100,
+            message_id: 1000,
+            rule: "1 @everyone ping(s) per 1h".to_string(),
+            action: QuotaAction::Warn,
+            error: None,
+            timestamp: time,
+        });
+        backup.departed_members.push(departed_member::Model {
+            guild_id: guild,
+            user_id: user,
+            departed_at: time,
+        });
+    }
+
+    #[tokio::test]
+    async fn guild_settings_override_global_ones() {
+        let database = data::load_memory_database().await.unwrap();
+        let (guild, other, user) = (GuildId(1), GuildId(2), UserId(10));
+        let hidden = |hidden| Privacy {
+            opted_out: false,
+            hidden,
+        };
+
+        update(&database, None, user, |x| x.hidden = true).await.unwrap();
+        update(&database, Some(guild), user, |x| x.hidden = false)
+            .await
+            .unwrap();
+        assert_eq!(settings(&database, guild, user).await.unwrap(), hidden(false));
+        assert_eq!(settings(&database, other, user).await.unwrap(), hidden(true));
+        assert_eq!(hidden_users(&database, guild).await.unwrap(), Vec::<i64>::new());
+        assert_eq!(hidden_users(&database, other).await.unwrap(), [10]);
+
+        // a new guild row starts from the global one, so opting out there keeps the stats hidden
+        update(&database, Some(other), user, |x| x.opted_out = true)
+            .await
+            .unwrap();
+        let privacy = settings(&database, other, user).await.unwrap();
+        assert!(privacy.opted_out && privacy.hidden);
+
+        // global changes apply to every guild, even ones with their own settings
+        update(&database, None, user, |x| x.opted_out = true).await.unwrap();
+        assert!(settings(&database, guild, user).await.unwrap().opted_out);
+        assert!(settings(&database, GuildId(3), user).await.unwrap().opted_out);
+    }
+
+    #[tokio::test]
+    async fn deletes_every_row_about_a_member() {
+        let database = data::load_memory_database().await.unwrap();
+        let mut backup = Backup::default();
+        member_data(&mut backup, 1, 10);
+        member_data(&mut backup, 2, 10);
+        member_data(&mut backup, 1, 11);
+        data::import_backup(&database, backup, false).await.unwrap();
+
+        let deleted = delete_user_data(&database, Some(GuildId(1)), UserId(10)).await.unwrap();
+        assert_eq!(deleted, 4);
+        let left = data::export_backup(&database).await.unwrap();
+        assert!(left
+            .moderation_actions
+            .iter()
+            .all(|x| (x.guild_id, x.user_id) != (1, 10)));
+        assert!(left.departed_members.iter().all(|x| (x.guild_id, x.user_id) != (1, 10)));
+        assert_eq!(left.guild_pings.len(), 2);
+
+        let deleted = delete_user_data(&database, None, UserId(10)).await.unwrap();
+        assert_eq!(deleted, 4);
+        let left = data::export_backup(&database).await.unwrap();
+        for users in [
+            left.guild_pings.iter().map(|x| x.user_id).collect::<Vec<_>>(),
+            left.ping_incidents.iter().map(|x| x.user_id).collect(),
+            left.moderation_actions.iter().map(|x| x.user_id).collect(),
+            left.departed_members.iter().map(|x| x.user_id).collect(),
+        ] {
+            assert_eq!(users, [11]);
+        }
+    }
+}
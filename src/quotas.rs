@@ -0,0 +1,316 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as AnyhowContext, Result};
+use itertools::Itertools;
+use poise::serenity_prelude::{Context, GuildId, Member, Mentionable, Message, Timestamp, UserId};
+use sea_orm::entity::prelude::{DeriveActiveEnum, EnumIter};
+use sea_orm::{DatabaseConnection, EntityTrait, Set};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::commands::apply_ui;
+use crate::config::{QuotaRule, ServerConfig};
+use crate::data::moderation_action;
+use crate::ping::PingKind;
+use crate::{metrics, Pingchu};
+
+/// What Pingchu does when a member goes over a quota.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
+#[sea_orm(rs_type = "String", db_type = "String(Some(16))")]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaAction {
+    /// Reply in the channel telling the member to slow down.
+    #[sea_orm(string_value = "warn")]
+    Warn,
+    /// Delete the message that went over the quota.
+    #[sea_orm(string_value = "delete")]
+    Delete,
+    /// Time the member out for the rule's `timeout`.
+    #[sea_orm(string_value = "timeout")]
+    Timeout,
+    /// Post an alert in the server's log channel.
+    #[sea_orm(string_value = "alert")]
+    Alert,
+}
+
+impl QuotaAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Warn => "warn",
+            Self::Delete => "delete",
+            Self::Timeout => "timeout",
+            Self::Alert => "alert",
+        }
+    }
+}
+
+/// How many of `counts` a rule applies to.
+pub fn matching_pings(rule: &QuotaRule, counts: &[(PingKind, usize)]) -> u32 {
+    counts
+        .iter()
+        .filter(|(kind, _)| rule.kinds.contains(kind))
+        .map(|(_, count)| *count as u32)
+        .sum()
+}
+
+/// Describes a rule for humans, like `3 @everyone/@here ping(s) per 1h`.
+pub fn describe(rule: &QuotaRule) -> String {
+    format!(
+        "{} {} ping(s) per {}",
+        rule.max,
+        rule.kinds.iter().map(|x| format!("@{}", x.as_str())).join("/"),
+        humantime::format_duration(rule.per)
+    )
+}
+
+/// Pings counted towards one quota rule, oldest first, and how long the rule remembers them.
+#[derive(Default)]
+struct Window {
+    pings: VecDeque<(Instant, u32)>,
+    per: Duration,
+}
+
+/// Remembers recent pings for every member and quota rule, in sliding windows.
+#[derive(Default)]
+pub struct QuotaTracker {
+    windows: Mutex<HashMap<(GuildId, UserId, usize), Window>>,
+}
+
+impl QuotaTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records `count` pings towards the `index`th rule of a guild,
+    /// returning whether the member is now over the rule's quota.
+    pub fn record(
+        &self,
+        guild: GuildId,
+        user: UserId,
+        index: usize,
+        rule: &QuotaRule,
+        count: u32,
+        now: Instant,
+    ) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+        // forget members whose pings have all expired, so windows don't pile up until a restart
+        windows.retain(|_, window| {
+            window
+                .pings
+                .back()
+                .map_or(false, |(time, _)| now.duration_since(*time) < window.per)
+        });
+        let window = windows.entry((guild, user, index)).or_default();
+        window.per = rule.per;
+        while let Some((time, _)) = window.pings.front() {
+            if now.duration_since(*time) >= rule.per {
+                window.pings.pop_front();
+            } else {
+                break;
+            }
+        }
+        window.pings.push_back((now, count));
+        window.pings.iter().map(|(_, count)| count).sum::<u32>() > rule.max
+    }
+}
+
+/// Checks a message against every quota on the server, taking action against its author for each one they broke.
+/// Returns `true` if the message was deleted.
+pub async fn enforce(
+    ctx: &Context,
+    pingchu: &Pingchu,
+    server: &ServerConfig,
+    member: &Member,
+    message: &Message,
+    counts: &[(PingKind, usize)],
+) -> Result<bool> {
+    let now = Instant::now();
+    let mut deleted = false;
+    for (index, rule) in server.quotas.iter().enumerate() {
+        let count = matching_pings(rule, counts);
+        if count == 0
+            || !pingchu
+                .quotas
+                .record(member.guild_id, member.user.id, index, rule, count, now)
+        {
+            continue;
+        }
+        let description = describe(rule);
+        info!(author = member.user.id.0, rule = %description, "Member went over quota");
+        for action in &rule.actions {
+            let result = take_action(ctx, server, member, message, rule, &description, *action).await;
+            let outcome = match &result {
+                Ok(()) => {
+                    deleted |= *action == QuotaAction::Delete;
+                    "ok"
+                }
+                Err(err) => {
+                    warn!(action = action.as_str(), "Couldn't take moderation action: {:#}", err);
+                    "error"
+                }
+            };
+            metrics::MODERATION_ACTIONS
+                .with_label_values(&[action.as_str(), outcome])
+                .inc();
+            record_action(
+                &pingchu.database,
+                message,
+                &description,
+                *action,
+                result.err().map(|x| format!("{:#}", x)),
+            )
+            .await?;
+        }
+    }
+    Ok(deleted)
+}
+
+async fn take_action(
+    ctx: &Context,
+    server: &ServerConfig,
+    member: &Member,
+    message: &Message,
+    rule: &QuotaRule,
+    description: &str,
+    action: QuotaAction,
+) -> Result<()> {
+    match action {
+        QuotaAction::Warn => {
+            // this doesn't reply to the message, since it might be deleted by another action
+            message
+                .channel_id
+                .say(
+                    &ctx.http,
+                    format!("{}, slow down! You can only send {}.", member.mention(), description),
+                )
+                .await
+                .context("Couldn't warn member")?;
+        }
+        QuotaAction::Delete => {
+            message.delete(&ctx.http).await.context("Couldn't delete message")?;
+        }
+        QuotaAction::Timeout => {
+            let until = time_v1::Duration::from_std(rule.timeout).context("Timeout is too long")?;
+            let until = Timestamp::from(*Timestamp::now() + until);
+            member
+                .clone()
+                .disable_communication_until_datetime(&ctx.http, until)
+                .await
+                .context("Couldn't time out member")?;
+        }
+        QuotaAction::Alert => {
+            server
+                .log_channel
+                .send_message(&ctx.http, |msg| {
+                    msg.add_embed(|embed| {
+                        apply_ui(embed, Some(&message.author), message.timestamp);
+                        embed
+                            .title(format!("_{} went over a ping quota!_", member.display_name()))
+                            .url(message.link())
+                            .field("Quota", description, false)
+                            .field("Author", member.mention(), true)
+                            .field("Channel", message.channel_id.mention(), true)
+                    })
+                })
+                .await
+                .context("Couldn't alert moderators")?;
+        }
+    }
+    Ok(())
+}
+
+async fn record_action(
+    database: &DatabaseConnection,
+    message: &Message,
+    rule: &str,
+    action: QuotaAction,
+    error: Option<String>,
+) -> Result<()> {
+    let model = moderation_action::ActiveModel {
+        guild_id: Set(message.guild_id.map(|x| x.0 as i64).unwrap_or_default()),
+        user_id: Set(message.author.id.0 as i64),
+        channel_id: Set(message.channel_id.0 as i64),
+        message_id: Set(message.id.0 as i64),
+        rule: Set(rule.to_string()),
+        action: Set(action),
+        error: Set(error),
+        timestamp: Set(*Timestamp::now()),
+        ..Default::default()
+    };
+    metrics::time_query(
+        "record_moderation_action",
+        moderation_action::Entity::insert(model).exec(database),
+    )
+    .await
+    .context("Couldn't record moderation action")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(max: u32, per: Duration) -> QuotaRule {
+        QuotaRule {
+            kinds: vec![PingKind::Everyone, PingKind::Here],
+            max,
+            per,
+            actions: vec![QuotaAction::Warn],
+            timeout: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn counts_matching_pings() {
+        let rule = rule(1, Duration::from_secs(60));
+        let counts = [(PingKind::Everyone, 1), (PingKind::Here, 2), (PingKind::User, 5)];
+        assert_eq!(matching_pings(&rule, &counts), 3);
+        assert_eq!(describe(&rule), "1 @everyone/@here ping(s) per 1m");
+    }
+
+    #[test]
+    fn goes_over_quota_after_max_pings() {
+        let tracker = QuotaTracker::new();
+        let rule = rule(2, Duration::from_secs(60));
+        let (guild, user, now) = (GuildId(1), UserId(10), Instant::now());
+
+        assert!(!tracker.record(guild, user, 0, &rule, 1, now));
+        assert!(!tracker.record(guild, user, 0, &rule, 1, now));
+        assert!(tracker.record(guild, user, 0, &rule, 1, now));
+        // other members and rules have their own windows
+        assert!(!tracker.record(guild, UserId(11), 0, &rule, 2, now));
+        assert!(!tracker.record(guild, user, 1, &rule, 2, now));
+        assert!(tracker.record(GuildId(2), user, 0, &rule, 3, now));
+    }
+
+    #[test]
+    fn pings_expire_once_the_window_has_passed() {
+        let tracker = QuotaTracker::new();
+        let per = Duration::from_secs(60);
+        let rule = rule(1, per);
+        let (guild, user, start) = (GuildId(1), UserId(10), Instant::now());
+
+        assert!(!tracker.record(guild, user, 0, &rule, 1, start));
+        assert!(tracker.record(guild, user, 0, &rule, 1, start + per - Duration::from_millis(1)));
+        // the first ping is exactly `per` old, so only the last two count
+        assert!(tracker.record(guild, user, 0, &rule, 1, start + per));
+        assert!(!tracker.record(guild, user, 0, &rule, 1, start + per * 3));
+    }
+
+    #[test]
+    fn forgets_members_once_their_pings_expire() {
+        let tracker = QuotaTracker::new();
+        let (short, long) = (rule(5, Duration::from_secs(60)), rule(5, Duration::from_secs(600)));
+        let (guild, now) = (GuildId(1), Instant::now());
+
+        tracker.record(guild, UserId(10), 0, &short, 1, now);
+        tracker.record(guild, UserId(11), 1, &long, 1, now);
+        tracker.record(guild, UserId(12), 0, &short, 1, now + Duration::from_secs(120));
+        let windows = tracker.windows.lock().unwrap();
+        let mut members = windows.keys().map(|(_, user, _)| user.0).collect::<Vec<_>>();
+        members.sort_unstable();
+        assert_eq!(members, [11, 12]);
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::{Context as AnyhowContext, Result};
+use poise::serenity_prelude::{Context, GuildId, Mentionable, Message, Timestamp, UserId};
+use sea_orm::sea_query::{OnConflict, Query};
+use sea_orm::{DatabaseConnection, EntityName, EntityTrait};
+use tracing::{info, warn};
+
+use crate::commands::apply_ui;
+use crate::config::{RaidConfig, ServerConfig};
+use crate::data::{execute_query, raid_mode};
+use crate::Pingchu;
+
+/// Why a guild looks like it's being raided.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RaidTrigger {
+    /// One message mentioned this many users.
+    MessageMentions(usize),
+    /// This many users were mentioned by this many accounts within `raids.window`.
+    MentionRate { mentions: usize, accounts: usize },
+}
+
+impl RaidTrigger {
+    pub fn describe(self) -> String {
+        match self {
+            Self::MessageMentions(mentions) => format!("One message mentioned {} users", mentions),
+            Self::MentionRate { mentions, accounts } => {
+                format!("{} mentions from {} account(s) in a short time", mentions, accounts)
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct GuildState {
+    /// Recent messages with mentions, oldest first.
+    window: VecDeque<(Instant, UserId, usize)>,
+    /// Whether the guild was in raid mode as of the last message.
+    raid_mode: bool,
+    last_alert: Option<Instant>,
+}
+
+/// Watches for mention spikes in every guild. Whether a guild is in raid mode is kept in the database.
+pub struct RaidDetector {
+    config: RaidConfig,
+    guilds: Mutex<HashMap<GuildId, GuildState>>,
+}
+
+/// What the listener should do about a message.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Verdict {
+    /// A new raid was detected and moderators should be alerted.
+    pub alert: Option<RaidTrigger>,
+    /// The guild is in raid mode and the message should be deleted.
+    pub delete: bool,
+    /// This message put the guild in raid mode, which should be saved.
+    pub enable_raid_mode: bool,
+}
+
+impl RaidDetector {
+    pub fn new(config: RaidConfig) -> Self {
+        Self {
+            config,
+            guilds: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a message that mentioned `mentions` users and decides what to do about it.
+    /// `raid_mode` is whether the guild is currently in raid mode.
+    pub fn observe(&self, guild: GuildId, user: UserId, mentions: usize, raid_mode: bool, now: Instant) -> Verdict {
+        let mut guilds = self.guilds.lock().unwrap();
+        let state = guilds.entry(guild).or_default();
+        if state.raid_mode && !raid_mode {
+            // raid mode was cleared, so start over and the raid that was just cleared doesn't immediately trigger again
+            state.window.clear();
+            state.last_alert = None;
+        }
+        state.raid_mode = raid_mode;
+        while let Some((time, _, _)) = state.window.front() {
+            if now.duration_since(*time) >= self.config.window {
+                state.window.pop_front();
+            } else {
+                break;
+            }
+        }
+        if mentions > 0 {
+            state.window.push_back((now, user, mentions));
+        }
+
+        let trigger = if self.config.max_mentions_per_message.map_or(false, |max| mentions > max) {
+            Some(RaidTrigger::MessageMentions(mentions))
+        } else {
+            let total = state.window.iter().map(|(_, _, x)| x).sum();
+            if self.config.max_mentions_per_window.map_or(false, |max| total > max) {
+                let accounts = state.window.iter().map(|(_, x, _)| x).collect::<HashSet<_>>().len();
+                Some(RaidTrigger::MentionRate {
+                    mentions: total,
+                    accounts,
+                })
+            } else {
+                None
+            }
+        };
+
+        let mut verdict = Verdict::default();
+        if let Some(trigger) = trigger {
+            // only alert once per raid, and not again until things have calmed down
+            let calm = state
+                .last_alert
+                .map_or(true, |x| now.duration_since(x) >= self.config.alert_cooldown);
+            if calm && !state.raid_mode {
+                verdict.alert = Some(trigger);
+                state.last_alert = Some(now);
+            }
+            if self.config.raid_mode && !state.raid_mode {
+                state.raid_mode = true;
+                verdict.enable_raid_mode = true;
+            }
+        }
+        verdict.delete = state.raid_mode && mentions >= self.config.raid_mode_min_mentions;
+        verdict
+    }
+}
+
+/// Whether a guild is in raid mode.
+pub async fn raid_mode(database: &DatabaseConnection, guild: GuildId) -> Result<bool> {
+    let row = raid_mode::Entity::find_by_id(guild.0 as i64)
+        .one(database)
+        .await
+        .context("Couldn't fetch raid mode")?;
+    Ok(row.is_some())
+}
+
+/// Turns raid mode on or off for a guild.
+pub async fn set_raid_mode(database: &DatabaseConnection, guild: GuildId, enabled: bool) -> Result<()> {
+    if enabled {
+        let query = Query::insert()
+            .into_table(raid_mode::Entity.table_ref())
+            .columns([raid_mode::Column::GuildId, raid_mode::Column::EnabledAt])
+            .values_panic([(guild.0 as i64).into(), (*Timestamp::now()).into()])
+            // keep when raid mode was first turned on
+            .on_conflict(OnConflict::column(raid_mode::Column::GuildId).do_nothing().to_owned())
+            .to_owned();
+        execute_query(database, &query)
+            .await
+            .context("Couldn't enable raid mode")?;
+    } else {
+        raid_mode::Entity::delete_by_id(guild.0 as i64)
+            .exec(database)
+            .await
+            .context("Couldn't clear raid mode")?;
+    }
+    Ok(())
+}
+
+/// Checks a message for mention spikes. Returns `true` if the message was deleted by raid mode.
+pub async fn check_message(ctx: &Context, pingchu: &Pingchu, server: &ServerConfig, message: &Message) -> Result<bool> {
+    // SAFETY: only guild messages are checked
+    let guild = message.guild_id.unwrap();
+    let raid_mode = raid_mode(&pingchu.database, guild).await?;
+    let verdict = pingchu.raids.observe(
+        guild,
+        message.author.id,
+        message.mentions.len(),
+        raid_mode,
+        Instant::now(),
+    );
+    if verdict.enable_raid_mode {
+        set_raid_mode(&pingchu.database, guild, true).await?;
+    }
+
+    if let Some(trigger) = verdict.alert {
+        warn!(author = message.author.id.0, trigger = %trigger.describe(), "Possible mention raid");
+        let raid_mode = raid_mode || verdict.enable_raid_mode;
+        server
+            .log_channel
+            .send_message(&ctx.http, |msg| {
+                msg.add_embed(|embed| {
+                    apply_ui(embed, Some(&message.author), message.timestamp);
+                    embed
+                        .title("_Possible mention raid!_")
+                        .url(message.link())
+                        .description(trigger.describe())
+                        .field("Latest Author", message.author.mention(), true)
+                        .field("Channel", message.channel_id.mention(), true);
+                    if raid_mode {
+                        embed.field(
+                            "Raid Mode",
+                            "On, messages with mass mentions will be deleted until a moderator runs `/pingchu raid clear`.",
+                            false,
+                        );
+                    }
+                    embed
+                })
+            })
+            .await
+            .context("Couldn't post raid alert")?;
+    }
+
+    if verdict.delete {
+        info!(author = message.author.id.0, "Deleting message in raid mode");
+        message
+            .delete(&ctx.http)
+            .await
+            .context("Couldn't delete message in raid mode")?;
+    }
+    Ok(verdict.delete)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::data;
+
+    #[tokio::test]
+    async fn raid_mode_is_stored() {
+        let database = data::load_memory_database().await.unwrap();
+        let guild = GuildId(1);
+        assert!(!raid_mode(&database, guild).await.unwrap());
+        set_raid_mode(&database, guild, true).await.unwrap();
+        // enabling twice keeps the first row instead of failing
+        set_raid_mode(&database, guild, true).await.unwrap();
+        assert!(raid_mode(&database, guild).await.unwrap());
+        assert!(!raid_mode(&database, GuildId(2)).await.unwrap());
+        set_raid_mode(&database, guild, false).await.unwrap();
+        assert!(!raid_mode(&database, guild).await.unwrap());
+    }
+
+    #[test]
+    fn raid_detection_is_off_by_default() {
+        let detector = RaidDetector::new(RaidConfig::default());
+        let (guild, user, now) = (GuildId(1), UserId(10), Instant::now());
+        for _ in 0..10 {
+            assert_eq!(detector.observe(guild, user, 1_000, false, now), Verdict::default());
+        }
+    }
+
+    #[test]
+    fn clearing_raid_mode_starts_over() {
+        let detector = RaidDetector::new(RaidConfig {
+            max_mentions_per_message: None,
+            max_mentions_per_window: Some(5),
+            raid_mode: true,
+            ..RaidConfig::default()
+        });
+        let (guild, user, now) = (GuildId(1), UserId(10), Instant::now());
+
+        assert_eq!(detector.observe(guild, user, 4, false, now), Verdict::default());
+        let verdict = detector.observe(guild, user, 4, false, now);
+        assert_eq!(
+            verdict.alert,
+            Some(RaidTrigger::MentionRate {
+                mentions: 8,
+                accounts: 1
+            })
+        );
+        assert!(verdict.enable_raid_mode && verdict.delete);
+        // raid mode was saved, so it isn't enabled again
+        let verdict = detector.observe(guild, user, 4, true, now + Duration::from_secs(1));
+        assert!(!verdict.enable_raid_mode && verdict.delete);
+
+        // someone cleared raid mode, possibly from another process
+        let later = now + Duration::from_secs(2);
+        assert_eq!(detector.observe(guild, user, 4, false, later), Verdict::default());
+        let verdict = detector.observe(guild, user, 4, false, later);
+        assert!(verdict.alert.is_some() && verdict.enable_raid_mode);
+    }
+}
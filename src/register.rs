@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Error, Result};
+use poise::builtins::create_application_commands;
+use poise::serenity_prelude::{ApplicationCommand, GuildId, Http};
+use poise::Command;
+use serde_json::{json, Map, Value};
+use tracing::info;
+
+use crate::config::PingchuConfig;
+use crate::Pingchu;
+
+/// Where a set of application commands lives.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Scope {
+    Global,
+    Guild(GuildId),
+}
+
+/// Registers commands according to the config's [`CommandsConfig`](crate::config::CommandsConfig),
+/// skipping any scope whose commands are already up to date.
+pub async fn register_from_config(
+    http: &Http,
+    config: &PingchuConfig,
+    commands: &[Command<Pingchu, Error>],
+) -> Result<()> {
+    let guilds = dev_guilds(config);
+    if config.commands.dev_mode {
+        for guild in &guilds {
+            register(http, Scope::Guild(*guild), commands).await?;
+        }
+        if config.commands.clear_stale {
+            clear(http, Scope::Global).await?;
+        }
+    } else {
+        register(http, Scope::Global, commands).await?;
+        if config.commands.clear_stale {
+            for guild in &guilds {
+                clear(http, Scope::Guild(*guild)).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Registers commands in `scope`, returning `false` if the registered commands already matched.
+pub async fn register(http: &Http, scope: Scope, commands: &[Command<Pingchu, Error>]) -> Result<bool> {
+    let wanted = create_application_commands(commands);
+    let existing = fetch(http, scope).await?;
+    if signatures(wanted.0.iter()) == signatures(existing.iter()) {
+        info!("Commands in {:?} are up to date", scope);
+        return Ok(false);
+    }
+
+    match scope {
+        Scope::Global => {
+            ApplicationCommand::set_global_application_commands(http, |x| {
+                *x = wanted;
+                x
+            })
+            .await
+        }
+        Scope::Guild(guild) => {
+            guild
+                .set_application_commands(http, |x| {
+                    *x = wanted;
+                    x
+                })
+                .await
+        }
+    }
+    .with_context(|| format!("Couldn't register commands in {:?}", scope))?;
+    info!("Registered {} command(s) in {:?}", commands.len(), scope);
+    Ok(true)
+}
+
+/// Removes every command in `scope`, if there are any.
+pub async fn clear(http: &Http, scope: Scope) -> Result<()> {
+    if fetch(http, scope).await?.is_empty() {
+        return Ok(());
+    }
+    match scope {
+        Scope::Global => ApplicationCommand::set_global_application_commands(http, |x| x).await,
+        Scope::Guild(guild) => guild.set_application_commands(http, |x| x).await,
+    }
+    .with_context(|| format!("Couldn't clear commands in {:?}", scope))?;
+    info!("Cleared stale commands in {:?}", scope);
+    Ok(())
+}
+
+/// The guilds commands are registered on in dev mode.
+fn dev_guilds(config: &PingchuConfig) -> Vec<GuildId> {
+    if config.commands.test_guilds.is_empty() {
+        config.allowed_servers.keys().copied().collect()
+    } else {
+        config.commands.test_guilds.clone()
+    }
+}
+
+async fn fetch(http: &Http, scope: Scope) -> Result<Vec<Value>> {
+    let commands = match scope {
+        Scope::Global => ApplicationCommand::get_global_application_commands(http).await,
+        Scope::Guild(guild) => guild.get_application_commands(http).await,
+    }
+    .with_context(|| format!("Couldn't fetch commands in {:?}", scope))?;
+    commands
+        .iter()
+        .map(|x| serde_json::to_value(x).context("Couldn't serialize command"))
+        .collect()
+}
+
+/// Reduces commands to the fields we actually set, keyed by name, so that
+/// commands returned by Discord can be compared with the ones we'd create.
+fn signatures<'a>(commands: impl Iterator<Item = &'a Value>) -> HashMap<String, Value> {
+    commands
+        .map(|x| (x["name"].as_str().unwrap_or_default().to_string(), normalize(x, true)))
+        .collect()
+}
+
+fn normalize(value: &Value, top_level: bool) -> Value {
+    const KEYS: [&str; 7] = [
+        "name",
+        "description",
+        "choices",
+        "channel_types",
+        "min_value",
+        "max_value",
+        "default_permission",
+    ];
+    let mut normalized = Map::new();
+    for key in KEYS {
+        if let Some(x) = value.get(key).filter(|x| !x.is_null()) {
+            normalized.insert(key.to_string(), x.clone());
+        }
+    }
+    // Discord fills in defaults that we usually leave out
+    let default_type = if top_level { json!(1) } else { Value::Null };
+    normalized.insert("type".to_string(), field_or(value, "type", default_type));
+    for key in ["required", "autocomplete"] {
+        normalized.insert(key.to_string(), field_or(value, key, json!(false)));
+    }
+    let options = value
+        .get("options")
+        .and_then(Value::as_array)
+        .map(|x| x.iter().map(|option| normalize(option, false)).collect())
+        .unwrap_or_default();
+    normalized.insert("options".to_string(), Value::Array(options));
+    if normalized.get("default_permission") == Some(&json!(true)) {
+        normalized.remove("default_permission");
+    }
+    if normalized
+        .get("choices")
+        .and_then(Value::as_array)
+        .map_or(false, |x| x.is_empty())
+    {
+        normalized.remove("choices");
+    }
+    Value::Object(normalized)
+}
+
+fn field_or(value: &Value, key: &str, default: Value) -> Value {
+    value.get(key).filter(|x| !x.is_null()).cloned().unwrap_or(default)
+}
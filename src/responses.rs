@@ -0,0 +1,453 @@
+use std::iter;
+
+use anyhow::{anyhow, Context, Error, Result};
+use poise::serenity_prelude::{GuildId, UserId};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use sea_orm::entity::Iterable;
+use sea_orm::sea_query::{OnConflict, Query};
+use sea_orm::{DatabaseConnection, EntityName, EntityTrait, TransactionTrait};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::data::{execute_query, response_bag};
+use crate::metrics;
+use crate::rules::TimeOfDay;
+use crate::templates::{Template, Variable};
+
+/// How Pingchu picks which of the `ping_responses` to reply with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Selection {
+    /// Pick at random every time, so responses can repeat.
+    Random,
+    /// Go through a shuffled bag of responses for each guild before repeating any.
+    ShuffleGuild,
+    /// Go through a shuffled bag of responses for each member before repeating any.
+    ShuffleUser,
+}
+
+impl Default for Selection {
+    fn default() -> Self {
+        Self::Random
+    }
+}
+
+/// One of the `ping_responses`. In the config, this is either just a template
+/// or an object with the template as `text`, a `weight` and conditions under `when`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RawResponse", into = "RawResponse")]
+pub struct Response {
+    pub template: Template,
+    /// How likely this response is compared to the others that can be picked.
+    pub weight: u32,
+    pub when: ResponseConditions,
+}
+
+/// When a response can be picked. The defaults allow it everywhere.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResponseConditions {
+    /// The pinger needs at least this many pings, counting the one they just sent.
+    pub min_pings: Option<u32>,
+    /// The pinger also needs to have pinged @everyone.
+    pub everyone: bool,
+    /// Only picked on these servers.
+    pub servers: Vec<GuildId>,
+    /// Start and end times in UTC, like `["00:00", "06:00"]`.
+    pub between: Option<(TimeOfDay, TimeOfDay)>,
+}
+
+/// What a response's conditions are checked against.
+#[derive(Debug, Copy, Clone)]
+pub struct ResponseContext {
+    pub guild: GuildId,
+    pub pings: u32,
+    pub everyone: bool,
+    pub time: TimeOfDay,
+}
+
+impl ResponseConditions {
+    pub fn allow(&self, context: &ResponseContext) -> bool {
+        self.min_pings.map_or(true, |min| context.pings >= min)
+            && (!self.everyone || context.everyone)
+            && (self.servers.is_empty() || self.servers.contains(&context.guild))
+            && self.between.map_or(true, |x| context.time.is_between(x))
+    }
+}
+
+/// Whether any response can be picked in `context`.
+pub fn any_allowed(responses: &[Response], context: &ResponseContext) -> bool {
+    responses.iter().any(|x| x.weight > 0 && x.when.allow(context))
+}
+
+/// Picks a response whose conditions allow it, by weight.
+pub fn choose<'a, R: Rng + ?Sized>(
+    responses: &'a [Response],
+    context: &ResponseContext,
+    rng: &mut R,
+) -> Option<&'a Response> {
+    let eligible = responses.iter().filter(|x| x.when.allow(context)).collect::<Vec<_>>();
+    // this fails if every eligible response has a weight of 0
+    eligible.choose_weighted(rng, |x| x.weight).ok().copied()
+}
+
+/// Fills a bag with every response as many times as its weight, in a random order.
+pub fn new_bag<R: Rng + ?Sized>(responses: &[Response], rng: &mut R) -> Vec<usize> {
+    let mut bag = responses
+        .iter()
+        .enumerate()
+        .flat_map(|(index, x)| iter::repeat(index).take(x.weight as usize))
+        .collect::<Vec<_>>();
+    bag.shuffle(rng);
+    bag
+}
+
+/// Takes the next response whose conditions allow it out of a bag, refilling the bag first if none of them do.
+pub fn draw<R: Rng + ?Sized>(
+    bag: &mut Vec<usize>,
+    responses: &[Response],
+    context: &ResponseContext,
+    rng: &mut R,
+) -> Option<usize> {
+    let allowed = |index: &usize| responses.get(*index).map_or(false, |x| x.when.allow(context));
+    if !bag.iter().any(allowed) {
+        *bag = new_bag(responses, rng);
+    }
+    let position = bag.iter().rposition(allowed)?;
+    Some(bag.remove(position))
+}
+
+/// Identifies a set of responses, so bags filled from different ones can be thrown out.
+/// This is stored, so it has to stay the same across builds.
+pub fn fingerprint(responses: &[Response]) -> i64 {
+    let mut hasher = Sha256::new();
+    for response in responses {
+        let source = response.template.source();
+        hasher.update((source.len() as u64).to_le_bytes());
+        hasher.update(source);
+        hasher.update(response.weight.to_le_bytes());
+    }
+    let hash = hasher.finalize();
+    i64::from_le_bytes(hash[..8].try_into().unwrap())
+}
+
+/// Picks a response from a guild's bag, or a member's bag if `user` is set, returning its index.
+/// Bags are saved in the database so they survive restarts.
+pub async fn choose_from_bag(
+    database: &DatabaseConnection,
+    responses: &[Response],
+    guild: GuildId,
+    user: Option<UserId>,
+    context: &ResponseContext,
+) -> Result<Option<usize>> {
+    let guild_id = guild.0 as i64;
+    let user_id = user.map_or(0, |x| x.0 as i64);
+    let fingerprint = fingerprint(responses);
+    let save = |bag: &[usize]| -> Result<_> {
+        Ok(Query::insert()
+            .into_table(response_bag::Entity.table_ref())
+            .columns(response_bag::Column::iter())
+            .values_panic([
+                guild_id.into(),
+                user_id.into(),
+                fingerprint.into(),
+                serde_json::to_string(bag)?.into(),
+            ])
+            .to_owned())
+    };
+
+    let txn = database
+        .begin()
+        .await
+        .context("Couldn't start response bag transaction")?;
+    // writing first makes concurrent pings wait for each other, instead of both drawing from the same bag
+    let ensure_row = save(&[])?
+        .on_conflict(
+            OnConflict::columns([response_bag::Column::GuildId, response_bag::Column::UserId])
+                .do_nothing()
+                .to_owned(),
+        )
+        .to_owned();
+    execute_query(&txn, &ensure_row)
+        .await
+        .context("Couldn't create response bag")?;
+    let row = metrics::time_query(
+        "response_bag",
+        response_bag::Entity::find_by_id((guild_id, user_id)).one(&txn),
+    )
+    .await
+    .context("Couldn't fetch response bag")?;
+    let mut bag = row
+        .filter(|x| x.fingerprint == fingerprint)
+        .and_then(|x| serde_json::from_str::<Vec<usize>>(&x.remaining).ok())
+        .unwrap_or_default();
+    let index = draw(&mut bag, responses, context, &mut rand::thread_rng());
+
+    let query = save(&bag)?
+        .on_conflict(
+            OnConflict::columns([response_bag::Column::GuildId, response_bag::Column::UserId])
+                .update_columns([response_bag::Column::Fingerprint, response_bag::Column::Remaining])
+                .to_owned(),
+        )
+        .to_owned();
+    execute_query(&txn, &query)
+        .await
+        .context("Couldn't save response bag")?;
+    txn.commit().await.context("Couldn't save response bag")?;
+    Ok(index)
+}
+
+impl From<Template> for Response {
+    fn from(template: Template) -> Self {
+        Self {
+            template,
+            weight: 1,
+            when: ResponseConditions::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawResponse {
+    Text(String),
+    Full {
+        text: String,
+        #[serde(default = "default_weight")]
+        weight: u32,
+        #[serde(default)]
+        when: ResponseConditions,
+    },
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl TryFrom<RawResponse> for Response {
+    type Error = Error;
+
+    fn try_from(value: RawResponse) -> Result<Self> {
+        let response = match value {
+            RawResponse::Text(text) => Self::from(text.parse::<Template>()?),
+            RawResponse::Full { text, weight, when } => Self {
+                template: text.parse()?,
+                weight,
+                when,
+            },
+        };
+        if response.template.uses(Variable::Rule) {
+            do yeet anyhow!(
+                "`{{rule}}` only works in rule replies, not in `{}`",
+                response.template.source()
+            );
+        }
+        Ok(response)
+    }
+}
+
+impl From<Response> for RawResponse {
+    fn from(value: Response) -> Self {
+        // keep plain responses as plain strings in the config
+        if value.weight == 1 && value.when == ResponseConditions::default() {
+            Self::Text(value.template.into())
+        } else {
+            Self::Full {
+                text: value.template.into(),
+                weight: value.weight,
+                when: value.when,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    const FINGERPRINT: i64 = -3525109026853066308;
+
+    fn response(text: &str, weight: u32, when: ResponseConditions) -> Response {
+        Response {
+            template: text.parse().unwrap(),
+            weight,
+            when,
+        }
+    }
+
+    fn context() -> ResponseContext {
+        ResponseContext {
+            guild: GuildId(1),
+            pings: 5,
+            everyone: false,
+            time: time("12:00"),
+        }
+    }
+
+    fn time(value: &str) -> TimeOfDay {
+        TimeOfDay::try_from(value.to_string()).unwrap()
+    }
+
+    #[test]
+    fn responses_cant_use_rule() {
+        assert!(serde_json::from_str::<Response>(r#""{author}, stop it""#).is_ok());
+        assert!(serde_json::from_str::<Response>(r#""{rule}""#).is_err());
+        assert!(serde_json::from_str::<Response>(r#"{ "text": "{rule}", "weight": 2 }"#).is_err());
+    }
+
+    #[test]
+    fn choose_respects_weights() {
+        let responses = [
+            response("a", 3, ResponseConditions::default()),
+            response("b", 1, ResponseConditions::default()),
+            response("never", 0, ResponseConditions::default()),
+        ];
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut counts = [0; 3];
+        for _ in 0..4000 {
+            let chosen = choose(&responses, &context(), &mut rng).unwrap();
+            counts[responses.iter().position(|x| x == chosen).unwrap()] += 1;
+        }
+        assert!((2800..3200).contains(&counts[0]), "{:?}", counts);
+        assert_eq!(counts[2], 0);
+    }
+
+    #[test]
+    fn choose_checks_conditions() {
+        let conditions = [
+            ResponseConditions {
+                min_pings: Some(6),
+                ..Default::default()
+            },
+            ResponseConditions {
+                everyone: true,
+                ..Default::default()
+            },
+            ResponseConditions {
+                servers: vec![GuildId(2)],
+                ..Default::default()
+            },
+            ResponseConditions {
+                between: Some((time("22:00"), time("06:00"))),
+                ..Default::default()
+            },
+        ];
+        let allowing = [
+            ResponseContext { pings: 6, ..context() },
+            ResponseContext {
+                everyone: true,
+                ..context()
+            },
+            ResponseContext {
+                guild: GuildId(2),
+                ..context()
+            },
+            ResponseContext {
+                time: time("23:30"),
+                ..context()
+            },
+        ];
+        let mut rng = StdRng::seed_from_u64(2);
+        for (when, allowed) in conditions.into_iter().zip(allowing) {
+            let responses = [response("gated", 1, when.clone())];
+            assert!(choose(&responses, &allowed, &mut rng).is_some(), "{:?}", when);
+            assert!(choose(&responses, &context(), &mut rng).is_none(), "{:?}", when);
+        }
+    }
+
+    #[test]
+    fn choose_without_matches() {
+        let mut rng = StdRng::seed_from_u64(3);
+        assert!(choose(&[], &context(), &mut rng).is_none());
+        let weightless = [response("a", 0, ResponseConditions::default())];
+        assert!(choose(&weightless, &context(), &mut rng).is_none());
+    }
+
+    #[test]
+    fn draw_empties_the_bag_before_repeating() {
+        let responses = [
+            response("a", 3, ResponseConditions::default()),
+            response("b", 1, ResponseConditions::default()),
+        ];
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut bag = vec![];
+        for _ in 0..3 {
+            let mut drawn = (0..4)
+                .map(|_| draw(&mut bag, &responses, &context(), &mut rng).unwrap())
+                .collect::<Vec<_>>();
+            drawn.sort_unstable();
+            assert_eq!(drawn, [0, 0, 0, 1]);
+            assert!(bag.is_empty());
+        }
+    }
+
+    #[test]
+    fn draw_skips_responses_that_arent_allowed() {
+        let responses = [
+            response("anyone", 1, ResponseConditions::default()),
+            response(
+                "everyone",
+                1,
+                ResponseConditions {
+                    everyone: true,
+                    ..Default::default()
+                },
+            ),
+        ];
+        let mut rng = StdRng::seed_from_u64(5);
+        // indices from a bag filled with other responses are ignored too
+        let mut bag = vec![1, 7, 0];
+        assert_eq!(draw(&mut bag, &responses, &context(), &mut rng), Some(0));
+        assert_eq!(bag, [1, 7]);
+        // the bag is refilled once nothing in it is allowed
+        assert_eq!(draw(&mut bag, &responses, &context(), &mut rng), Some(0));
+        assert_eq!(bag, [1]);
+
+        let gated = [responses[1].clone()];
+        let mut bag = vec![];
+        assert_eq!(draw(&mut bag, &gated, &context(), &mut rng), None);
+        assert_eq!(bag, [0]);
+    }
+
+    #[test]
+    fn fingerprints_are_stable() {
+        let responses = [
+            response("a", 3, ResponseConditions::default()),
+            response("b", 1, ResponseConditions::default()),
+        ];
+        // changing this resets every stored bag
+        assert_eq!(fingerprint(&responses), FINGERPRINT);
+        assert_ne!(fingerprint(&responses[..1]), fingerprint(&responses));
+        let merged = [response("ab", 3, ResponseConditions::default())];
+        assert_ne!(
+            fingerprint(&merged),
+            fingerprint(&[
+                response("a", 3, ResponseConditions::default()),
+                response("b", 3, ResponseConditions::default())
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_pings_draw_different_responses() {
+        // a file, so pings get their own connections like they do in production
+        let dir = std::env::temp_dir().join(format!("pingchu-bags-{}", std::process::id()));
+        let database = crate::data::load_database(&dir).await.unwrap();
+        let responses = ["a", "b", "c", "d", "e", "f", "g", "h"].map(|x| response(x, 1, ResponseConditions::default()));
+        let context = context();
+        let draws = (0..responses.len()).map(|_| choose_from_bag(&database, &responses, GuildId(1), None, &context));
+        let mut drawn = poise::futures_util::future::try_join_all(draws)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(Option::unwrap)
+            .collect::<Vec<_>>();
+        drawn.sort_unstable();
+        assert_eq!(drawn, (0..responses.len()).collect::<Vec<_>>());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
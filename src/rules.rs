@@ -0,0 +1,470 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context as AnyhowContext, Error, Result};
+use itertools::Itertools;
+use poise::serenity_prelude::{
+    ChannelId, Context, Member, Mentionable, Message, ReactionType, RoleId, Timestamp, UserId,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::commands::apply_ui;
+use crate::config::{PingRule, RuleConditions, ServerConfig};
+use crate::ping::PingKind;
+use crate::templates::{Template, Values, Variable};
+use crate::{metrics, ping, utils, Pingchu};
+
+/// A time of day in UTC, written like `"22:30"`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(u16);
+
+impl TimeOfDay {
+    pub fn of(timestamp: Timestamp) -> Self {
+        Self((timestamp.unix_timestamp().rem_euclid(24 * 60 * 60) / 60) as u16)
+    }
+
+    /// Whether this is at or after `from` and before `to`. Ranges can wrap past midnight.
+    pub fn is_between(self, (from, to): (TimeOfDay, TimeOfDay)) -> bool {
+        if from <= to {
+            from <= self && self < to
+        } else {
+            from <= self || self < to
+        }
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let (hours, minutes) = value
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected a time like `22:30`, got `{}`", value))?;
+        let hours = hours.parse::<u16>().context("Invalid hour")?;
+        let minutes = minutes.parse::<u16>().context("Invalid minute")?;
+        if hours >= 24 || minutes >= 60 {
+            return Err(anyhow!("`{}` isn't a time of day", value));
+        }
+        Ok(Self(hours * 60 + minutes))
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(value: TimeOfDay) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for TimeOfDay {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+/// A unicode emoji or a custom one like `<:pingchu:1234>`, checked when the config loads.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Emoji(pub ReactionType);
+
+impl TryFrom<String> for Emoji {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        ReactionType::try_from(value.as_str())
+            .map(Self)
+            .map_err(|_| anyhow!("`{}` isn't an emoji", value))
+    }
+}
+
+impl From<Emoji> for String {
+    fn from(value: Emoji) -> Self {
+        value.0.to_string()
+    }
+}
+
+/// What a rule does when it matches a message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RuleAction {
+    /// Post an embed about the message in the server's log channel.
+    Log,
+    /// Reply to the message. The template can use the same placeholders as ping responses, plus `{rule}`,
+    /// but `{pings}` is how many of the message's pings the rule matched.
+    Reply { template: Template },
+    /// React to the message with a unicode emoji or a custom one like `<:pingchu:1234>`.
+    React { emoji: Emoji },
+    /// Delete the message. Nothing else is done about it afterwards.
+    Delete,
+    /// Time the author out.
+    Timeout {
+        #[serde(with = "crate::utils::humantime_duration")]
+        duration: Duration,
+    },
+    /// Post an embed about the message in another channel.
+    Forward { channel: ChannelId },
+}
+
+impl RuleAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Log => "log",
+            Self::Reply { .. } => "reply",
+            Self::React { .. } => "react",
+            Self::Delete => "delete",
+            Self::Timeout { .. } => "timeout",
+            Self::Forward { .. } => "forward",
+        }
+    }
+}
+
+/// Everything rules can match on about a message that pinged.
+#[derive(Debug, Copy, Clone)]
+pub struct PingFacts<'a> {
+    pub channel: ChannelId,
+    pub author_roles: &'a [RoleId],
+    pub pinged_roles: &'a [RoleId],
+    pub counts: &'a [(PingKind, usize)],
+    pub time: TimeOfDay,
+}
+
+impl PingFacts<'_> {
+    /// How many pings of `kinds` there were, or pings of any kind if `kinds` is empty.
+    pub fn pings_of(&self, kinds: &[PingKind]) -> usize {
+        self.counts
+            .iter()
+            .filter(|(kind, _)| kinds.is_empty() || kinds.contains(kind))
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+/// Whether a message matches every condition of a rule.
+pub fn matches(conditions: &RuleConditions, facts: &PingFacts) -> bool {
+    let pings = facts.pings_of(&conditions.kinds);
+    pings > 0
+        && conditions.min_pings.map_or(true, |min| pings >= min)
+        && conditions.max_pings.map_or(true, |max| pings <= max)
+        && (conditions.channels.is_empty() || conditions.channels.contains(&facts.channel))
+        && (conditions.author_roles.is_empty()
+            || conditions.author_roles.iter().any(|x| facts.author_roles.contains(x)))
+        && (conditions.target_roles.is_empty()
+            || conditions.target_roles.iter().any(|x| facts.pinged_roles.contains(x)))
+        && conditions.between.map_or(true, |x| facts.time.is_between(x))
+}
+
+/// Fills in a reply template.
+async fn render(
+    ctx: &Context,
+    pingchu: &Pingchu,
+    template: &Template,
+    member: &Member,
+    message: &Message,
+    rule: &PingRule,
+    facts: &PingFacts<'_>,
+) -> Result<String> {
+    let since_last_ping = if template.uses(Variable::SinceLastPing) {
+        // rules run before the message's pings are saved, so these are the stats from before it
+        ping::member_ping_info(&pingchu.database, member.guild_id, member.user.id)
+            .await?
+            .as_ref()
+            .and_then(ping::last_ping)
+            .map(|x| utils::format_time_v1_duration(*message.timestamp - x))
+    } else {
+        None
+    };
+    let values = Values {
+        name: member.display_name().to_string(),
+        pings: facts.pings_of(&rule.when.kinds) as u32,
+        since_last_ping,
+        server: member.guild_id.name(ctx).unwrap_or_default(),
+        random_member: if template.uses(Variable::RandomMember) {
+            ping::random_member(ctx, member)
+        } else {
+            String::new()
+        },
+        author: message.author.mention().to_string(),
+        channel: message.channel_id.mention().to_string(),
+        rule: rule.name.clone(),
+    };
+    Ok(template.render(&values))
+}
+
+/// Runs every rule on the server that matches a message. In dry-run mode, matching rules are only logged.
+/// Stops as soon as a rule deletes the message, returning `true`.
+pub async fn evaluate(
+    ctx: &Context,
+    pingchu: &Pingchu,
+    server: &ServerConfig,
+    member: &Member,
+    message: &Message,
+    facts: &PingFacts<'_>,
+) -> Result<bool> {
+    if server.rules_dry_run {
+        dry_run(server, member.user.id, facts);
+        return Ok(false);
+    }
+    for rule in server.rules.iter().filter(|x| matches(&x.when, facts)) {
+        let actions = rule.actions.iter().map(|x| x.as_str()).join(", ");
+        info!(author = member.user.id.0, rule = %rule.name, actions = %actions, "Rule fired");
+        for action in &rule.actions {
+            let result = take_action(ctx, pingchu, server, member, message, rule, facts, action).await;
+            let outcome = match &result {
+                Ok(()) => "ok",
+                Err(err) => {
+                    warn!(rule = %rule.name, action = action.as_str(), "Couldn't run rule action: {:#}", err);
+                    "error"
+                }
+            };
+            metrics::RULE_ACTIONS
+                .with_label_values(&[action.as_str(), outcome])
+                .inc();
+            if result.is_ok() && matches!(action, RuleAction::Delete) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Logs every rule that matches a message without running any of them, returning the matching rules.
+fn dry_run<'a>(server: &'a ServerConfig, author: UserId, facts: &PingFacts) -> Vec<&'a PingRule> {
+    let matching = server
+        .rules
+        .iter()
+        .filter(|x| matches(&x.when, facts))
+        .collect::<Vec<_>>();
+    for rule in &matching {
+        let actions = rule.actions.iter().map(|x| x.as_str()).join(", ");
+        info!(author = author.0, rule = %rule.name, actions = %actions, "Rule would fire (dry run)");
+        for action in &rule.actions {
+            metrics::RULE_ACTIONS
+                .with_label_values(&[action.as_str(), "dry_run"])
+                .inc();
+        }
+    }
+    matching
+}
+
+async fn take_action(
+    ctx: &Context,
+    pingchu: &Pingchu,
+    server: &ServerConfig,
+    member: &Member,
+    message: &Message,
+    rule: &PingRule,
+    facts: &PingFacts<'_>,
+    action: &RuleAction,
+) -> Result<()> {
+    match action {
+        RuleAction::Log => {
+            post_embed(ctx, server.log_channel, member, message, rule)
+                .await
+                .context("Couldn't log message")?;
+        }
+        RuleAction::Reply { template } => {
+            let content = render(ctx, pingchu, template, member, message, rule, facts).await?;
+            message
+                .channel_id
+                .send_message(&ctx.http, |msg| msg.reference_message(message).content(content))
+                .await
+                .context("Couldn't reply to message")?;
+        }
+        RuleAction::React { emoji } => {
+            message
+                .react(&ctx.http, emoji.0.clone())
+                .await
+                .context("Couldn't react to message")?;
+        }
+        RuleAction::Delete => {
+            message.delete(&ctx.http).await.context("Couldn't delete message")?;
+        }
+        RuleAction::Timeout { duration } => {
+            let until = time_v1::Duration::from_std(*duration).context("Timeout is too long")?;
+            let until = Timestamp::from(*Timestamp::now() + until);
+            member
+                .clone()
+                .disable_communication_until_datetime(&ctx.http, until)
+                .await
+                .context("Couldn't time out member")?;
+        }
+        RuleAction::Forward { channel } => {
+            post_embed(ctx, *channel, member, message, rule)
+                .await
+                .context("Couldn't forward message")?;
+        }
+    }
+    Ok(())
+}
+
+async fn post_embed(
+    ctx: &Context,
+    channel: ChannelId,
+    member: &Member,
+    message: &Message,
+    rule: &PingRule,
+) -> Result<()> {
+    channel
+        .send_message(&ctx.http, |msg| {
+            msg.add_embed(|embed| {
+                apply_ui(embed, Some(&message.author), message.timestamp);
+                embed
+                    .title(format!("_{} matched {}_", member.display_name(), rule.name))
+                    .url(message.link())
+                    .field(
+                        "Message",
+                        message.content.graphemes(true).take(100).collect::<String>(),
+                        false,
+                    )
+                    .field("Author", message.author.mention(), true)
+                    .field("Channel", message.channel_id.mention(), true)
+            })
+        })
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn time(x: &str) -> TimeOfDay {
+        TimeOfDay::try_from(x.to_string()).unwrap()
+    }
+
+    fn facts<'a>(
+        counts: &'a [(PingKind, usize)],
+        author_roles: &'a [RoleId],
+        pinged_roles: &'a [RoleId],
+    ) -> PingFacts<'a> {
+        PingFacts {
+            channel: ChannelId(100),
+            author_roles,
+            pinged_roles,
+            counts,
+            time: time("12:00"),
+        }
+    }
+
+    fn rule(name: &str, when: RuleConditions) -> PingRule {
+        PingRule {
+            name: name.to_string(),
+            when,
+            actions: vec![RuleAction::Log, RuleAction::Delete],
+        }
+    }
+
+    #[test]
+    fn parses_times_of_day() {
+        assert_eq!(time("07:05").to_string(), "07:05");
+        assert_eq!(
+            TimeOfDay::of(Timestamp::from_unix_timestamp(24 * 60 * 60 + 90).unwrap()),
+            time("00:01")
+        );
+        for invalid in ["24:00", "12:60", "noon", "12"] {
+            assert!(TimeOfDay::try_from(invalid.to_string()).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn times_can_wrap_past_midnight() {
+        let day = (time("09:00"), time("17:00"));
+        assert!(time("09:00").is_between(day));
+        assert!(time("16:59").is_between(day));
+        assert!(!time("17:00").is_between(day));
+        assert!(!time("08:59").is_between(day));
+
+        let night = (time("22:00"), time("06:00"));
+        assert!(time("22:00").is_between(night));
+        assert!(time("23:59").is_between(night));
+        assert!(time("00:00").is_between(night));
+        assert!(time("05:59").is_between(night));
+        assert!(!time("06:00").is_between(night));
+        assert!(!time("12:00").is_between(night));
+    }
+
+    #[test]
+    fn matches_every_condition() {
+        let counts = [(PingKind::Everyone, 1), (PingKind::Role, 2)];
+        let facts = facts(&counts, &[RoleId(1)], &[RoleId(2)]);
+        let when = |f: fn(&mut RuleConditions)| {
+            let mut conditions = RuleConditions::default();
+            f(&mut conditions);
+            matches(&conditions, &facts)
+        };
+
+        assert!(when(|_| {}));
+        assert!(when(|x| x.kinds = vec![PingKind::Role]));
+        assert!(!when(|x| x.kinds = vec![PingKind::Here]));
+        assert!(when(|x| x.min_pings = Some(3)));
+        assert!(!when(|x| x.min_pings = Some(4)));
+        assert!(when(|x| x.max_pings = Some(3)));
+        assert!(!when(|x| {
+            x.kinds = vec![PingKind::Role];
+            x.max_pings = Some(1)
+        }));
+        assert!(when(|x| x.channels = vec![ChannelId(100), ChannelId(101)]));
+        assert!(!when(|x| x.channels = vec![ChannelId(101)]));
+        assert!(when(|x| x.author_roles = vec![RoleId(1)]));
+        assert!(!when(|x| x.author_roles = vec![RoleId(2)]));
+        assert!(when(|x| x.target_roles = vec![RoleId(2)]));
+        assert!(!when(|x| x.target_roles = vec![RoleId(1)]));
+        assert!(when(|x| x.between = Some((time("11:00"), time("13:00")))));
+        assert!(!when(|x| x.between = Some((time("22:00"), time("06:00")))));
+
+        // messages without any pings never match
+        assert!(!matches(
+            &RuleConditions::default(),
+            &PingFacts { counts: &[], ..facts }
+        ));
+    }
+
+    #[test]
+    fn dry_runs_only_count_matching_rules() {
+        let mut server = ServerConfig::new(ChannelId(1));
+        server.rules_dry_run = true;
+        server.rules = vec![
+            rule(
+                "everyone",
+                RuleConditions {
+                    kinds: vec![PingKind::Everyone],
+                    ..Default::default()
+                },
+            ),
+            rule(
+                "here",
+                RuleConditions {
+                    kinds: vec![PingKind::Here],
+                    ..Default::default()
+                },
+            ),
+        ];
+        let counts = [(PingKind::Everyone, 1)];
+        let deletes = || metrics::RULE_ACTIONS.with_label_values(&["delete", "dry_run"]).get();
+
+        let before = deletes();
+        let matching = dry_run(&server, UserId(10), &facts(&counts, &[], &[]));
+        assert_eq!(
+            matching.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(),
+            ["everyone"]
+        );
+        assert_eq!(deletes() - before, 1);
+    }
+
+    #[test]
+    fn checks_emoji_when_loading() {
+        for emoji in ["😴", "<:pingchu:1234>", "<a:pingchu:1234>"] {
+            let action = json!({ "type": "react", "emoji": emoji });
+            let parsed = serde_json::from_value::<RuleAction>(action.clone()).unwrap();
+            assert_eq!(serde_json::to_value(parsed).unwrap(), action);
+        }
+        for emoji in ["", "<:pingchu>", "<:pingchu:emoji>"] {
+            let action = json!({ "type": "react", "emoji": emoji });
+            assert!(serde_json::from_value::<RuleAction>(action).is_err(), "{}", emoji);
+        }
+    }
+}
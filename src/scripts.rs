@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use anyhow::{anyhow, Context as AnyhowContext, Result};
+use poise::serenity_prelude::{Context, GuildId, Member, Mentionable, Message, ReactionType, UserId};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use sea_orm::prelude::DateTimeUtc;
+use sea_orm::DatabaseConnection;
+use tokio::runtime::Handle;
+use tracing::{debug, info, warn};
+
+use crate::commands::apply_ui;
+use crate::config::{ScriptsConfig, ServerConfig};
+use crate::data::guild_ping;
+use crate::ping::PingKind;
+use crate::rules::PingFacts;
+use crate::{metrics, ping, privacy, Pingchu};
+
+/// The function Pingchu calls in every script.
+const ENTRY_POINT: &str = "on_ping";
+
+/// Something a script asked Pingchu to do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptAction {
+    Reply(String),
+    React(String),
+    Log(String),
+}
+
+struct LoadedScript {
+    modified: SystemTime,
+    /// `None` if the script didn't compile, so it isn't recompiled until it changes.
+    ast: Option<Arc<AST>>,
+}
+
+/// Loads per-server scripts from `scripts/<guild id>.rhai`, reloading them whenever they change.
+pub struct ScriptHost {
+    dir: PathBuf,
+    config: ScriptsConfig,
+    scripts: Mutex<HashMap<GuildId, LoadedScript>>,
+}
+
+impl ScriptHost {
+    pub fn new(dir: PathBuf, config: ScriptsConfig) -> Self {
+        Self {
+            dir,
+            config,
+            scripts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Gets a guild's script, compiling it again if the file changed since it was last loaded.
+    fn script(&self, guild: GuildId) -> Option<Arc<AST>> {
+        let path = self.dir.join(format!("{}.rhai", guild));
+        let mut scripts = self.scripts.lock().unwrap();
+        let modified = match fs::metadata(&path).and_then(|x| x.modified()) {
+            Ok(x) => x,
+            Err(_) => {
+                if scripts.remove(&guild).is_some() {
+                    info!(guild = guild.0, "Unloaded script");
+                }
+                return None;
+            }
+        };
+        if let Some(script) = scripts.get(&guild) {
+            if script.modified == modified {
+                return script.ast.clone();
+            }
+        }
+
+        let result: Result<AST> = try {
+            let source = fs::read_to_string(&path)?;
+            engine(&self.config, guild).compile(&source)?
+        };
+        let ast = match result {
+            Ok(ast) => {
+                info!(guild = guild.0, "Loaded script from {}", path.display());
+                Some(Arc::new(ast))
+            }
+            Err(err) => {
+                warn!(
+                    guild = guild.0,
+                    "Couldn't load script from {}: {:#}",
+                    path.display(),
+                    err
+                );
+                None
+            }
+        };
+        scripts.insert(
+            guild,
+            LoadedScript {
+                modified,
+                ast: ast.clone(),
+            },
+        );
+        ast
+    }
+}
+
+/// Creates an engine with the configured limits for a guild's script. Scripts can't touch the filesystem or network,
+/// and anything they print is logged instead of written to stdout.
+fn engine(config: &ScriptsConfig, guild: GuildId) -> Engine {
+    let mut engine = Engine::new();
+    engine.on_print(move |text| info!(guild = guild.0, "Script printed: {}", text));
+    engine.on_debug(move |text, source, position| debug!(guild = guild.0, source, %position, "Script debug: {}", text));
+    engine.set_max_operations(config.max_operations);
+    engine.set_max_call_levels(config.max_call_levels);
+    engine.set_max_expr_depths(64, 32);
+    engine.set_max_string_size(config.max_string_size);
+    engine.set_max_array_size(config.max_collection_size);
+    engine.set_max_map_size(config.max_collection_size);
+    engine.register_fn("reply", |text: &str| action("reply", text));
+    engine.register_fn("react", |emoji: &str| action("react", emoji));
+    engine.register_fn("log", |text: &str| action("log", text));
+    engine
+}
+
+fn action(kind: &str, value: &str) -> Map {
+    let mut map = Map::new();
+    map.insert("type".into(), kind.into());
+    map.insert("value".into(), value.into());
+    map
+}
+
+/// Turns what `on_ping` returned into actions. Scripts can return nothing, one action or an array of them.
+pub fn parse_actions(result: Dynamic, max_actions: usize) -> Result<Vec<ScriptAction>> {
+    let actions = if result.is::<()>() {
+        vec![]
+    } else if result.is::<Array>() {
+        result.cast::<Array>()
+    } else {
+        vec![result]
+    };
+    if actions.len() > max_actions {
+        do yeet anyhow!(
+            "Script returned {} actions, but at most {} are allowed",
+            actions.len(),
+            max_actions
+        );
+    }
+    actions
+        .into_iter()
+        .map(|x| {
+            let mut map = x
+                .try_cast::<Map>()
+                .ok_or_else(|| anyhow!("Expected an action like `reply(\"hi\")`"))?;
+            let mut field = |name: &str| {
+                map.remove(name)
+                    .and_then(|x| x.into_string().ok())
+                    .ok_or_else(|| anyhow!("Action is missing `{}`", name))
+            };
+            let kind = field("type")?;
+            let value = field("value")?;
+            match kind.as_str() {
+                "reply" => Ok(ScriptAction::Reply(value)),
+                "react" => Ok(ScriptAction::React(value)),
+                "log" => Ok(ScriptAction::Log(value)),
+                _ => Err(anyhow!("Unknown action `{}`", kind)),
+            }
+        })
+        .collect()
+}
+
+/// The ping event scripts receive.
+fn event_map(member: &Member, message: &Message, facts: &PingFacts) -> Map {
+    let ids = |ids: Vec<String>| ids.into_iter().map(Dynamic::from).collect::<Array>();
+    let count = |kind: PingKind| {
+        facts
+            .counts
+            .iter()
+            .find(|(x, _)| *x == kind)
+            .map_or(0, |(_, count)| *count as i64)
+    };
+    let mut map = Map::new();
+    map.insert("guild".into(), member.guild_id.to_string().into());
+    map.insert("channel".into(), message.channel_id.to_string().into());
+    map.insert("message".into(), message.id.to_string().into());
+    map.insert("author".into(), message.author.id.to_string().into());
+    map.insert("author_name".into(), member.display_name().to_string().into());
+    map.insert("content".into(), message.content.clone().into());
+    map.insert("timestamp".into(), message.timestamp.to_string().into());
+    map.insert(
+        "author_roles".into(),
+        ids(facts.author_roles.iter().map(|x| x.to_string()).collect()).into(),
+    );
+    map.insert(
+        "pinged_roles".into(),
+        ids(facts.pinged_roles.iter().map(|x| x.to_string()).collect()).into(),
+    );
+    map.insert(
+        "pinged_users".into(),
+        ids(message.mentions.iter().map(|x| x.id.to_string()).collect()).into(),
+    );
+    map.insert("user_pings".into(), count(PingKind::User).into());
+    map.insert("role_pings".into(), count(PingKind::Role).into());
+    map.insert("everyone".into(), (count(PingKind::Everyone) > 0).into());
+    map.insert("here".into(), (count(PingKind::Here) > 0).into());
+    map.insert("pings".into(), (facts.pings_of(&[]) as i64).into());
+    map
+}
+
+fn stats_map(model: guild_ping::Model) -> Map {
+    let time = |x: Option<DateTimeUtc>| x.map_or(Dynamic::UNIT, |x| x.to_rfc3339().into());
+    let mut map = Map::new();
+    map.insert("user".into(), (model.user_id as u64).to_string().into());
+    map.insert("pings".into(), (model.pings as i64).into());
+    map.insert("last_everyone_ping".into(), time(model.last_everyone_ping));
+    map.insert("last_here_ping".into(), time(model.last_here_ping));
+    map.insert("last_role_ping".into(), time(model.last_role_ping));
+    map.insert("last_user_ping".into(), time(model.last_user_ping));
+    map
+}
+
+/// Runs a script on a blocking thread, giving it read-only access to the guild's stats.
+/// Returns whatever `on_ping` returned.
+fn call(
+    config: &ScriptsConfig,
+    ast: &AST,
+    database: DatabaseConnection,
+    guild: GuildId,
+    event: Map,
+    handle: Handle,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    let mut engine = engine(config, guild);
+    let deadline = Instant::now() + config.timeout;
+    engine.on_progress(move |_| (Instant::now() > deadline).then_some(Dynamic::UNIT));
+
+    // stats are fetched synchronously, since scripts run on their own thread
+    let stats_db = database.clone();
+    let stats_handle = handle.clone();
+    engine.register_result_fn("stats", move |user: &str| -> Result<Dynamic, Box<EvalAltResult>> {
+        let user = UserId(user.parse::<u64>().map_err(|_| format!("`{}` isn't a user ID", user))?);
+        let result: Result<Option<guild_ping::Model>> = stats_handle.block_on(async {
+            if privacy::settings(&stats_db, guild, user).await?.hidden {
+                return Ok(None);
+            }
+            ping::member_ping_info(&stats_db, guild, user).await
+        });
+        match result {
+            Ok(Some(model)) => Ok(stats_map(model).into()),
+            Ok(None) => Ok(Dynamic::UNIT),
+            Err(err) => Err(format!("{:#}", err).into()),
+        }
+    });
+    engine.register_result_fn("leaderboard", move |limit: i64| -> Result<Array, Box<EvalAltResult>> {
+        handle
+            .block_on(ping::guild_leaderboard(&database, guild, limit.clamp(0, 100) as u64))
+            .map(|rows| rows.into_iter().map(|x| stats_map(x).into()).collect())
+            .map_err(|err| format!("{:#}", err).into())
+    });
+
+    engine.call_fn(&mut Scope::new(), ast, ENTRY_POINT, (event,))
+}
+
+/// Runs the guild's script, if it has one, and carries out the actions it returns.
+pub async fn run(
+    ctx: &Context,
+    pingchu: &Pingchu,
+    server: &ServerConfig,
+    member: &Member,
+    message: &Message,
+    facts: &PingFacts<'_>,
+) -> Result<()> {
+    let ast = match pingchu.scripts.script(member.guild_id) {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let config = pingchu.scripts.config.clone();
+    let max_actions = config.max_actions;
+    let database = pingchu.database.clone();
+    let guild = member.guild_id;
+    let event = event_map(member, message, facts);
+    let handle = Handle::current();
+    let result = tokio::task::spawn_blocking(move || call(&config, &ast, database, guild, event, handle))
+        .await
+        .context("Script thread panicked")?
+        .map_err(|err| anyhow!("{}", err))
+        .and_then(|x| parse_actions(x, max_actions));
+
+    let actions = match result {
+        Ok(x) => x,
+        Err(err) => {
+            warn!(guild = guild.0, "Script failed: {:#}", err);
+            metrics::SCRIPT_RUNS.with_label_values(&["error"]).inc();
+            return Ok(());
+        }
+    };
+    metrics::SCRIPT_RUNS.with_label_values(&["ok"]).inc();
+    debug!(guild = guild.0, ?actions, "Script finished");
+    for action in actions {
+        if let Err(err) = take_action(ctx, server, member, message, &action).await {
+            warn!(guild = guild.0, ?action, "Couldn't run script action: {:#}", err);
+        }
+    }
+    Ok(())
+}
+
+async fn take_action(
+    ctx: &Context,
+    server: &ServerConfig,
+    member: &Member,
+    message: &Message,
+    action: &ScriptAction,
+) -> Result<()> {
+    match action {
+        ScriptAction::Reply(content) => {
+            message
+                .channel_id
+                .send_message(&ctx.http, |msg| msg.reference_message(message).content(content))
+                .await
+                .context("Couldn't reply to message")?;
+        }
+        ScriptAction::React(emoji) => {
+            let reaction = ReactionType::try_from(emoji.as_str()).context("Invalid emoji")?;
+            message
+                .react(&ctx.http, reaction)
+                .await
+                .context("Couldn't react to message")?;
+        }
+        ScriptAction::Log(text) => {
+            server
+                .log_channel
+                .send_message(&ctx.http, |msg| {
+                    msg.add_embed(|embed| {
+                        apply_ui(embed, Some(&message.author), message.timestamp);
+                        embed
+                            .title(format!("_{}'s ping triggered a script_", member.display_name()))
+                            .url(message.link())
+                            .description(text)
+                            .field("Author", message.author.mention(), true)
+                            .field("Channel", message.channel_id.mention(), true)
+                    })
+                })
+                .await
+                .context("Couldn't log script output")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::data;
+
+    const GUILD: GuildId = GuildId(1);
+
+    /// Runs `source`'s `on_ping` the way [`run`] does, with an event that only has an author.
+    async fn run_script(config: ScriptsConfig, source: &str) -> Result<Vec<ScriptAction>> {
+        let database = data::load_memory_database().await.unwrap();
+        let ast = engine(&config, GUILD).compile(source).unwrap();
+        let mut event = Map::new();
+        event.insert("author".into(), "10".into());
+        let handle = Handle::current();
+        let max_actions = config.max_actions;
+        tokio::task::spawn_blocking(move || call(&config, &ast, database, GUILD, event, handle))
+            .await
+            .unwrap()
+            .map_err(|err| anyhow!("{}", err))
+            .and_then(|x| parse_actions(x, max_actions))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn returns_every_kind_of_action() {
+        let source = r#"
+            fn on_ping(event) {
+                print("pinged by " + event.author);
+                [reply("hi <@" + event.author + ">"), react("👍"), log("logged")]
+            }
+        "#;
+        assert_eq!(
+            run_script(ScriptsConfig::default(), source).await.unwrap(),
+            [
+                ScriptAction::Reply("hi <@10>".to_string()),
+                ScriptAction::React("👍".to_string()),
+                ScriptAction::Log("logged".to_string()),
+            ]
+        );
+        let source = r#"fn on_ping(event) { if event.author == "11" { reply("hi") } }"#;
+        assert_eq!(run_script(ScriptsConfig::default(), source).await.unwrap(), []);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stops_scripts_that_go_over_the_limits() {
+        let source = "fn on_ping(event) { let x = 0; loop { x += 1; } }";
+        let config = ScriptsConfig {
+            max_operations: 1_000,
+            timeout: Duration::from_secs(60),
+            ..ScriptsConfig::default()
+        };
+        let err = run_script(config, source).await.unwrap_err();
+        assert!(err.to_string().contains("Too many operations"), "{}", err);
+
+        let config = ScriptsConfig {
+            max_operations: 0,
+            timeout: Duration::from_millis(50),
+            ..ScriptsConfig::default()
+        };
+        let err = run_script(config, source).await.unwrap_err();
+        assert!(err.to_string().contains("terminated"), "{}", err);
+
+        let config = ScriptsConfig {
+            max_actions: 1,
+            ..ScriptsConfig::default()
+        };
+        let source = r#"fn on_ping(event) { [reply("a"), reply("b")] }"#;
+        assert!(run_script(config, source).await.is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_actions() {
+        let action = |kind: &str| Dynamic::from(action(kind, "hi"));
+        assert!(parse_actions(Dynamic::UNIT, 5).unwrap().is_empty());
+        assert_eq!(
+            parse_actions(action("reply"), 5).unwrap(),
+            [ScriptAction::Reply("hi".to_string())]
+        );
+        assert!(parse_actions(action("ban"), 5).is_err());
+        assert!(parse_actions(Dynamic::from("hi"), 5).is_err());
+        assert!(parse_actions(Dynamic::from(Map::new()), 5).is_err());
+    }
+
+    #[test]
+    fn reloads_scripts_when_they_change() {
+        let dir = std::env::temp_dir().join(format!("pingchu-scripts-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{}.rhai", GUILD));
+        let host = ScriptHost::new(dir.clone(), ScriptsConfig::default());
+        assert!(host.script(GUILD).is_none());
+
+        fs::write(&path, "fn on_ping(event) { reply(\"one\") }").unwrap();
+        let first = host.script(GUILD).unwrap();
+        assert!(Arc::ptr_eq(&first, &host.script(GUILD).unwrap()));
+
+        // make sure the modification time changes, even on coarse filesystems
+        std::thread::sleep(Duration::from_millis(50));
+        fs::write(&path, "fn on_ping(event) { reply(\"two\") ").unwrap();
+        assert!(host.script(GUILD).is_none());
+        std::thread::sleep(Duration::from_millis(50));
+        fs::write(&path, "fn on_ping(event) { reply(\"two\") }").unwrap();
+        assert!(!Arc::ptr_eq(&first, &host.script(GUILD).unwrap()));
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(host.script(GUILD).is_none());
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use poise::serenity_prelude::{ChannelId, GuildId, Http, Timestamp};
+use sea_orm::entity::Iterable;
+use sea_orm::sea_query::{OnConflict, Query};
+use sea_orm::{DatabaseConnection, EntityName, EntityTrait};
+use tracing::{error, info};
+
+use crate::config::{PingchuConfig, ServerConfig};
+use crate::data::{execute_query, guild_allowlist};
+
+/// Whether Pingchu is allowed to run on a guild, and why.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ServerStatus {
+    /// Allowed by `allowed_servers` in the config.
+    AllowedByConfig,
+    /// Allowed by a bot owner.
+    Allowed,
+    /// Denied by a bot owner, even if the config allows it.
+    Denied,
+    /// Never mentioned anywhere.
+    Unknown,
+}
+
+impl ServerStatus {
+    pub fn is_allowed(self) -> bool {
+        matches!(self, Self::AllowedByConfig | Self::Allowed)
+    }
+}
+
+/// The set of servers Pingchu runs on, combining `allowed_servers` from the config
+/// with overrides that bot owners have stored in the database.
+pub struct ServerRegistry {
+    configured: HashMap<GuildId, ServerConfig>,
+    overrides: RwLock<HashMap<GuildId, guild_allowlist::Model>>,
+}
+
+impl ServerRegistry {
+    pub async fn load(config: &PingchuConfig, database: &DatabaseConnection) -> Result<Self> {
+        Ok(Self {
+            configured: config.allowed_servers.clone(),
+            overrides: RwLock::new(fetch_overrides(database).await?),
+        })
+    }
+
+    /// Picks up overrides stored by other processes, like `/servers` in the bot while serving interactions.
+    pub async fn reload(&self, database: &DatabaseConnection) -> Result<()> {
+        let overrides = fetch_overrides(database).await?;
+        *self.overrides.write().unwrap() = overrides;
+        Ok(())
+    }
+
+    /// Gets the config for a guild, or `None` if Pingchu shouldn't run there.
+    pub fn get(&self, guild: GuildId) -> Option<ServerConfig> {
+        let x = self.overrides.read().unwrap().get(&guild).cloned();
+        match x {
+            Some(x) if !x.allowed => None,
+            Some(x) => {
+                let configured = self.configured.get(&guild);
+                match (configured, x.log_channel) {
+                    (Some(config), Some(channel)) => Some(ServerConfig {
+                        log_channel: ChannelId(channel as u64),
+                        ..config.clone()
+                    }),
+                    (Some(config), None) => Some(config.clone()),
+                    (None, Some(channel)) => Some(ServerConfig::new(ChannelId(channel as u64))),
+                    (None, None) => None,
+                }
+            }
+            None => self.configured.get(&guild).cloned(),
+        }
+    }
+
+    pub fn is_allowed(&self, guild: GuildId) -> bool {
+        self.get(guild).is_some()
+    }
+
+    pub fn status(&self, guild: GuildId) -> ServerStatus {
+        let allowed = self.overrides.read().unwrap().get(&guild).map(|x| x.allowed);
+        match allowed {
+            Some(true) => ServerStatus::Allowed,
+            Some(false) => ServerStatus::Denied,
+            None if self.configured.contains_key(&guild) => ServerStatus::AllowedByConfig,
+            None => ServerStatus::Unknown,
+        }
+    }
+
+    /// Every guild that is either configured or has an override.
+    pub fn known_guilds(&self) -> Vec<GuildId> {
+        let overrides = self.overrides.read().unwrap();
+        let mut guilds = self
+            .configured
+            .keys()
+            .chain(overrides.keys())
+            .copied()
+            .collect::<Vec<_>>();
+        guilds.sort();
+        guilds.dedup();
+        guilds
+    }
+
+    /// Allows a guild, optionally changing its log channel.
+    pub async fn allow(
+        &self,
+        database: &DatabaseConnection,
+        guild: GuildId,
+        log_channel: Option<ChannelId>,
+    ) -> Result<()> {
+        let log_channel = log_channel
+            .or_else(|| {
+                self.get_override(guild)
+                    .and_then(|x| x.log_channel.map(|x| ChannelId(x as u64)))
+            })
+            .or_else(|| self.configured.get(&guild).map(|x| x.log_channel));
+        if log_channel.is_none() {
+            do yeet anyhow!("{} needs a log channel before it can be allowed", guild);
+        }
+        self.store(database, guild, true, log_channel).await
+    }
+
+    /// Denies a guild, even if it's in `allowed_servers`.
+    pub async fn deny(&self, database: &DatabaseConnection, guild: GuildId) -> Result<()> {
+        let log_channel = self
+            .get_override(guild)
+            .and_then(|x| x.log_channel.map(|x| ChannelId(x as u64)));
+        self.store(database, guild, false, log_channel).await
+    }
+
+    /// Changes a guild's log channel without changing whether it's allowed.
+    /// Guilds that haven't been allowed or denied yet need `allow` instead.
+    pub async fn set_log_channel(
+        &self,
+        database: &DatabaseConnection,
+        guild: GuildId,
+        channel: ChannelId,
+    ) -> Result<()> {
+        let status = self.status(guild);
+        if status == ServerStatus::Unknown {
+            do yeet anyhow!(
+                "{} hasn't been allowed yet, use `/servers allow` to set its log channel",
+                guild
+            );
+        }
+        self.store(database, guild, status.is_allowed(), Some(channel)).await
+    }
+
+    /// Drops a guild's override after its row was deleted, so it falls back to the config.
+    pub fn forget(&self, guild: GuildId) {
+        self.overrides.write().unwrap().remove(&guild);
+    }
+
+    fn get_override(&self, guild: GuildId) -> Option<guild_allowlist::Model> {
+        self.overrides.read().unwrap().get(&guild).cloned()
+    }
+
+    async fn store(
+        &self,
+        database: &DatabaseConnection,
+        guild: GuildId,
+        allowed: bool,
+        log_channel: Option<ChannelId>,
+    ) -> Result<()> {
+        let model = guild_allowlist::Model {
+            guild_id: guild.0 as i64,
+            allowed,
+            log_channel: log_channel.map(|x| x.0 as i64),
+            updated_at: *Timestamp::now(),
+        };
+        let query = Query::insert()
+            .into_table(guild_allowlist::Entity.table_ref())
+            .columns(guild_allowlist::Column::iter())
+            .values_panic([
+                model.guild_id.into(),
+                model.allowed.into(),
+                model.log_channel.into(),
+                model.updated_at.into(),
+            ])
+            .on_conflict(
+                OnConflict::column(guild_allowlist::Column::GuildId)
+                    .update_columns([
+                        guild_allowlist::Column::Allowed,
+                        guild_allowlist::Column::LogChannel,
+                        guild_allowlist::Column::UpdatedAt,
+                    ])
+                    .to_owned(),
+            )
+            .to_owned();
+        execute_query(database, &query)
+            .await
+            .context("Couldn't update guild allowlist")?;
+        self.overrides.write().unwrap().insert(guild, model);
+        Ok(())
+    }
+}
+
+async fn fetch_overrides(database: &DatabaseConnection) -> Result<HashMap<GuildId, guild_allowlist::Model>> {
+    Ok(guild_allowlist::Entity::find()
+        .all(database)
+        .await
+        .context("Couldn't fetch guild allowlist")?
+        .into_iter()
+        .map(|x| (GuildId(x.guild_id as u64), x))
+        .collect())
+}
+
+/// Leaves `guild` after `delay` unless it gets allowed in the meantime.
+pub fn schedule_leave(http: Arc<Http>, servers: Arc<ServerRegistry>, guild: GuildId, delay: Duration) {
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        if !servers.is_allowed(guild) {
+            if let Err(err) = guild.leave(&http).await {
+                error!(%guild, "Couldn't leave disallowed guild: {}", err);
+            } else {
+                info!(%guild, "Left disallowed guild");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data;
+
+    #[tokio::test]
+    async fn log_channels_dont_change_whether_a_guild_is_allowed() {
+        let database = data::load_memory_database().await.unwrap();
+        let servers = ServerRegistry::load(&PingchuConfig::default(), &database)
+            .await
+            .unwrap();
+        let (allowed, denied, unknown) = (GuildId(1), GuildId(2), GuildId(3));
+        let channel = ChannelId(10);
+
+        servers.allow(&database, allowed, Some(ChannelId(9))).await.unwrap();
+        servers.deny(&database, denied).await.unwrap();
+        for guild in [allowed, denied] {
+            servers.set_log_channel(&database, guild, channel).await.unwrap();
+        }
+        assert!(servers.set_log_channel(&database, unknown, channel).await.is_err());
+
+        assert_eq!(servers.status(allowed), ServerStatus::Allowed);
+        assert_eq!(servers.get(allowed).unwrap().log_channel, channel);
+        assert_eq!(servers.status(denied), ServerStatus::Denied);
+        assert_eq!(servers.status(unknown), ServerStatus::Unknown);
+
+        // overrides survive a restart
+        let servers = ServerRegistry::load(&PingchuConfig::default(), &database)
+            .await
+            .unwrap();
+        assert_eq!(servers.status(denied), ServerStatus::Denied);
+        assert_eq!(servers.status(unknown), ServerStatus::Unknown);
+    }
+}
@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+
+/// Tracks work that has to finish before Pingchu can shut down.
+#[derive(Default)]
+pub struct Shutdown {
+    stopping: AtomicBool,
+    in_flight: AtomicUsize,
+    idle: Notify,
+}
+
+/// Keeps shutdown waiting until it's dropped.
+pub struct InFlight(Arc<Shutdown>);
+
+impl Shutdown {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Registers a new piece of work, or returns `None` if Pingchu is shutting down
+    /// and the work shouldn't be started at all.
+    pub fn enter(self: &Arc<Self>) -> Option<InFlight> {
+        if self.is_stopping() {
+            return None;
+        }
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        Some(InFlight(self.clone()))
+    }
+
+    /// Stops accepting new work and waits for everything in flight to finish.
+    /// Returns `false` if that took longer than `timeout`.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.stopping.store(true, Ordering::SeqCst);
+        tokio::time::timeout(timeout, async {
+            while self.in_flight.load(Ordering::SeqCst) > 0 {
+                self.idle.notified().await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // notify_one stores a permit, so this can't be missed by `drain`
+            self.0.idle.notify_one();
+        }
+    }
+}
+
+/// Resolves once the process is asked to stop with Ctrl+C or SIGTERM.
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = sigterm.recv() => {},
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
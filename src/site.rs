@@ -0,0 +1,300 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use poise::serenity_prelude::{GuildId, Http, Timestamp};
+use sea_orm::prelude::DateTimeUtc;
+use tracing::info;
+
+use crate::data::{guild_ping, ping_incident};
+use crate::names::Names;
+use crate::ping::{self, PingKind};
+use crate::servers::ServerRegistry;
+use crate::{config, data, lifecycle, privacy};
+
+/// Incidents listed on the timeline. Older ones still count towards the charts.
+const TIMELINE_LENGTH: usize = 50;
+/// Months of incidents shown in the chart.
+const CHART_MONTHS: usize = 12;
+
+const STYLE: &str = r#"
+body { margin: 0 auto; max-width: 56rem; padding: 1.5rem; font-family: system-ui, sans-serif; background: #1e1f22; color: #dbdee1; }
+a { color: #ef5858; }
+h1, h2 { color: #fff; }
+table { width: 100%; border-collapse: collapse; margin-bottom: 1.5rem; }
+th, td { padding: 0.4rem 0.6rem; text-align: left; border-bottom: 1px solid #3f4147; }
+td.num, th.num { text-align: right; font-variant-numeric: tabular-nums; }
+.cards { display: flex; flex-wrap: wrap; gap: 0.75rem; margin-bottom: 1.5rem; }
+.card { flex: 1 1 8rem; padding: 0.75rem; border-radius: 0.5rem; background: #2b2d31; }
+.card b { display: block; font-size: 1.5rem; color: #fff; }
+svg { display: block; margin-bottom: 1.5rem; }
+svg text { fill: #dbdee1; font: 12px system-ui, sans-serif; }
+svg rect { fill: #ef5858; }
+footer { color: #949ba4; font-size: 0.8rem; }
+"#;
+
+/// Everything shown on a guild's page.
+pub struct GuildPage {
+    pub guild: GuildId,
+    /// Members with the most pings, most pings first.
+    pub leaderboard: Vec<guild_ping::Model>,
+    /// Every member's stats, used for totals.
+    pub members: Vec<guild_ping::Model>,
+    /// Every @everyone and @here ping, newest first.
+    pub incidents: Vec<ping_incident::Model>,
+    /// Members who left the guild.
+    pub departed: HashSet<i64>,
+}
+
+/// Renders a site for every allowed server into `output`.
+pub async fn generate(
+    config_dir: &Path,
+    data_dir: &Path,
+    output: &Path,
+    limit: usize,
+    resolve_names: bool,
+) -> Result<()> {
+    let config = config::load_config(config_dir)?;
+    let database = data::load_database(data_dir).await.context("Couldn't load database!")?;
+    let servers = ServerRegistry::load(&config, &database).await?;
+    let http = if resolve_names {
+        Some(Http::new(&crate::read_token()?))
+    } else {
+        None
+    };
+    let generated_at = *Timestamp::now();
+
+    let mut index = vec![];
+    for guild in servers.known_guilds().into_iter().filter(|x| servers.is_allowed(*x)) {
+        // hidden members are left out entirely, so the site can't show who they are
+        let hidden = privacy::hidden_users(&database, guild).await?;
+        let mut members = ping::guild_pings(&database, guild).await?;
+        members.retain(|x| !hidden.contains(&x.user_id));
+        members.sort_by(|a, b| b.pings.cmp(&a.pings));
+        let mut incidents = ping::guild_incidents(&database, guild).await?;
+        incidents.retain(|x| !hidden.contains(&x.user_id));
+        let page = GuildPage {
+            guild,
+            leaderboard: members.iter().take(limit).cloned().collect(),
+            members,
+            incidents,
+            departed: lifecycle::departed_users(&database, guild).await?,
+        };
+        let mut names = Names::default();
+        if let Some(http) = &http {
+            names.fetch_guild(http, guild).await;
+            let users = page
+                .leaderboard
+                .iter()
+                .map(|x| x.user_id)
+                .chain(page.incidents.iter().take(TIMELINE_LENGTH).map(|x| x.user_id));
+            names.fetch_members(http, guild, users.map(|x| x as u64)).await;
+        }
+
+        let dir = output.join(guild.to_string());
+        fs::create_dir_all(&dir).with_context(|| format!("Couldn't create {}", dir.display()))?;
+        fs::write(dir.join("index.html"), render_guild(&page, &names, generated_at))
+            .with_context(|| format!("Couldn't write page for guild {}", guild))?;
+        index.push((
+            guild,
+            names.get(guild.0),
+            page.members.iter().map(|x| x.pings as u64).sum(),
+        ));
+    }
+
+    fs::create_dir_all(output).with_context(|| format!("Couldn't create {}", output.display()))?;
+    fs::write(output.join("index.html"), render_index(&index, generated_at)).context("Couldn't write index page")?;
+    info!("Rendered {} server(s) to {}", index.len(), output.display());
+    Ok(())
+}
+
+/// Renders the page linking to every guild, given each guild's name and total pings.
+pub fn render_index(guilds: &[(GuildId, String, u64)], generated_at: DateTimeUtc) -> String {
+    let mut body =
+        String::from("<h1>Ping'chu Stats</h1>\n<table>\n<tr><th>Server</th><th class=\"num\">Pings</th></tr>\n");
+    for (guild, name, pings) in guilds {
+        let _ = writeln!(
+            body,
+            "<tr><td><a href=\"{}/index.html\">{}</a></td><td class=\"num\">{}</td></tr>",
+            guild,
+            escape(name),
+            pings
+        );
+    }
+    body.push_str("</table>\n");
+    page("Ping'chu Stats", &body, generated_at)
+}
+
+pub fn render_guild(page_data: &GuildPage, names: &Names, generated_at: DateTimeUtc) -> String {
+    let GuildPage {
+        guild,
+        leaderboard,
+        members,
+        incidents,
+        departed,
+    } = page_data;
+    let guild_name = names.get(guild.0);
+    let mut body = format!(
+        "<p><a href=\"../index.html\">&larr; All servers</a></p>\n<h1>{}</h1>\n",
+        escape(&guild_name)
+    );
+
+    // per-kind totals
+    let incidents_of = |kind| incidents.iter().filter(|x| x.kind == kind).count() as u64;
+    let members_with = |f: fn(&guild_ping::Model) -> bool| members.iter().filter(|x| f(x)).count() as u64;
+    let totals = [
+        ("Total pings", members.iter().map(|x| x.pings as u64).sum()),
+        ("@everyone pings", incidents_of(PingKind::Everyone)),
+        ("@here pings", incidents_of(PingKind::Here)),
+        ("Members who pinged", members.len() as u64),
+    ];
+    body.push_str("<div class=\"cards\">\n");
+    for (label, value) in totals {
+        let _ = writeln!(body, "<div class=\"card\"><b>{}</b>{}</div>", value, label);
+    }
+    body.push_str("</div>\n");
+
+    body.push_str("<h2>Members who pinged each kind</h2>\n");
+    body.push_str(&bar_chart(&[
+        (
+            "@everyone".to_string(),
+            members_with(|x| x.last_everyone_ping.is_some()),
+        ),
+        ("@here".to_string(), members_with(|x| x.last_here_ping.is_some())),
+        ("@role".to_string(), members_with(|x| x.last_role_ping.is_some())),
+        ("@User".to_string(), members_with(|x| x.last_user_ping.is_some())),
+    ]));
+
+    body.push_str("<h2>Leaderboard</h2>\n");
+    if leaderboard.is_empty() {
+        body.push_str("<p>Nobody has pinged anyone yet.</p>\n");
+    } else {
+        let bars = leaderboard
+            .iter()
+            .take(10)
+            .map(|x| (names.get(x.user_id as u64), x.pings as u64))
+            .collect::<Vec<_>>();
+        body.push_str(&bar_chart(&bars));
+        body.push_str("<table>\n<tr><th class=\"num\">#</th><th>Member</th><th class=\"num\">Pings</th><th>Last @everyone</th></tr>\n");
+        for (rank, row) in leaderboard.iter().enumerate() {
+            let _ = writeln!(
+                body,
+                "<tr><td class=\"num\">{}</td><td>{}{}</td><td class=\"num\">{}</td><td>{}</td></tr>",
+                rank + 1,
+                escape(&names.get(row.user_id as u64)),
+                if departed.contains(&row.user_id) {
+                    " <em>(left)</em>"
+                } else {
+                    ""
+                },
+                row.pings,
+                row.last_everyone_ping.map(format_time).unwrap_or_default()
+            );
+        }
+        body.push_str("</table>\n");
+    }
+
+    body.push_str("<h2>@everyone and @here incidents</h2>\n");
+    if incidents.is_empty() {
+        body.push_str("<p>Nobody has pinged @everyone or @here yet.</p>\n");
+    } else {
+        let mut months = BTreeMap::new();
+        for incident in incidents {
+            *months
+                .entry(incident.timestamp.format("%Y-%m").to_string())
+                .or_insert(0u64) += 1;
+        }
+        let skip = months.len().saturating_sub(CHART_MONTHS);
+        body.push_str(&bar_chart(&months.into_iter().skip(skip).collect::<Vec<_>>()));
+        body.push_str("<table>\n<tr><th>When</th><th>Who</th><th>Ping</th><th>Message</th></tr>\n");
+        for incident in incidents.iter().take(TIMELINE_LENGTH) {
+            let _ = writeln!(
+                body,
+                "<tr><td>{}</td><td>{}</td><td>@{}</td><td><a href=\"https://discord.com/channels/{}/{}/{}\">Jump</a></td></tr>",
+                format_time(incident.timestamp),
+                escape(&names.get(incident.user_id as u64)),
+                incident.kind.as_str(),
+                guild,
+                incident.channel_id as u64,
+                incident.message_id as u64
+            );
+        }
+        body.push_str("</table>\n");
+    }
+
+    page(&format!("{} - Ping'chu Stats", guild_name), &body, generated_at)
+}
+
+/// Draws a horizontal bar chart as inline SVG, so the page doesn't need any scripts or assets.
+pub fn bar_chart(bars: &[(String, u64)]) -> String {
+    const ROW_HEIGHT: usize = 24;
+    const LABEL_WIDTH: usize = 160;
+    const BAR_WIDTH: usize = 400;
+
+    let max = bars.iter().map(|(_, value)| *value).max().unwrap_or(0).max(1);
+    let height = bars.len() * ROW_HEIGHT;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" role=\"img\">\n",
+        LABEL_WIDTH + BAR_WIDTH + 60,
+        height
+    );
+    for (i, (label, value)) in bars.iter().enumerate() {
+        let y = i * ROW_HEIGHT;
+        let width = (*value as f64 / max as f64 * BAR_WIDTH as f64).round() as usize;
+        let _ = writeln!(
+            svg,
+            "<text x=\"0\" y=\"{}\">{}</text><rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"3\"/><text x=\"{}\" y=\"{}\">{}</text>",
+            y + 16,
+            escape(&truncate(label, 22)),
+            LABEL_WIDTH,
+            y + 4,
+            width,
+            ROW_HEIGHT - 8,
+            LABEL_WIDTH + width + 6,
+            y + 16,
+            value
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn page(title: &str, body: &str, generated_at: DateTimeUtc) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n{}<footer>Generated by Ping'chu on {}</footer>\n</body>\n</html>\n",
+        escape(title),
+        STYLE,
+        body,
+        format_time(generated_at)
+    )
+}
+
+fn format_time(time: DateTimeUtc) -> String {
+    time.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() > max_chars {
+        format!("{}…", text.chars().take(max_chars - 1).collect::<String>())
+    } else {
+        text.to_string()
+    }
+}
+
+/// Escapes text for use in HTML, including inside attributes.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
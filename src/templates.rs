@@ -0,0 +1,219 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+use serde::{Deserialize, Serialize};
+
+/// Something a template can fill in, written like `{name}`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Variable {
+    /// The pinger's display name.
+    Name,
+    /// The pinger's total ping count.
+    Pings,
+    /// How long ago the pinger last pinged before this message.
+    SinceLastPing,
+    /// The server's name.
+    Server,
+    /// The display name of a random member of the server.
+    RandomMember,
+    /// A mention of the pinger.
+    Author,
+    /// A mention of the channel the ping was sent in.
+    Channel,
+    /// The name of the rule that matched, only in rule replies.
+    Rule,
+}
+
+impl Variable {
+    pub const ALL: [Variable; 8] = [
+        Self::Name,
+        Self::Pings,
+        Self::SinceLastPing,
+        Self::Server,
+        Self::RandomMember,
+        Self::Author,
+        Self::Channel,
+        Self::Rule,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Name => "name",
+            Self::Pings => "pings",
+            Self::SinceLastPing => "since_last_ping",
+            Self::Server => "server",
+            Self::RandomMember => "random_member",
+            Self::Author => "author",
+            Self::Channel => "channel",
+            Self::Rule => "rule",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Variable(Variable),
+}
+
+/// A response with placeholders like `{name}`. Write `{{` and `}}` for literal braces.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+/// What a template's placeholders get filled in with.
+#[derive(Debug, Clone, Default)]
+pub struct Values {
+    pub name: String,
+    pub pings: u32,
+    /// Already formatted, or `None` if they never pinged before.
+    pub since_last_ping: Option<String>,
+    pub server: String,
+    pub random_member: String,
+    pub author: String,
+    pub channel: String,
+    pub rule: String,
+}
+
+impl Template {
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether the template has a placeholder for `variable`, so values that are expensive to get can be skipped.
+    pub fn uses(&self, variable: Variable) -> bool {
+        self.parts.contains(&Part::Variable(variable))
+    }
+
+    pub fn render(&self, values: &Values) -> String {
+        let mut output = String::with_capacity(self.source.len());
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => output.push_str(text),
+                Part::Variable(Variable::Name) => output.push_str(&values.name),
+                Part::Variable(Variable::Pings) => output.push_str(&values.pings.to_string()),
+                Part::Variable(Variable::SinceLastPing) => {
+                    output.push_str(values.since_last_ping.as_deref().unwrap_or("never"))
+                }
+                Part::Variable(Variable::Server) => output.push_str(&values.server),
+                Part::Variable(Variable::RandomMember) => output.push_str(&values.random_member),
+                Part::Variable(Variable::Author) => output.push_str(&values.author),
+                Part::Variable(Variable::Channel) => output.push_str(&values.channel),
+                Part::Variable(Variable::Rule) => output.push_str(&values.rule),
+            }
+        }
+        output
+    }
+}
+
+impl FromStr for Template {
+    type Err = Error;
+
+    fn from_str(source: &str) -> Result<Self> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut chars = source.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    if !closed {
+                        do yeet anyhow!("Unclosed `{{` in `{}`", source);
+                    }
+                    let variable = Variable::ALL
+                        .into_iter()
+                        .find(|x| x.as_str() == name.trim())
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "Unknown placeholder `{{{}}}` in `{}`, expected one of {}",
+                                name,
+                                source,
+                                Variable::ALL.map(|x| format!("{{{}}}", x.as_str())).join(", ")
+                            )
+                        })?;
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Variable(variable));
+                }
+                '}' => do yeet anyhow!("Unmatched `}}` in `{}`, write `}}}}` for a literal brace", source),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Self {
+            source: source.to_string(),
+            parts,
+        })
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<Template> for String {
+    fn from(value: Template) -> Self {
+        value.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_every_placeholder() {
+        let template =
+            "{name} ({author}) in {channel} on {server}: {pings}, {since_last_ping}, {random_member}, {rule} {{}}"
+                .parse::<Template>()
+                .unwrap();
+        let values = Values {
+            name: "Pika".to_string(),
+            pings: 3,
+            since_last_ping: None,
+            server: "Kanto".to_string(),
+            random_member: "Ash".to_string(),
+            author: "<@10>".to_string(),
+            channel: "<#100>".to_string(),
+            rule: "No pings".to_string(),
+        };
+        assert_eq!(
+            template.render(&values),
+            "Pika (<@10>) in <#100> on Kanto: 3, never, Ash, No pings {}"
+        );
+        assert!(Variable::ALL.into_iter().all(|x| template.uses(x)));
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for source in ["{nam}", "{name", "name}", "{author} {chanel}"] {
+            assert!(source.parse::<Template>().is_err(), "{}", source);
+        }
+    }
+}
@@ -11,3 +11,38 @@ pub fn format_time_v1_duration(duration: Duration) -> String {
 pub fn check_uwu_support() -> bool {
     is_x86_feature_detected!("sse4.1")
 }
+
+/// (De)serializes an optional [`std::time::Duration`] as a human-readable string like `"1h 30m"`.
+pub mod humantime_option {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => serializer.serialize_some(&humantime::format_duration(*duration).to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|x| humantime::parse_duration(&x).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+/// (De)serializes a [`std::time::Duration`] as a human-readable string like `"10m"`.
+pub mod humantime_duration {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&humantime::format_duration(*duration).to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        humantime::parse_duration(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
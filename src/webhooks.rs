@@ -0,0 +1,343 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use hmac::{Hmac, Mac};
+use poise::serenity_prelude::{GuildId, Timestamp};
+use sea_orm::{ActiveModelTrait, DatabaseConnection, Set};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{debug, error, warn};
+
+use crate::config::{WebhookEndpoint, WebhooksConfig};
+use crate::data::webhook_dead_letter;
+use crate::events::PingEvent;
+use crate::ping::PingKind;
+use crate::shutdown::Shutdown;
+
+pub const SIGNATURE_HEADER: &str = "X-Pingchu-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Pingchu-Timestamp";
+
+/// How long to wait before the first retry. Every retry after that waits twice as long.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Events that webhook endpoints can subscribe to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// Someone pinged @everyone.
+    EveryoneIncident,
+    /// Someone pinged @here.
+    HereIncident,
+    /// A member's total pings reached one of the configured milestones.
+    Milestone,
+}
+
+/// The JSON body POSTed to webhook endpoints.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookPayload {
+    EveryoneIncident(PingEvent),
+    HereIncident(PingEvent),
+    Milestone {
+        guild_id: String,
+        user_id: String,
+        milestone: u32,
+        pings: u32,
+        timestamp: Timestamp,
+    },
+}
+
+impl WebhookPayload {
+    /// Wraps an incident event, if it's the kind of incident webhooks care about.
+    pub fn incident(event: PingEvent) -> Option<Self> {
+        match event.kind {
+            PingKind::Everyone => Some(Self::EveryoneIncident(event)),
+            PingKind::Here => Some(Self::HereIncident(event)),
+            _ => None,
+        }
+    }
+
+    fn event(&self) -> WebhookEvent {
+        match self {
+            Self::EveryoneIncident(_) => WebhookEvent::EveryoneIncident,
+            Self::HereIncident(_) => WebhookEvent::HereIncident,
+            Self::Milestone { .. } => WebhookEvent::Milestone,
+        }
+    }
+
+    fn guild_id(&self) -> &str {
+        match self {
+            Self::EveryoneIncident(x) | Self::HereIncident(x) => &x.guild_id,
+            Self::Milestone { guild_id, .. } => guild_id,
+        }
+    }
+}
+
+/// The guild a dead letter's payload was about, if it can still be read.
+pub fn dead_letter_guild(dead_letter: &webhook_dead_letter::Model) -> Option<GuildId> {
+    let payload = serde_json::from_str::<serde_json::Value>(&dead_letter.payload).ok()?;
+    payload["guild_id"].as_str()?.parse().ok().map(GuildId)
+}
+
+/// Signs a webhook body. Receivers should recompute this over the timestamp header,
+/// a `.`, and the raw body, using their shared secret.
+pub fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Delivers [`WebhookPayload`]s to every subscribed endpoint in the background.
+pub struct WebhookDispatcher {
+    config: WebhooksConfig,
+    client: reqwest::Client,
+    database: DatabaseConnection,
+    shutdown: Arc<Shutdown>,
+}
+
+impl WebhookDispatcher {
+    pub fn new(config: WebhooksConfig, database: DatabaseConnection, shutdown: Arc<Shutdown>) -> Result<Self> {
+        Ok(Self {
+            config,
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .context("Couldn't create webhook client")?,
+            database,
+            shutdown,
+        })
+    }
+
+    /// Milestones crossed by going from `before` to `after` total pings.
+    pub fn milestones_between(&self, before: u32, after: u32) -> impl Iterator<Item = u32> + '_ {
+        self.config
+            .milestones
+            .iter()
+            .copied()
+            .filter(move |x| before < *x && *x <= after)
+    }
+
+    /// Whether any endpoint would receive `event`, to skip work that'd go nowhere.
+    pub fn wants(&self, event: WebhookEvent) -> bool {
+        self.config.endpoints.iter().any(|x| x.events.contains(&event))
+    }
+
+    /// Queues `payload` for every endpoint that subscribed to it. This never waits on the network.
+    pub fn dispatch(&self, payload: WebhookPayload) {
+        let body = match serde_json::to_string(&payload) {
+            Ok(x) => x,
+            Err(err) => {
+                error!("Couldn't serialize webhook payload: {}", err);
+                return;
+            }
+        };
+        let guild = payload.guild_id().parse::<u64>().ok().map(GuildId);
+        for endpoint in &self.config.endpoints {
+            let subscribed = endpoint.events.contains(&payload.event())
+                && (endpoint.guilds.is_empty() || guild.map_or(false, |x| endpoint.guilds.contains(&x)));
+            if subscribed {
+                let delivery = Delivery {
+                    endpoint: endpoint.clone(),
+                    body: body.clone(),
+                    max_attempts: self.config.max_attempts.max(1),
+                    initial_backoff: INITIAL_BACKOFF,
+                    client: self.client.clone(),
+                    database: self.database.clone(),
+                };
+                // keep shutdown waiting for deliveries that are already queued
+                let in_flight = self.shutdown.enter();
+                tokio::spawn(async move {
+                    delivery.run().await;
+                    drop(in_flight);
+                });
+            }
+        }
+    }
+}
+
+struct Delivery {
+    endpoint: WebhookEndpoint,
+    body: String,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    client: reqwest::Client,
+    database: DatabaseConnection,
+}
+
+impl Delivery {
+    async fn run(self) {
+        let mut backoff = self.initial_backoff;
+        let mut last_error = String::new();
+        for attempt in 1..=self.max_attempts {
+            match self.attempt().await {
+                Ok(()) => {
+                    debug!(url = %self.endpoint.url, attempt, "Delivered webhook");
+                    return;
+                }
+                Err(err) => {
+                    warn!(url = %self.endpoint.url, attempt, "Couldn't deliver webhook: {:#}", err);
+                    last_error = format!("{:#}", err);
+                }
+            }
+            if attempt < self.max_attempts {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+
+        let dead_letter = webhook_dead_letter::ActiveModel {
+            url: Set(self.endpoint.url.clone()),
+            payload: Set(self.body.clone()),
+            attempts: Set(self.max_attempts),
+            last_error: Set(last_error),
+            failed_at: Set(*Timestamp::now()),
+            ..Default::default()
+        };
+        if let Err(err) = dead_letter.insert(&self.database).await {
+            error!(url = %self.endpoint.url, "Couldn't record failed webhook: {}", err);
+        }
+    }
+
+    async fn attempt(&self) -> Result<()> {
+        let timestamp = Timestamp::now().timestamp().to_string();
+        let response = self
+            .client
+            .post(&self.endpoint.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(TIMESTAMP_HEADER, &timestamp)
+            .header(SIGNATURE_HEADER, sign(&self.endpoint.secret, &timestamp, &self.body))
+            .body(self.body.clone())
+            .send()
+            .await
+            .context("Request failed")?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(anyhow!("Endpoint responded with {}", response.status()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    use axum::extract::Extension;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+    use sea_orm::EntityTrait;
+
+    use super::*;
+    use crate::{data, http};
+
+    const SECRET: &str = "hunter2";
+    const BODY: &str = r#"{"event":"milestone"}"#;
+
+    /// Stands in for a webhook receiver, answering with `statuses` in order and then with 200s.
+    #[derive(Default)]
+    struct Receiver {
+        statuses: Mutex<VecDeque<StatusCode>>,
+        requests: Mutex<Vec<(Instant, HeaderMap, String)>>,
+    }
+
+    async fn receive(Extension(receiver): Extension<Arc<Receiver>>, headers: HeaderMap, body: String) -> StatusCode {
+        receiver.requests.lock().unwrap().push((Instant::now(), headers, body));
+        receiver.statuses.lock().unwrap().pop_front().unwrap_or(StatusCode::OK)
+    }
+
+    async fn deliver(statuses: &[StatusCode], max_attempts: u32) -> (Arc<Receiver>, Delivery) {
+        let receiver = Arc::new(Receiver {
+            statuses: Mutex::new(statuses.iter().copied().collect()),
+            ..Receiver::default()
+        });
+        let router = Router::new()
+            .route("/hook", post(receive))
+            .layer(Extension(receiver.clone()));
+        let delivery = Delivery {
+            endpoint: WebhookEndpoint {
+                url: format!("http://{}/hook", http::serve_locally(router)),
+                secret: SECRET.to_string(),
+                events: vec![WebhookEvent::Milestone],
+                guilds: vec![],
+            },
+            body: BODY.to_string(),
+            max_attempts,
+            initial_backoff: Duration::from_millis(50),
+            client: reqwest::Client::new(),
+            database: data::load_memory_database().await.unwrap(),
+        };
+        (receiver, delivery)
+    }
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        assert_eq!(
+            sign(SECRET, "1656000000", BODY),
+            "sha256=508013e3312f996d5b38e3b96d4d14ce33173ff3953f51534e356e6e88913b5a"
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_signature_headers() {
+        let (receiver, delivery) = deliver(&[], 1).await;
+        delivery.run().await;
+
+        let requests = receiver.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let (_, headers, body) = &requests[0];
+        let header = |name| headers.get(name).unwrap().to_str().unwrap();
+        assert_eq!(body, BODY);
+        assert_eq!(header("content-type"), "application/json");
+        let timestamp = header(TIMESTAMP_HEADER);
+        assert!(timestamp.parse::<i64>().is_ok(), "{} isn't a unix timestamp", timestamp);
+        let signature = header(SIGNATURE_HEADER);
+        assert_eq!(signature, sign(SECRET, timestamp, BODY));
+        let digest = signature.strip_prefix("sha256=").unwrap();
+        assert_eq!(digest.len(), 64);
+        assert!(digest.chars().all(|x| x.is_ascii_hexdigit()));
+    }
+
+    #[tokio::test]
+    async fn retries_with_backoff() {
+        let (receiver, delivery) = deliver(&[StatusCode::INTERNAL_SERVER_ERROR, StatusCode::BAD_GATEWAY], 5).await;
+        let database = delivery.database.clone();
+        delivery.run().await;
+
+        let times = receiver
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(time, _, _)| *time)
+            .collect::<Vec<_>>();
+        assert_eq!(times.len(), 3);
+        assert!(times[1] - times[0] >= Duration::from_millis(50));
+        assert!(times[2] - times[1] >= Duration::from_millis(100));
+        let dead_letters = webhook_dead_letter::Entity::find().all(&database).await.unwrap();
+        assert!(dead_letters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn records_dead_letter_after_max_attempts() {
+        let (receiver, delivery) = deliver(&[StatusCode::INTERNAL_SERVER_ERROR; 3], 3).await;
+        let url = delivery.endpoint.url.clone();
+        let database = delivery.database.clone();
+        delivery.run().await;
+
+        assert_eq!(receiver.requests.lock().unwrap().len(), 3);
+        let dead_letters = webhook_dead_letter::Entity::find().all(&database).await.unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        let dead_letter = &dead_letters[0];
+        assert_eq!(dead_letter.url, url);
+        assert_eq!(dead_letter.payload, BODY);
+        assert_eq!(dead_letter.attempts, 3);
+        assert!(dead_letter.last_error.contains("500"), "{}", dead_letter.last_error);
+    }
+}